@@ -1,33 +1,28 @@
-extern crate openssl_probe;
 use log::*;
-
-mod comm;
-mod comp;
+use thomas::cli;
 
 fn main() {
-    let config = comm::utils::parse_cmd_args(false);
-    if config == None {
-        std::process::exit(0);
-    }
+    let (config, path) = match cli::parse_cmd_args(false) {
+        Some(a) => a,
+        None => std::process::exit(0),
+    };
 
-    let cfg = parse_args_for_client(&config.unwrap());
-    comm::logging::init(&cfg.loglevel);
-    openssl_probe::init_ssl_cert_env_vars();
-    comm::utils::register_ctrl_c_handler();
+    let cfg = parse_args_for_client(&config);
+    cli::init_logging(&cfg.loglevel);
+    cli::init_ssl_cert_env_vars();
+    cli::register_ctrl_c_handler();
 
-    let ver = crate::comm::cons::VERSION;
-    let name = crate::comm::cons::PKG_NAME;
+    let ver = cli::VERSION;
+    let name = cli::PKG_NAME;
     println!("{} client v{} starts", name, ver);
 
-    comp::listener::serv(cfg);
+    cli::serv_client(cfg, path);
     info!("{} exits", name);
 }
 
-fn parse_args_for_client(config: &str) -> comm::models::ClientConfigs {
+fn parse_args_for_client(config: &str) -> thomas::ClientConfigs {
     match serde_json::from_str(config) {
-        Ok(c) => {
-            return c;
-        }
+        Ok(c) => c,
         Err(e) => {
             println!("parse config fail");
             println!("{:?}", e);
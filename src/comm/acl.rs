@@ -0,0 +1,402 @@
+use crate::comm::{
+    models::{AclAction, AclRule, Cmds},
+    utils,
+};
+use async_std::net::ToSocketAddrs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub(crate) fn parse(s: &str) -> Option<Cidr> {
+        let (ip, prefix) = match s.split_once('/') {
+            Some((ip, prefix)) => (ip.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+            None => (s.parse::<IpAddr>().ok()?, None),
+        };
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return None;
+        }
+        Some(Cidr { addr: ip, prefix })
+    }
+
+    pub(crate) fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, to_canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn to_canonical(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => *ip,
+        },
+        _ => *ip,
+    }
+}
+
+fn is_private_v4(ip: &Ipv4Addr) -> bool {
+    let o = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || o[0] == 0
+        // carrier-grade NAT 100.64.0.0/10
+        || (o[0] == 100 && (o[1] & 0xc0) == 64)
+}
+
+fn is_private_v6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        // unique local fc00::/7 and link local fe80::/10
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+}
+
+/// Loopback, private, link-local (including cloud metadata) and other non-public addresses.
+pub fn is_private(ip: &IpAddr) -> bool {
+    match to_canonical(ip) {
+        IpAddr::V4(v4) => is_private_v4(&v4),
+        IpAddr::V6(v6) => is_private_v6(&v6),
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    allow: bool,
+    text: String,
+    cmds: Vec<Cmds>,
+    cidrs: Vec<Cidr>,
+    domains: Vec<String>,
+    ports: Vec<(u16, u16)>,
+}
+
+pub(crate) fn parse_port_range(s: &str) -> Option<(u16, u16)> {
+    let r = match s.split_once('-') {
+        Some((lo, hi)) => (lo.trim().parse().ok()?, hi.trim().parse().ok()?),
+        None => {
+            let p = s.trim().parse().ok()?;
+            (p, p)
+        }
+    };
+    if r.0 > r.1 {
+        return None;
+    }
+    Some(r)
+}
+
+pub(crate) fn match_domain(suffix: &str, host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_lowercase();
+    let suffix = suffix.trim_start_matches('.').to_lowercase();
+    host == suffix || host.ends_with(&format!(".{suffix}"))
+}
+
+impl Rule {
+    fn parse(cfg: &AclRule) -> Result<Rule, String> {
+        let mut cidrs = vec![];
+        for c in cfg.cidrs.iter() {
+            cidrs.push(Cidr::parse(c).ok_or(format!("invalid cidr: {c}"))?);
+        }
+        let mut ports = vec![];
+        for p in cfg.ports.iter() {
+            ports.push(parse_port_range(p).ok_or(format!("invalid port range: {p}"))?);
+        }
+        let allow = cfg.action == AclAction::Allow;
+        Ok(Rule {
+            allow,
+            text: format!("{:?}", cfg),
+            cmds: cfg.cmds.clone(),
+            cidrs,
+            domains: cfg.domains.clone(),
+            ports,
+        })
+    }
+
+    // every non-empty condition must match
+    fn is_match(&self, cmd: &Cmds, host: &str, ip: Option<&IpAddr>, port: u16) -> bool {
+        if !self.cmds.is_empty() && !self.cmds.contains(cmd) {
+            return false;
+        }
+        if !self.ports.is_empty() && !self.ports.iter().any(|(lo, hi)| *lo <= port && port <= *hi) {
+            return false;
+        }
+        if !self.domains.is_empty() && !self.domains.iter().any(|d| match_domain(d, host)) {
+            return false;
+        }
+        if !self.cidrs.is_empty() {
+            match ip {
+                Some(ip) => return self.cidrs.iter().any(|c| c.contains(ip)),
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Destination access control of an outlet server.
+///
+/// Rules are checked in order and the first match wins. Without a match,
+/// `Connect`, `UdpAssoc` and `Relay` to non-public addresses are denied when
+/// `block_private` is set, and so is a `Bind` on a non-public address other
+/// than the wildcard one. Everything else is allowed.
+#[derive(Debug)]
+pub struct Acl {
+    rules: Vec<Rule>,
+    block_private: bool,
+}
+
+impl Acl {
+    pub fn new(rules: &[AclRule], block_private: bool) -> Result<Acl, String> {
+        let mut parsed = vec![];
+        for r in rules.iter() {
+            parsed.push(Rule::parse(r)?);
+        }
+        Ok(Acl {
+            rules: parsed,
+            block_private,
+        })
+    }
+
+    /// `host` is the domain or IP in the request and `ip` is what it resolves to.
+    pub fn check(
+        &self,
+        cmd: &Cmds,
+        host: &str,
+        ip: Option<&IpAddr>,
+        port: u16,
+    ) -> Result<(), String> {
+        for rule in self.rules.iter() {
+            if rule.is_match(cmd, host, ip, port) {
+                if rule.allow {
+                    return Ok(());
+                }
+                return Err(format!("denied by rule {}", rule.text));
+            }
+        }
+        let checked = match cmd {
+            Cmds::Connect | Cmds::UdpAssoc | Cmds::Relay => true,
+            // a bind on the wildcard address is the usual request, one on a
+            // private address listens inside the network of the server
+            Cmds::Bind => ip.is_some_and(|ip| !to_canonical(ip).is_unspecified()),
+            _ => false,
+        };
+        if self.block_private && checked {
+            if let Some(ip) = ip {
+                if is_private(ip) {
+                    return Err(format!("denied private address {ip}"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves "host:port" and drops the addresses denied for `cmd`, so a
+    /// name is checked like the IPs it points to. The inner error is why
+    /// nothing is left.
+    pub async fn resolve(
+        &self,
+        cmd: &Cmds,
+        addr: &str,
+    ) -> std::io::Result<Result<Vec<SocketAddr>, String>> {
+        let (host, port) = match utils::split_host_port(addr) {
+            Some(r) => r,
+            None => return Ok(Err(format!("invalid address {addr}"))),
+        };
+        let mut allowed = vec![];
+        let mut reason = format!("no address for {addr}");
+        for sa in addr.to_socket_addrs().await? {
+            match self.check(cmd, &host, Some(&sa.ip()), port) {
+                Ok(_) => allowed.push(sa),
+                Err(e) => reason = format!("{addr}: {e}"),
+            }
+        }
+        if allowed.is_empty() {
+            return Ok(Err(reason));
+        }
+        Ok(Ok(allowed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(
+        action: AclAction,
+        cmds: Vec<Cmds>,
+        cidrs: &[&str],
+        domains: &[&str],
+        ports: &[&str],
+    ) -> AclRule {
+        let to_vec = |v: &[&str]| v.iter().map(|s| s.to_string()).collect();
+        AclRule {
+            action,
+            cmds,
+            cidrs: to_vec(cidrs),
+            domains: to_vec(domains),
+            ports: to_vec(ports),
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_tests() {
+        let c = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(c.contains(&ip("10.1.2.3")));
+        assert!(c.contains(&ip("::ffff:10.1.2.3")));
+        assert!(!c.contains(&ip("10.2.0.1")));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(&ip("8.8.8.8")));
+        assert!(Cidr::parse("fd00::/8").unwrap().contains(&ip("fd12::1")));
+        assert!(Cidr::parse("1.2.3.4").unwrap().contains(&ip("1.2.3.4")));
+        assert!(Cidr::parse("1.2.3.4/33").is_none());
+        assert!(Cidr::parse("bing.com/8").is_none());
+
+        for p in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+        ] {
+            assert!(is_private(&ip(p)), "{}", p);
+        }
+        for p in [
+            "::1",
+            "fe80::1",
+            "fc00::1",
+            "::ffff:127.0.0.1",
+            "100.64.0.1",
+            "0.0.0.0",
+        ] {
+            assert!(is_private(&ip(p)), "{}", p);
+        }
+        assert!(!is_private(&ip("8.8.8.8")));
+        assert!(!is_private(&ip("2001:4860:4860::8888")));
+    }
+
+    #[test]
+    fn acl_tests() {
+        let rules = vec![
+            rule(
+                AclAction::Allow,
+                vec![],
+                &["192.168.1.0/24"],
+                &[],
+                &["80", "8000-8080"],
+            ),
+            rule(AclAction::Deny, vec![], &[], &["example.com"], &[]),
+            rule(AclAction::Deny, vec![Cmds::Bind], &[], &[], &[]),
+            rule(AclAction::Deny, vec![Cmds::Connect], &[], &[], &["25"]),
+        ];
+        let acl = Acl::new(&rules, true).unwrap();
+        let pub_ip = ip("93.184.216.34");
+
+        assert!(acl
+            .check(
+                &Cmds::Connect,
+                "192.168.1.2",
+                Some(&ip("192.168.1.2")),
+                8080
+            )
+            .is_ok());
+        assert!(acl
+            .check(&Cmds::Connect, "192.168.1.2", Some(&ip("192.168.1.2")), 22)
+            .is_err());
+        assert!(acl
+            .check(&Cmds::Connect, "127.0.0.1", Some(&ip("127.0.0.1")), 80)
+            .is_err());
+        assert!(acl
+            .check(&Cmds::Connect, "www.example.com", Some(&pub_ip), 443)
+            .is_err());
+        assert!(acl
+            .check(&Cmds::Connect, "notexample.com", Some(&pub_ip), 443)
+            .is_ok());
+        assert!(acl
+            .check(&Cmds::Connect, "bing.com", Some(&pub_ip), 25)
+            .is_err());
+        assert!(acl
+            .check(&Cmds::UdpAssoc, "bing.com", Some(&pub_ip), 25)
+            .is_ok());
+        assert!(acl
+            .check(&Cmds::Bind, "0.0.0.0", Some(&ip("0.0.0.0")), 8000)
+            .is_err());
+        assert!(acl
+            .check(&Cmds::UdpAssoc, "10.0.0.1", Some(&ip("10.0.0.1")), 53)
+            .is_err());
+        assert!(acl
+            .check(&Cmds::Relay, "127.0.0.1", Some(&ip("127.0.0.1")), 3001)
+            .is_err());
+        assert!(acl
+            .check(
+                &Cmds::Relay,
+                "169.254.169.254",
+                Some(&ip("169.254.169.254")),
+                80
+            )
+            .is_err());
+
+        let open = Acl::new(&[], false).unwrap();
+        assert!(open
+            .check(&Cmds::Connect, "127.0.0.1", Some(&ip("127.0.0.1")), 80)
+            .is_ok());
+
+        let bad = rule(AclAction::Deny, vec![], &[], &[], &["80-20"]);
+        assert!(Acl::new(&[bad], true).is_err());
+    }
+
+    #[test]
+    fn resolve_tests() {
+        async_std::task::block_on(async {
+            let deny = rule(AclAction::Deny, vec![], &["10.0.0.0/8"], &[], &[]);
+            let acl = Acl::new(&[deny], true).unwrap();
+            // names are checked by what they resolve to
+            for cmd in [Cmds::Connect, Cmds::Relay] {
+                let r = acl.resolve(&cmd, "localhost:80").await.unwrap();
+                assert!(r.is_err(), "{:?}", cmd);
+            }
+            let r = acl.resolve(&Cmds::Bind, "10.1.2.3:80").await.unwrap();
+            assert!(r.is_err());
+
+            // binds only on the wildcard address or a public one
+            let acl = Acl::new(&[], true).unwrap();
+            for addr in ["localhost:80", "192.168.1.2:80", "[::ffff:127.0.0.1]:80"] {
+                let r = acl.resolve(&Cmds::Bind, addr).await.unwrap();
+                assert!(r.is_err(), "{}", addr);
+            }
+            for addr in ["0.0.0.0:0", "[::]:0", "93.184.216.34:80"] {
+                let r = acl.resolve(&Cmds::Bind, addr).await.unwrap();
+                assert!(r.is_ok(), "{}", addr);
+            }
+            assert!(acl
+                .resolve(&Cmds::Connect, "bing.com")
+                .await
+                .unwrap()
+                .is_err());
+
+            let open = Acl::new(&[], false).unwrap();
+            let addrs = open.resolve(&Cmds::Relay, "127.0.0.1:3001").await.unwrap();
+            assert_eq!(addrs.unwrap(), vec!["127.0.0.1:3001".parse().unwrap()]);
+        });
+    }
+}
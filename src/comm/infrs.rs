@@ -1,11 +1,20 @@
-use crate::comm::cons::{BUFF_LEN, CONN_TIMEOUT, UDP_TIMEOUT};
+use crate::comm::{
+    acl::Acl,
+    cons::BUFF_LEN,
+    metrics,
+    models::{Cmds, Timeouts},
+    tls,
+    tunnel::Tunnel,
+};
 use async_std::{
     future::timeout,
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream, UdpSocket},
     sync::Arc,
 };
 use async_tungstenite::{
-    async_std::ConnectStream,
+    async_std::{client_async_tls, ConnectStream},
+    client_async,
+    stream::Stream as WsStream,
     tungstenite::{
         protocol::{frame::coding::CloseCode, CloseFrame},
         Error, Message, Result,
@@ -16,10 +25,234 @@ use futures::{
     io::{ReadHalf, WriteHalf},
     join,
     stream::{SplitSink, SplitStream},
-    AsyncReadExt, AsyncWrite, AsyncWriteExt, SinkExt, StreamExt,
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Sink, SinkExt, Stream, StreamExt,
 };
 use log::*;
-use std::sync::atomic;
+use std::{
+    io,
+    pin::Pin,
+    sync::atomic,
+    task::{Context, Poll},
+};
+
+/// A tunnel that has finished the chain handshake, or a stream over one,
+/// exposed as a byte stream.
+///
+/// Every write is sent as one binary message and binary/text messages are
+/// read back as plain bytes. Ping and pong are skipped.
+pub struct ChainStream<S: MsgStream = Tunnel> {
+    ws: S,
+    buff: Vec<u8>,
+    pos: usize,
+}
+
+impl<S: MsgStream> ChainStream<S> {
+    pub fn new(ws: S) -> ChainStream<S> {
+        ChainStream {
+            ws,
+            buff: vec![],
+            pos: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.ws
+    }
+}
+
+/// A tunnel or a stream multiplexed over one, whatever the pumps relay.
+pub trait MsgStream: Stream<Item = Result<Message>> + Sink<Message, Error = Error> + Unpin {}
+
+impl<S> MsgStream for S where
+    S: Stream<Item = Result<Message>> + Sink<Message, Error = Error> + Unpin
+{
+}
+
+/// A plain or TLS connection of the client listener.
+pub trait ByteStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S> ByteStream for S where S: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// A connection whose first bytes were read to tell its protocol, and are
+/// read again from it.
+pub struct Rewind<S> {
+    ahead: Vec<u8>,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(ahead: Vec<u8>, inner: S) -> Rewind<S> {
+        Rewind { ahead, inner }
+    }
+}
+
+impl<S: ByteStream> AsyncRead for Rewind<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.ahead.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let n = std::cmp::min(buf.len(), this.ahead.len());
+        buf[..n].copy_from_slice(&this.ahead[..n]);
+        this.ahead.drain(..n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S: ByteStream> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+fn to_io_error(e: Error) -> io::Error {
+    match e {
+        Error::ConnectionClosed | Error::AlreadyClosed => {
+            io::Error::new(io::ErrorKind::BrokenPipe, e)
+        }
+        Error::Io(e) => e,
+        _ => io::Error::other(e),
+    }
+}
+
+impl<S: MsgStream> futures::AsyncRead for ChainStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if this.pos < this.buff.len() {
+                let n = std::cmp::min(buf.len(), this.buff.len() - this.pos);
+                buf[..n].copy_from_slice(&this.buff[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(n));
+            }
+
+            let msg = match Pin::new(&mut this.ws).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(to_io_error(e))),
+                Poll::Ready(Some(Ok(msg))) => msg,
+            };
+            match msg {
+                Message::Binary(data) => this.buff = data,
+                Message::Text(txt) => this.buff = txt.into_bytes(),
+                Message::Ping(_) | Message::Pong(_) => continue,
+                Message::Close(Some(frame)) if frame.code == CloseCode::Policy => {
+                    let e = io::Error::new(io::ErrorKind::PermissionDenied, frame.reason);
+                    return Poll::Ready(Err(e));
+                }
+                Message::Close(_) => return Poll::Ready(Ok(0)),
+            }
+            // an empty message means the other side has finished
+            if this.buff.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            this.pos = 0;
+        }
+    }
+}
+
+impl<S: MsgStream> futures::AsyncWrite for ChainStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // empty binary messages are used as the end-of-stream marker
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let this = self.get_mut();
+        let mut ws = Pin::new(&mut this.ws);
+        match ws.as_mut().poll_ready(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(to_io_error(e))),
+            Poll::Ready(Ok(_)) => {}
+        }
+        match ws.start_send(Message::binary(buf)) {
+            Ok(_) => Poll::Ready(Ok(buf.len())),
+            Err(e) => Poll::Ready(Err(to_io_error(e))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.ws).poll_flush(cx).map_err(to_io_error)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.ws).poll_close(cx).map_err(to_io_error)
+    }
+}
+
+fn timed_out(phase: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, format!("{} timeout", phase))
+}
+
+// a "wss://" url with a pinned fingerprint skips the usual certificate checks
+async fn client_handshake(
+    url: &str,
+    tcp_stream: TcpStream,
+) -> Result<WebSocketStream<ConnectStream>> {
+    let pin = match tls::pin(url) {
+        Some(p) => p,
+        None => return Ok(client_async_tls(url, tcp_stream).await?.0),
+    };
+    let host = url::Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.to_string()))
+        .unwrap_or_default();
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let tls_stream = tls::connect_pinned(host, tcp_stream, &pin).await?;
+    Ok(client_async(url, WsStream::Tls(tls_stream)).await?.0)
+}
+
+/// Websocket handshake over an established connection, `url` is "ws://..." or "wss://...".
+pub async fn handshake_ws(
+    url: &str,
+    tcp_stream: TcpStream,
+    t: &Timeouts,
+) -> io::Result<WebSocketStream<ConnectStream>> {
+    match timeout(t.handshake(), client_handshake(url, tcp_stream)).await {
+        Ok(Ok(ws_stream)) => Ok(ws_stream),
+        Ok(Err(e)) => Err(to_io_error(e)),
+        Err(_) => Err(timed_out("handshake")),
+    }
+}
+
+/// Connects to a websocket server, a timed out phase is reported as `TimedOut`.
+pub async fn connect_ws(url: &str, t: &Timeouts) -> io::Result<WebSocketStream<ConnectStream>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid url {}", url));
+    let u = url::Url::parse(url).map_err(|_| invalid())?;
+    let host = u.host_str().ok_or_else(invalid)?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = u.port_or_known_default().ok_or_else(invalid)?;
+    let tcp_stream = match timeout(t.connect(), TcpStream::connect((host, port))).await {
+        Ok(r) => r?,
+        Err(_) => return Err(timed_out("connect")),
+    };
+    handshake_ws(url, tcp_stream, t).await
+}
 
 async fn send_msg_ws<S>(wsw: &mut SplitSink<S, Message>, msg: Message) -> Result<()>
 where
@@ -40,6 +273,7 @@ where
             if n < 1 {
                 finished
             } else {
+                count_relayed(n, &r);
                 r
             }
         }
@@ -49,67 +283,77 @@ where
             if n < 1 {
                 finished
             } else {
+                count_relayed(n, &r);
                 r
             }
         }
-        Message::Ping(_) | Message::Pong(_) => {
-            info!("send ping pong websocket message");
-            Ok(())
-        }
+        // pings keep warm chains open up to the server waiting for a header
+        Message::Ping(data) => wsw.send(Message::Ping(data)).await,
+        Message::Pong(data) => wsw.send(Message::Pong(data)).await,
     }
 }
 
-async fn send_msg_tcp(tcpw: &mut WriteHalf<TcpStream>, msg: Message) -> Result<()> {
+// a message read from one tunnel and sent into another
+fn count_relayed(n: usize, r: &Result<()>) {
+    metrics::bytes_in(n);
+    if r.is_ok() {
+        metrics::bytes_out(n);
+    }
+}
+
+async fn send_msg_tcp<T: ByteStream>(tcpw: &mut WriteHalf<T>, msg: Message) -> Result<()> {
     let finished = Err(Error::ConnectionClosed);
-    let _ = match msg {
+    match msg {
         Message::Binary(buff) => {
+            metrics::bytes_in(buff.len());
             let r = tcpw.write_all(&buff).await;
-            if buff.len() > 0 {
-                if let Ok(_) = r {
-                    return Ok(());
-                }
+            if !buff.is_empty() && r.is_ok() {
+                return Ok(());
             }
         }
         Message::Text(txt) => {
+            metrics::bytes_in(txt.len());
             let r = tcpw.write_all(txt.as_bytes()).await;
-            if txt.len() > 0 {
-                if let Ok(_) = r {
-                    return Ok(());
-                }
+            if !txt.is_empty() && r.is_ok() {
+                return Ok(());
             }
         }
         Message::Ping(_) | Message::Pong(_) => return Ok(()),
+        Message::Close(Some(frame)) if !frame.reason.is_empty() => {
+            info!("closed by remote: {}", frame.reason);
+            return finished;
+        }
         _ => return finished,
     };
-    return finished;
+    finished
 }
 
-async fn close_tcp<S>(r: ReadHalf<S>, w: WriteHalf<S>)
+async fn close_tcp<S>(r: ReadHalf<S>, w: WriteHalf<S>, t: &Timeouts)
 where
     S: AsyncWrite + Unpin,
 {
     if let Ok(mut stream) = w.reunite(r) {
-        let _ = timeout(CONN_TIMEOUT, stream.close()).await;
+        let _ = timeout(t.close(), stream.close()).await;
     }
 }
 
-async fn close_ws<S, M>(r: SplitStream<S>, w: SplitSink<S, M>)
+async fn close_ws<S, M>(r: SplitStream<S>, w: SplitSink<S, M>, t: &Timeouts)
 where
     S: futures::Sink<M> + Unpin,
 {
     if let Ok(mut stream) = w.reunite(r) {
-        let _ = timeout(CONN_TIMEOUT, stream.close()).await;
+        let _ = timeout(t.close(), stream.close()).await;
     }
 }
 
-async fn copy_ws_ws<S>(wsr: &mut SplitStream<S>, wsw: &mut SplitSink<S, Message>)
+async fn copy_ws_ws<S>(wsr: &mut SplitStream<S>, wsw: &mut SplitSink<S, Message>, t: &Timeouts)
 where
     S: futures::Stream<Item = Result<Message>>
         + futures::Sink<Message, Error = async_tungstenite::tungstenite::Error>
         + Unpin,
 {
-    while let Ok(Some(Ok(msg))) = timeout(CONN_TIMEOUT, wsr.next()).await {
-        if let Ok(Ok(_)) = timeout(CONN_TIMEOUT, send_msg_ws(wsw, msg)).await {
+    while let Ok(Some(Ok(msg))) = timeout(t.idle(), wsr.next()).await {
+        if let Ok(Ok(_)) = timeout(t.idle(), send_msg_ws(wsw, msg)).await {
             continue;
         }
         break;
@@ -117,22 +361,26 @@ where
 }
 
 // both directions
-pub async fn pump_ws_ws(ws1: WebSocketStream<ConnectStream>, ws2: WebSocketStream<ConnectStream>) {
+pub async fn pump_ws_ws(ws1: Tunnel, ws2: Tunnel, t: &Timeouts) {
     debug!("pump ws <-> ws");
     let (mut w1, mut r1) = ws1.split();
     let (mut w2, mut r2) = ws2.split();
 
-    let _ = join!(copy_ws_ws(&mut r2, &mut w1), copy_ws_ws(&mut r1, &mut w2));
+    let _ = join!(
+        copy_ws_ws(&mut r2, &mut w1, t),
+        copy_ws_ws(&mut r1, &mut w2, t)
+    );
 
-    let _ = join!(close_ws(r1, w1), close_ws(r2, w2));
+    let _ = join!(close_ws(r1, w1, t), close_ws(r2, w2, t));
     debug!("ws <= x => ws");
 }
 
-pub async fn send_socks5_udp_pkg_to_remote_host(sender: &UdpSocket, buf: &Vec<u8>) {
+pub async fn send_socks5_udp_pkg_to_remote_host(sender: &UdpSocket, buf: &[u8], acl: &Acl) {
     use bytes::Buf;
     use std::net::ToSocketAddrs;
 
     let mut dest: Option<SocketAddr> = None;
+    let mut host = String::new();
     let mut idx = 0usize;
     //processing receved packet from client
     if buf[0] == 0x00 && buf[1] == 0x00 && buf[2] == 0x00 {
@@ -153,9 +401,9 @@ pub async fn send_socks5_udp_pkg_to_remote_host(sender: &UdpSocket, buf: &Vec<u8
                 let port: u16 = buf[5 + len..5 + 2 + len].as_ref().get_u16();
                 if let Ok(addr) = std::str::from_utf8(&buf[5..5 + len]) {
                     let addr_port = format!("{}:{}", addr, port);
-                    if let Ok(iter) = addr_port.to_socket_addrs() {
-                        let servs: Vec<_> = iter.collect();
-                        dest = Some(servs[0]);
+                    if let Ok(mut iter) = addr_port.to_socket_addrs() {
+                        dest = iter.next();
+                        host = addr.to_string();
                         idx = 5 + 2 + len;
                     }
                 }
@@ -176,22 +424,33 @@ pub async fn send_socks5_udp_pkg_to_remote_host(sender: &UdpSocket, buf: &Vec<u8
     }
 
     if let Some(addr) = dest {
-        let _ = sender.send_to(&buf[idx..], addr).await;
+        if host.is_empty() {
+            host = addr.ip().to_string();
+        }
+        match acl.check(&Cmds::UdpAssoc, &host, Some(&addr.ip()), addr.port()) {
+            Ok(_) => {
+                let _ = sender.send_to(&buf[idx..], addr).await;
+            }
+            Err(e) => debug!("drop udp packet to {}: {}", host, e),
+        }
     } else {
         info!("parse addr fail!");
     }
 }
 
-async fn copy_ws_udp_to_remote_host(
-    wsr: &mut SplitStream<WebSocketStream<ConnectStream>>,
+async fn copy_ws_udp_to_remote_host<S: MsgStream>(
+    wsr: &mut SplitStream<S>,
     udpw: &mut Arc<UdpSocket>,
+    acl: &Acl,
+    t: &Timeouts,
 ) {
-    while let Ok(result) = timeout(CONN_TIMEOUT, wsr.next()).await {
+    while let Ok(result) = timeout(t.idle(), wsr.next()).await {
         if let Some(Ok(msg)) = result {
             match msg {
                 Message::Binary(buff) => {
-                    if buff.len() > 0 {
-                        send_socks5_udp_pkg_to_remote_host(&*udpw, &buff).await;
+                    if !buff.is_empty() {
+                        metrics::udp_in();
+                        send_socks5_udp_pkg_to_remote_host(&*udpw, &buff, acl).await;
                         continue;
                     }
                 }
@@ -206,12 +465,13 @@ async fn copy_ws_udp_to_remote_host(
     }
 }
 
-async fn copy_ws_udp_from_remote_host(
+async fn copy_ws_udp_from_remote_host<S: MsgStream>(
     udpr: &mut Arc<UdpSocket>,
-    wsw: &mut SplitSink<WebSocketStream<ConnectStream>, Message>,
+    wsw: &mut SplitSink<S, Message>,
+    t: &Timeouts,
 ) {
     let mut buff = vec![0u8; BUFF_LEN];
-    while let Ok(Ok((len, s))) = timeout(UDP_TIMEOUT, udpr.recv_from(&mut buff)).await {
+    while let Ok(Ok((len, s))) = timeout(t.udp(), udpr.recv_from(&mut buff)).await {
         // debug!("Recv udp from {}: len {}", s, len);
         if len > 0 {
             let addr = super::utils::addr_to_vec(s);
@@ -219,7 +479,8 @@ async fn copy_ws_udp_from_remote_host(
             b.extend(&addr);
             b.extend(&buff[..len]);
             let msg = Message::binary(b);
-            if let Ok(Ok(_)) = timeout(CONN_TIMEOUT, wsw.send(msg)).await {
+            if let Ok(Ok(_)) = timeout(t.idle(), wsw.send(msg)).await {
+                metrics::udp_out();
                 continue;
             }
         }
@@ -227,60 +488,66 @@ async fn copy_ws_udp_from_remote_host(
     }
 }
 
-pub async fn close_ws_stream(mut websocket: WebSocketStream<ConnectStream>) {
+pub async fn close_ws_stream(mut websocket: WebSocketStream<ConnectStream>, t: &Timeouts) {
     let close_frame = CloseFrame {
         code: CloseCode::Away,
         reason: Default::default(),
     };
-    let _ = timeout(CONN_TIMEOUT, websocket.close(Some(close_frame))).await;
+    let _ = timeout(t.close(), websocket.close(Some(close_frame))).await;
 }
 
-pub async fn pump_ws_udp_remote_host(
-    ws_stream: WebSocketStream<ConnectStream>,
+pub async fn pump_ws_udp_remote_host<S: MsgStream>(
+    ws_stream: S,
     udp_socket: UdpSocket,
+    acl: &Acl,
+    t: &Timeouts,
 ) {
     let mut udpw = Arc::new(udp_socket);
     let mut udpr = udpw.clone();
     let (mut wsw, mut wsr) = ws_stream.split();
 
     join!(
-        copy_ws_udp_to_remote_host(&mut wsr, &mut udpw),
-        copy_ws_udp_from_remote_host(&mut udpr, &mut wsw),
+        copy_ws_udp_to_remote_host(&mut wsr, &mut udpw, acl, t),
+        copy_ws_udp_from_remote_host(&mut udpr, &mut wsw, t),
     );
 
-    close_ws(wsr, wsw).await;
+    close_ws(wsr, wsw, t).await;
     debug!("local ws <= x => outlet udp");
 }
 
-async fn get_client_addr_from_first_udp_pkg(
+async fn get_client_addr_from_first_udp_pkg<S: MsgStream>(
     udpr: &mut Arc<UdpSocket>,
-    wsw: &mut SplitSink<WebSocketStream<ConnectStream>, Message>,
+    wsw: &mut SplitSink<S, Message>,
+    t: &Timeouts,
 ) -> Option<SocketAddr> {
     let mut buff = vec![0u8; BUFF_LEN];
-    if let Ok(Ok((n, src_addr))) = timeout(UDP_TIMEOUT, udpr.recv_from(&mut buff)).await {
+    if let Ok(Ok((n, src_addr))) = timeout(t.udp(), udpr.recv_from(&mut buff)).await {
         if n > 0 {
             let msg = Message::binary(&buff[0..n]);
-            if let Ok(_) = timeout(CONN_TIMEOUT, wsw.send(msg)).await {
+            if timeout(t.idle(), wsw.send(msg)).await.is_ok() {
+                metrics::udp_out();
                 return Some(src_addr);
             }
         }
     }
-    return None;
+    None
 }
 
-async fn copy_ws_udp_from_local_client(
+async fn copy_ws_udp_from_local_client<S: MsgStream>(
     udpr: &mut Arc<UdpSocket>,
-    wsw: &mut SplitSink<WebSocketStream<ConnectStream>, Message>,
+    wsw: &mut SplitSink<S, Message>,
     sig_close: Arc<atomic::AtomicBool>,
+    t: &Timeouts,
 ) {
     let mut buff = vec![0u8; BUFF_LEN];
-    while let Ok(Ok((len, _))) = timeout(UDP_TIMEOUT, udpr.recv_from(&mut buff)).await {
+    while let Ok(Ok((len, _))) = timeout(t.udp(), udpr.recv_from(&mut buff)).await {
         if sig_close.load(atomic::Ordering::Relaxed) {
             break;
         }
         if len > 0 {
             let msg = Message::binary(&buff[0..len]);
-            if let Ok(Ok(_)) = timeout(CONN_TIMEOUT, wsw.send(msg)).await {
+            if let Ok(Ok(_)) = timeout(t.idle(), wsw.send(msg)).await {
+                metrics::udp_out();
                 continue;
             }
         }
@@ -288,13 +555,14 @@ async fn copy_ws_udp_from_local_client(
     }
 }
 
-async fn copy_ws_udp_to_local_client(
-    wsr: &mut SplitStream<WebSocketStream<ConnectStream>>,
+async fn copy_ws_udp_to_local_client<S: MsgStream>(
+    wsr: &mut SplitStream<S>,
     udpw: &mut Arc<UdpSocket>,
     client_addr: SocketAddr,
     sig_close: Arc<atomic::AtomicBool>,
+    t: &Timeouts,
 ) {
-    while let Ok(result) = timeout(CONN_TIMEOUT, wsr.next()).await {
+    while let Ok(result) = timeout(t.idle(), wsr.next()).await {
         if sig_close.load(atomic::Ordering::Relaxed) {
             break;
         }
@@ -302,8 +570,11 @@ async fn copy_ws_udp_to_local_client(
         if let Some(Ok(msg)) = result {
             match msg {
                 Message::Binary(buff) => {
-                    if buff.len() > 0 {
-                        if let Ok(_) = timeout(UDP_TIMEOUT, udpw.send_to(&buff, client_addr)).await
+                    if !buff.is_empty() {
+                        metrics::udp_in();
+                        if timeout(t.udp(), udpw.send_to(&buff, client_addr))
+                            .await
+                            .is_ok()
                         {
                             continue;
                         } else {
@@ -322,34 +593,39 @@ async fn copy_ws_udp_to_local_client(
     }
 }
 
-pub async fn pump_ws_udp_local_client(
+pub async fn pump_ws_udp_local_client<S: MsgStream>(
     udp_socket: UdpSocket,
-    ws_stream: WebSocketStream<ConnectStream>,
+    ws_stream: S,
     sig_close: Arc<atomic::AtomicBool>,
+    t: &Timeouts,
 ) {
     let mut udpw = Arc::new(udp_socket);
     let mut udpr = udpw.clone();
     let (mut wsw, mut wsr) = ws_stream.split();
 
-    let r = get_client_addr_from_first_udp_pkg(&mut udpr, &mut wsw).await;
+    let r = get_client_addr_from_first_udp_pkg(&mut udpr, &mut wsw, t).await;
     if let Some(src_addr) = r {
         join!(
-            copy_ws_udp_to_local_client(&mut wsr, &mut udpw, src_addr, sig_close.clone()),
-            copy_ws_udp_from_local_client(&mut udpr, &mut wsw, sig_close),
+            copy_ws_udp_to_local_client(&mut wsr, &mut udpw, src_addr, sig_close.clone(), t),
+            copy_ws_udp_from_local_client(&mut udpr, &mut wsw, sig_close, t),
         );
     }
-    close_ws(wsr, wsw).await;
+    close_ws(wsr, wsw, t).await;
 }
 
-pub async fn pump_ws_tcp(tcp_stream: TcpStream, ws_stream: WebSocketStream<ConnectStream>) {
+pub async fn pump_ws_tcp<T, S>(tcp_stream: T, ws_stream: S, t: &Timeouts)
+where
+    T: ByteStream,
+    S: MsgStream,
+{
     debug!("pump ws <-> tcp");
 
     let (mut tcpr, mut tcpw) = tcp_stream.split();
     let (mut wsw, mut wsr) = ws_stream.split();
 
     let ws2tcp = async {
-        while let Ok(Some(Ok(msg))) = timeout(CONN_TIMEOUT, wsr.next()).await {
-            if let Ok(Ok(_)) = timeout(CONN_TIMEOUT, send_msg_tcp(&mut tcpw, msg)).await {
+        while let Ok(Some(Ok(msg))) = timeout(t.idle(), wsr.next()).await {
+            if let Ok(Ok(_)) = timeout(t.idle(), send_msg_tcp(&mut tcpw, msg)).await {
                 continue;
             }
             break;
@@ -358,11 +634,12 @@ pub async fn pump_ws_tcp(tcp_stream: TcpStream, ws_stream: WebSocketStream<Conne
 
     let tcp2ws = async {
         let mut buff = vec![0u8; BUFF_LEN];
-        while let Ok(result) = timeout(CONN_TIMEOUT, tcpr.read(&mut buff)).await {
+        while let Ok(result) = timeout(t.idle(), tcpr.read(&mut buff)).await {
             if let Ok(len) = result {
                 if len > 0 {
                     let msg = Message::binary(&buff[0..len]);
-                    if let Ok(Ok(_)) = timeout(CONN_TIMEOUT, wsw.send(msg)).await {
+                    if let Ok(Ok(_)) = timeout(t.idle(), wsw.send(msg)).await {
+                        metrics::bytes_out(len);
                         continue;
                     }
                 }
@@ -372,7 +649,77 @@ pub async fn pump_ws_tcp(tcp_stream: TcpStream, ws_stream: WebSocketStream<Conne
     };
 
     join!(ws2tcp, tcp2ws);
-    join!(close_ws(wsr, wsw), close_tcp(tcpr, tcpw));
+    join!(close_ws(wsr, wsw, t), close_tcp(tcpr, tcpw, t));
 
     debug!("tcp <= x => ws");
 }
+
+// `local` is a connection of the client listener, `remote` the target
+pub async fn pump_tcp_tcp<T: ByteStream>(local: T, remote: TcpStream, t: &Timeouts) {
+    debug!("pump tcp <-> tcp");
+
+    let (mut localr, mut localw) = local.split();
+    let local2remote = async {
+        let mut buff = vec![0u8; BUFF_LEN];
+        let mut w = &remote;
+        while let Ok(Ok(len)) = timeout(t.idle(), localr.read(&mut buff)).await {
+            if len < 1 {
+                break;
+            }
+            if let Ok(Ok(_)) = timeout(t.idle(), w.write_all(&buff[0..len])).await {
+                continue;
+            }
+            break;
+        }
+        let _ = remote.shutdown(std::net::Shutdown::Write);
+    };
+    let remote2local = async {
+        let mut buff = vec![0u8; BUFF_LEN];
+        let mut r = &remote;
+        while let Ok(Ok(len)) = timeout(t.idle(), r.read(&mut buff)).await {
+            if len < 1 {
+                break;
+            }
+            if let Ok(Ok(_)) = timeout(t.idle(), localw.write_all(&buff[0..len])).await {
+                continue;
+            }
+            break;
+        }
+        let _ = timeout(t.close(), localw.close()).await;
+    };
+
+    join!(local2remote, remote2local);
+    debug!("tcp <= x => tcp");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{net::TcpListener, task};
+
+    #[test]
+    fn connect_ws_timeout_tests() {
+        task::block_on(async {
+            // accepts tcp connections but never answers the websocket handshake
+            let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}", silent.local_addr().unwrap());
+            task::spawn(async move {
+                let mut conns = vec![];
+                while let Ok((stream, _)) = silent.accept().await {
+                    conns.push(stream);
+                }
+            });
+            let t = Timeouts {
+                handshake: 1,
+                ..Default::default()
+            };
+            let start = std::time::Instant::now();
+            let e = connect_ws(&url, &t).await.err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+            assert!(start.elapsed() < std::time::Duration::from_secs(5));
+
+            let e = connect_ws("ws://", &t).await.err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        });
+    }
+}
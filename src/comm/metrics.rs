@@ -0,0 +1,272 @@
+use crate::comm::models::{Cmds, Status};
+use async_std::{
+    io::{ReadExt, WriteExt},
+    net::{TcpListener, TcpStream},
+    task,
+};
+use futures::StreamExt;
+use log::*;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Why a websocket handshake or header exchange failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Failure {
+    Timeout,
+    // not a websocket or not a header
+    Invalid,
+    // the header does not decrypt with any key of the server
+    Decrypt,
+    // the server replied with a hash that does not match the header
+    BadHash,
+    Unauthorized,
+    Replay,
+}
+
+impl Failure {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Failure::Timeout => "timeout",
+            Failure::Invalid => "invalid",
+            Failure::Decrypt => "decrypt",
+            Failure::BadHash => "bad_hash",
+            Failure::Unauthorized => "unauthorized",
+            Failure::Replay => "replay",
+        }
+    }
+}
+
+// values keyed by one label
+#[derive(Default)]
+struct Family(Mutex<BTreeMap<String, i64>>);
+
+impl Family {
+    fn add(&self, label: &str, n: i64) {
+        *self.0.lock().unwrap().entry(label.to_string()).or_default() += n;
+    }
+
+    fn render(&self, out: &mut String, name: &str, label: &str) {
+        for (k, v) in self.0.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, k, v);
+        }
+    }
+}
+
+const CHAIN_BUCKETS: [u64; 7] = [1, 2, 3, 4, 5, 6, 8];
+
+/// Counters of this process, see `render()` for the names.
+#[derive(Default)]
+pub struct Metrics {
+    tunnels: Family,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    udp_in: AtomicU64,
+    udp_out: AtomicU64,
+    handshake_failures: Family,
+    dial_failures: Family,
+    chain_buckets: [AtomicU64; CHAIN_BUCKETS.len()],
+    chain_sum: AtomicU64,
+    chain_count: AtomicU64,
+}
+
+lazy_static::lazy_static! {
+    static ref METRICS: Metrics = Metrics::default();
+}
+
+/// Counts an active tunnel of `cmd` until dropped.
+pub struct TunnelGuard(String);
+
+impl Drop for TunnelGuard {
+    fn drop(&mut self) {
+        METRICS.tunnels.add(&self.0, -1);
+    }
+}
+
+pub fn tunnel(cmd: &Cmds) -> TunnelGuard {
+    let label = format!("{:?}", cmd);
+    METRICS.tunnels.add(&label, 1);
+    TunnelGuard(label)
+}
+
+/// Payload bytes received from a websocket tunnel.
+pub fn bytes_in(n: usize) {
+    METRICS.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+/// Payload bytes sent into a websocket tunnel.
+pub fn bytes_out(n: usize) {
+    METRICS.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+/// An udp packet received from a websocket tunnel.
+pub fn udp_in() {
+    METRICS.udp_in.fetch_add(1, Ordering::Relaxed);
+}
+
+/// An udp packet sent into a websocket tunnel.
+pub fn udp_out() {
+    METRICS.udp_out.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn handshake_failed(reason: Failure) {
+    METRICS.handshake_failures.add(reason.as_str(), 1);
+}
+
+pub fn dial_failed(status: &Status) {
+    METRICS.dial_failures.add(&format!("{:?}", status), 1);
+}
+
+/// Number of servers in a chain that connected.
+pub fn chain_length(n: usize) {
+    let n = n as u64;
+    for (i, le) in CHAIN_BUCKETS.iter().enumerate() {
+        if n <= *le {
+            METRICS.chain_buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    METRICS.chain_sum.fetch_add(n, Ordering::Relaxed);
+    METRICS.chain_count.fetch_add(1, Ordering::Relaxed);
+}
+
+/// All metrics in the Prometheus text format.
+pub fn render() -> String {
+    let m = &*METRICS;
+    let mut out = String::new();
+    let header = |out: &mut String, name: &str, help: &str, kind: &str| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    };
+    let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+
+    let name = "thomas_active_tunnels";
+    header(&mut out, name, "Tunnels in use by command.", "gauge");
+    m.tunnels.render(&mut out, name, "cmd");
+
+    let name = "thomas_bytes_total";
+    header(
+        &mut out,
+        name,
+        "Payload bytes through websocket tunnels.",
+        "counter",
+    );
+    let _ = writeln!(out, "{}{{direction=\"in\"}} {}", name, load(&m.bytes_in));
+    let _ = writeln!(out, "{}{{direction=\"out\"}} {}", name, load(&m.bytes_out));
+
+    let name = "thomas_udp_packets_total";
+    header(
+        &mut out,
+        name,
+        "UDP packets through websocket tunnels.",
+        "counter",
+    );
+    let _ = writeln!(out, "{}{{direction=\"in\"}} {}", name, load(&m.udp_in));
+    let _ = writeln!(out, "{}{{direction=\"out\"}} {}", name, load(&m.udp_out));
+
+    let name = "thomas_handshake_failures_total";
+    header(&mut out, name, "Failed handshakes by reason.", "counter");
+    m.handshake_failures.render(&mut out, name, "reason");
+
+    let name = "thomas_dial_failures_total";
+    header(&mut out, name, "Failed dials by status.", "counter");
+    m.dial_failures.render(&mut out, name, "status");
+
+    let name = "thomas_chain_length";
+    header(&mut out, name, "Servers in connected chains.", "histogram");
+    for (i, le) in CHAIN_BUCKETS.iter().enumerate() {
+        let n = load(&m.chain_buckets[i]);
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, n);
+    }
+    let count = load(&m.chain_count);
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+    let _ = writeln!(out, "{}_sum {}", name, load(&m.chain_sum));
+    let _ = writeln!(out, "{}_count {}", name, count);
+    out
+}
+
+async fn handle_request(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut buff = vec![0u8; 1024];
+    let n = stream.read(&mut buff).await?;
+    let req = String::from_utf8_lossy(&buff[..n]);
+    let path = req.split_whitespace().nth(1).unwrap_or_default();
+    let resp = match path {
+        "/metrics" => {
+            let body = render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(resp.as_bytes()).await
+}
+
+/// Serves `GET /metrics` on `listener`.
+pub async fn run(listener: TcpListener) {
+    while let Some(Ok(stream)) = listener.incoming().next().await {
+        task::spawn(async move {
+            if let Err(e) = handle_request(stream).await {
+                debug!("metrics request failed: {}", e);
+            }
+        });
+    }
+}
+
+pub async fn serv(addr: String) {
+    match TcpListener::bind(&addr).await {
+        Ok(listener) => {
+            info!("metrics on http://{}/metrics", addr);
+            run(listener).await;
+        }
+        Err(e) => error!("failed to listen on {}: {}", addr, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_tests() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            task::spawn(run(listener));
+
+            let guard = tunnel(&Cmds::Bind);
+            bytes_in(5);
+            handshake_failed(Failure::BadHash);
+            dial_failed(&Status::NextHopUnreachable);
+            chain_length(3);
+
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(b"GET /metrics HTTP/1.1\r\n\r\n")
+                .await
+                .unwrap();
+            let mut resp = String::new();
+            stream.read_to_string(&mut resp).await.unwrap();
+            assert!(resp.starts_with("HTTP/1.1 200 OK"));
+            assert!(resp.contains("thomas_active_tunnels{cmd=\"Bind\"} 1"));
+            assert!(resp.contains("thomas_handshake_failures_total{reason=\"bad_hash\"}"));
+            assert!(resp.contains("thomas_dial_failures_total{status=\"NextHopUnreachable\"}"));
+            assert!(resp.contains("thomas_chain_length_bucket{le=\"+Inf\"}"));
+
+            drop(guard);
+            assert!(render().contains("thomas_active_tunnels{cmd=\"Bind\"} 0"));
+
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+            let mut resp = String::new();
+            stream.read_to_string(&mut resp).await.unwrap();
+            assert!(resp.starts_with("HTTP/1.1 404"));
+        });
+    }
+}
@@ -1,11 +1,12 @@
+pub mod acl;
 pub mod cons;
-pub mod logging;
-
-#[allow(dead_code)]
 pub mod infrs;
-
-#[allow(dead_code)]
-pub mod utils;
-
-#[allow(dead_code)]
+pub mod logging;
+pub mod metrics;
 pub mod models;
+pub mod mux;
+pub mod replay;
+pub mod router;
+pub mod tls;
+pub mod tunnel;
+pub mod utils;
@@ -1,49 +1,299 @@
 use crate::comm::utils;
 use serde::{Deserialize, Serialize};
+use std::{
+    convert::{TryFrom, TryInto},
+    time::Duration,
+};
+use x25519_dalek::{PublicKey, StaticSecret};
 
-pub struct CloseSignal {
-    closed: std::cell::Cell<bool>,
+/// Wire format of the headers a client sends, servers accept all of them.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderFormat {
+    // a json text message with its version, cipher and hop
+    #[default]
+    Json,
+    // a binary message with fixed fields, see `EncHeader::to_bytes`
+    Binary,
+    // the json of v1.1.4 and older, the only format of old servers
+    Legacy,
 }
 
-impl CloseSignal {
-    pub fn new() -> CloseSignal {
-        CloseSignal {
-            closed: std::cell::Cell::new(false),
-        }
-    }
-
-    pub fn is_closed(&self) -> bool {
-        self.closed.get()
-    }
+/// AEAD of headers, legacy headers always use aes-256-gcm.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Cipher {
+    #[default]
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm = 0x01,
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305 = 0x02,
+}
 
-    pub fn close(&self) {
-        self.closed.set(true);
+impl Cipher {
+    pub fn from_u8(b: u8) -> Option<Cipher> {
+        match b {
+            0x01 => Some(Cipher::Aes256Gcm),
+            0x02 => Some(Cipher::ChaCha20Poly1305),
+            _ => None,
+        }
     }
 }
 
+const HEADER_V1: u8 = 0x01;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct EncHeader {
     pub nonce: Vec<u8>,
     pub pubkey: [u8; 32],
     pub ciphertext: Vec<u8>,
+    // also the format of the plaintext
+    #[serde(skip)]
+    pub format: HeaderFormat,
+    // legacy headers have none of the fields below
+    #[serde(default)]
+    pub version: u8,
+    #[serde(default)]
+    pub cipher: Cipher,
+    // 0 is the outlet and counts up to the inlet
+    #[serde(default)]
+    pub hop: u8,
+}
+
+// context of the key (client to server) and the hash (server to client) of a header
+fn header_label(direction: &str, hop: u8) -> String {
+    format!("thomas header v{HEADER_V1} {direction} hop {hop}")
+}
+
+// the hash a server replies with for a plain header
+fn header_hash(pubkey: &[u8; 32], plaintext: &[u8], format: HeaderFormat, hop: u8) -> Vec<u8> {
+    match format {
+        HeaderFormat::Legacy => {
+            let text = String::from_utf8_lossy(plaintext);
+            utils::sha256(&format!("{pubkey:?}{text}"))
+        }
+        HeaderFormat::Json | HeaderFormat::Binary => {
+            let label = header_label("s2c", hop);
+            utils::sha256_parts(&[label.as_bytes(), pubkey, plaintext])
+        }
+    }
+}
+
+// the AEAD key of a header from the ECDH `key` of its hop
+fn header_key(key: &str, format: HeaderFormat, hop: u8) -> Option<Vec<u8>> {
+    match format {
+        HeaderFormat::Legacy => Some(utils::sha256(key)),
+        HeaderFormat::Json | HeaderFormat::Binary => {
+            utils::hkdf_sha256(key, &header_label("c2s", hop))
+        }
+    }
 }
 
 impl EncHeader {
     pub fn decrypt(&self, pubkey: &[u8; 32], key: &str) -> Option<(HeaderFrame, Vec<u8>)> {
-        if let Some(text) = utils::aes_decrypt(&self.nonce, &self.ciphertext, key) {
-            if let Ok(header) = serde_json::from_str(&text) {
-                let hash = utils::sha256(&format!("{pubkey:?}{text}"));
-                return Some((header, hash));
+        let aead_key = header_key(key, self.format, self.hop)?;
+        let plaintext = utils::aead_decrypt(self.cipher, &aead_key, &self.nonce, &self.ciphertext)?;
+        let header = match self.format {
+            HeaderFormat::Json | HeaderFormat::Legacy => serde_json::from_slice(&plaintext).ok()?,
+            HeaderFormat::Binary => HeaderFrame::from_bytes(&plaintext)?,
+        };
+        Some((
+            header,
+            header_hash(pubkey, &plaintext, self.format, self.hop),
+        ))
+    }
+
+    /// Parses a json header, one without a version comes from an old client.
+    pub fn from_text(text: &str) -> Option<EncHeader> {
+        let mut header: EncHeader = serde_json::from_str(text).ok()?;
+        match header.version {
+            0 => {
+                header.format = HeaderFormat::Legacy;
+                header.cipher = Cipher::Aes256Gcm;
+                header.hop = 0;
             }
+            HEADER_V1 => header.format = HeaderFormat::Json,
+            _ => return None,
         }
-        None
+        Some(header)
     }
 
     pub fn to_string(&self) -> Option<String> {
         if let Ok(r) = serde_json::to_string(&self) {
             return Some(r);
         }
-        return None;
+        None
+    }
+
+    /// `[version: 0x01][cipher][hop][pubkey: 32][nonce: 12][ciphertext]`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![HEADER_V1, self.cipher as u8, self.hop];
+        buf.extend(self.pubkey);
+        buf.extend(&self.nonce);
+        buf.extend(&self.ciphertext);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<EncHeader> {
+        let mut fields = Fields(buf);
+        if fields.u8()? != HEADER_V1 {
+            return None;
+        }
+        Some(EncHeader {
+            cipher: Cipher::from_u8(fields.u8()?)?,
+            hop: fields.u8()?,
+            pubkey: fields.take(32)?.try_into().ok()?,
+            nonce: fields.take(12)?.to_vec(),
+            ciphertext: fields.0.to_vec(),
+            format: HeaderFormat::Binary,
+            version: HEADER_V1,
+        })
+    }
+}
+
+// reads the fields of a binary header one by one
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (field, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(field)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    // a field after its length in one byte
+    fn short(&mut self) -> Option<&'a [u8]> {
+        let n = self.u8()? as usize;
+        self.take(n)
+    }
+
+    // a field after its length in two bytes
+    fn long(&mut self) -> Option<&'a [u8]> {
+        let n = u16::from_be_bytes(self.take(2)?.try_into().ok()?) as usize;
+        self.take(n)
+    }
+}
+
+fn put_short(buf: &mut Vec<u8>, field: &[u8]) -> Option<()> {
+    buf.push(u8::try_from(field.len()).ok()?);
+    buf.extend(field);
+    Some(())
+}
+
+fn put_long(buf: &mut Vec<u8>, field: &[u8]) -> Option<()> {
+    buf.extend(u16::try_from(field.len()).ok()?.to_be_bytes());
+    buf.extend(field);
+    Some(())
+}
+
+/// Result of a hop, sent by the server after the hash.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Ok,
+    GeneralFailure,
+    Denied,
+    NextHopUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+    TtlExpired,
+}
+
+impl Status {
+    pub fn socks5_reply(&self) -> u8 {
+        match self {
+            Status::Ok => 0x00,
+            Status::GeneralFailure => 0x01,
+            Status::Denied => 0x02,
+            Status::NextHopUnreachable => 0x03,
+            Status::HostUnreachable => 0x04,
+            Status::ConnectionRefused => 0x05,
+            Status::TtlExpired => 0x06,
+        }
+    }
+
+    pub fn http_status(&self) -> &'static str {
+        match self {
+            Status::Ok => "200 Connection Established",
+            Status::Denied => "403 Forbidden",
+            Status::NextHopUnreachable => "503 Service Unavailable",
+            Status::TtlExpired => "504 Gateway Timeout",
+            _ => "502 Bad Gateway",
+        }
+    }
+
+    pub fn from_io_error(e: &std::io::Error) -> Status {
+        match e.kind() {
+            std::io::ErrorKind::ConnectionRefused => Status::ConnectionRefused,
+            std::io::ErrorKind::TimedOut => Status::TtlExpired,
+            _ => Status::HostUnreachable,
+        }
+    }
+
+    pub fn to_message(self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+
+    pub fn from_message(text: &str) -> Option<Status> {
+        serde_json::from_str(text).ok()
+    }
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Status::Ok => "succeeded",
+            Status::GeneralFailure => "general failure",
+            Status::Denied => "denied by rule",
+            Status::NextHopUnreachable => "next hop unreachable",
+            Status::HostUnreachable => "host unreachable",
+            Status::ConnectionRefused => "connection refused",
+            Status::TtlExpired => "ttl expired",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Why a chain could not be built. `hop` is the name of the node that failed.
+#[derive(Debug, Clone)]
+pub struct DialError {
+    pub status: Status,
+    pub hop: String,
+}
+
+impl DialError {
+    pub fn new(status: Status, hop: &str) -> DialError {
+        DialError {
+            status,
+            hop: hop.to_string(),
+        }
+    }
+
+    /// Failures of a node, another chain may work.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.status,
+            Status::GeneralFailure | Status::NextHopUnreachable | Status::TtlExpired
+        )
+    }
+}
+
+impl std::fmt::Display for DialError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}", self.hop, self.status)
+    }
+}
+
+impl std::error::Error for DialError {}
+
+impl From<DialError> for async_tungstenite::tungstenite::Error {
+    fn from(e: DialError) -> Self {
+        std::io::Error::other(e).into()
     }
 }
 
@@ -53,6 +303,24 @@ pub enum Cmds {
     Connect = 0x01,
     Bind = 0x02,
     UdpAssoc = 0x03,
+    // handshake only, used by health checks
+    Ping = 0x04,
+    // streams multiplexed over the tunnel, see comm::mux
+    Mux = 0x05,
+}
+
+impl Cmds {
+    pub fn from_u8(b: u8) -> Option<Cmds> {
+        match b {
+            0x00 => Some(Cmds::Relay),
+            0x01 => Some(Cmds::Connect),
+            0x02 => Some(Cmds::Bind),
+            0x03 => Some(Cmds::UdpAssoc),
+            0x04 => Some(Cmds::Ping),
+            0x05 => Some(Cmds::Mux),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -60,34 +328,471 @@ pub struct HeaderFrame {
     pub cmd: Cmds,
     pub param: String,
     pub padding: Vec<u8>,
+    // ask the outlet to seal the payload, old servers ignore this field
+    #[serde(default)]
+    pub e2e: bool,
+    // unix time in seconds and a random nonce, for replay protection
+    #[serde(default)]
+    pub timestamp: i64,
+    #[serde(default)]
+    pub nonce: Vec<u8>,
+    // long-term client pubkey and its proof, see sign()
+    #[serde(default)]
+    pub client: String,
+    #[serde(default)]
+    pub proof: Vec<u8>,
+    // ask the server to send a Status message after the hash
+    #[serde(default)]
+    pub status: bool,
 }
 
 impl HeaderFrame {
-    pub fn encrypt(&self, pubkey: &[u8; 32], key: &str) -> Option<(EncHeader, Vec<u8>)> {
-        if let Ok(text) = serde_json::to_string(self) {
-            if let Some((nonce, ciphertext)) = utils::aes_encrypt(&text, key) {
-                let hash = utils::sha256(&format!("{pubkey:?}{text}"));
-                return Some((
-                    EncHeader {
-                        nonce,
-                        pubkey: pubkey.clone(),
-                        ciphertext,
-                    },
-                    hash,
-                ));
-            }
+    pub fn new(cmd: Cmds, param: &str) -> HeaderFrame {
+        HeaderFrame {
+            cmd,
+            param: param.to_string(),
+            padding: utils::rand_padding(),
+            e2e: false,
+            timestamp: chrono::Utc::now().timestamp(),
+            nonce: utils::rand_vec8(16),
+            client: "".to_string(),
+            proof: vec![],
+            status: true,
+        }
+    }
+
+    fn proof_of(shared: &[u8; 32], ephemeral: &[u8; 32], nonce: &[u8]) -> Vec<u8> {
+        let key = base64::encode(shared);
+        utils::sha256(&format!("{key}{ephemeral:?}{nonce:?}"))
+    }
+
+    /// Proves to the server behind `their_pubkey` that this frame comes from the owner of `identity`.
+    /// `ephemeral` is the pubkey in the `EncHeader` that carries this frame.
+    pub fn sign(
+        &mut self,
+        identity: &StaticSecret,
+        their_pubkey: &PublicKey,
+        ephemeral: &[u8; 32],
+    ) {
+        let shared = identity.diffie_hellman(their_pubkey).to_bytes();
+        self.client = base64::encode(PublicKey::from(identity).as_bytes());
+        self.proof = Self::proof_of(&shared, ephemeral, &self.nonce);
+    }
+
+    /// Returns true if the frame carries a valid proof for its `client` key.
+    pub fn verify(&self, secret: &StaticSecret, ephemeral: &[u8; 32]) -> bool {
+        if let Some(client) = utils::b64_to_pubkey(&self.client) {
+            let shared = secret.diffie_hellman(&client).to_bytes();
+            return Self::proof_of(&shared, ephemeral, &self.nonce).eq(&self.proof);
+        }
+        false
+    }
+
+    /// Whether the server seals the payload of this frame. Relays never see the payload.
+    pub fn is_sealed(&self) -> bool {
+        self.e2e && self.cmd != Cmds::Relay
+    }
+
+    /// The hash a server replies with. It acknowledges the features requested by this frame,
+    /// so the client can tell new servers from old ones, which reply with the plain hash.
+    pub fn reply_hash(&self, hash: &[u8]) -> Vec<u8> {
+        let mut features = vec![];
+        if self.is_sealed() {
+            features.push("e2e");
+        }
+        if self.status {
+            features.push("status");
+        }
+        if features.is_empty() {
+            return hash.to_vec();
+        }
+        utils::sha256(&format!("{hash:?}{}", features.join(",")))
+    }
+
+    /// Encrypts this frame for the node `hop` hops before the outlet, legacy
+    /// headers always use aes-256-gcm.
+    pub fn encrypt(
+        &self,
+        pubkey: &[u8; 32],
+        key: &str,
+        format: HeaderFormat,
+        cipher: Cipher,
+        hop: u8,
+    ) -> Option<(EncHeader, Vec<u8>)> {
+        let (plaintext, cipher, version) = match format {
+            HeaderFormat::Json => (serde_json::to_vec(self).ok()?, cipher, HEADER_V1),
+            HeaderFormat::Binary => (self.to_bytes()?, cipher, HEADER_V1),
+            HeaderFormat::Legacy => (serde_json::to_vec(self).ok()?, Cipher::Aes256Gcm, 0),
+        };
+        let aead_key = header_key(key, format, hop)?;
+        let (nonce, ciphertext) = utils::aead_encrypt(cipher, &aead_key, &plaintext)?;
+        let hash = header_hash(pubkey, &plaintext, format, hop);
+        let header = EncHeader {
+            nonce,
+            pubkey: *pubkey,
+            ciphertext,
+            format,
+            version,
+            cipher,
+            hop,
+        };
+        Some((header, hash))
+    }
+
+    /// `[cmd][flags][timestamp: i64][nonce: u8 len][param: u16 len][client: u8 len]
+    /// [proof: u8 len][padding]`, integers are big endian and the client key is raw.
+    fn to_bytes(&self) -> Option<Vec<u8>> {
+        let client = match self.client.as_str() {
+            "" => vec![],
+            c => base64::decode(c).ok()?,
+        };
+        let mut flags = 0u8;
+        if self.e2e {
+            flags |= 0x01;
+        }
+        if self.status {
+            flags |= 0x02;
+        }
+        let mut buf = vec![self.cmd.clone() as u8, flags];
+        buf.extend(self.timestamp.to_be_bytes());
+        put_short(&mut buf, &self.nonce)?;
+        put_long(&mut buf, self.param.as_bytes())?;
+        put_short(&mut buf, &client)?;
+        put_short(&mut buf, &self.proof)?;
+        buf.extend(&self.padding);
+        Some(buf)
+    }
+
+    fn from_bytes(buf: &[u8]) -> Option<HeaderFrame> {
+        let mut fields = Fields(buf);
+        let cmd = Cmds::from_u8(fields.u8()?)?;
+        let flags = fields.u8()?;
+        let timestamp = i64::from_be_bytes(fields.take(8)?.try_into().ok()?);
+        let nonce = fields.short()?.to_vec();
+        let param = String::from_utf8(fields.long()?.to_vec()).ok()?;
+        let client = match fields.short()? {
+            [] => "".to_string(),
+            c => base64::encode(c),
+        };
+        Some(HeaderFrame {
+            cmd,
+            param,
+            e2e: flags & 0x01 != 0,
+            timestamp,
+            nonce,
+            client,
+            proof: fields.short()?.to_vec(),
+            status: flags & 0x02 != 0,
+            padding: fields.0.to_vec(),
+        })
+    }
+}
+
+/// Timeouts of each phase of a connection, in seconds.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Timeouts {
+    // tcp connect to the next node or the target
+    pub connect: u64,
+    // websocket (and tls) handshake
+    pub handshake: u64,
+    // each header round-trip of a hop, including its status reply
+    pub header: u64,
+    // no data in either direction of a tunnel
+    pub idle: u64,
+    // closing a tunnel or a websocket
+    pub close: u64,
+    // no packet on an udp association
+    pub udp: u64,
+    // a warm chain of the client pool waiting for its header, servers close it
+    // after this and clients replace it before
+    pub warm: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            connect: 10,
+            handshake: 10,
+            header: 30,
+            idle: 60 * 3,
+            close: 5,
+            udp: 60 * 30,
+            warm: 120,
         }
-        return None;
     }
 }
 
+impl Timeouts {
+    pub fn connect(&self) -> Duration {
+        Duration::from_secs(self.connect)
+    }
+
+    pub fn handshake(&self) -> Duration {
+        Duration::from_secs(self.handshake)
+    }
+
+    pub fn header(&self) -> Duration {
+        Duration::from_secs(self.header)
+    }
+
+    pub fn idle(&self) -> Duration {
+        Duration::from_secs(self.idle)
+    }
+
+    pub fn close(&self) -> Duration {
+        Duration::from_secs(self.close)
+    }
+
+    pub fn udp(&self) -> Duration {
+        Duration::from_secs(self.udp)
+    }
+
+    pub fn warm(&self) -> Duration {
+        Duration::from_secs(self.warm)
+    }
+}
+
+/// Certificate of a TLS listener, see `comm::tls`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct TlsConfigs {
+    // PEM file of the certificate chain, empty disables TLS
+    pub cert: String,
+    // PEM file of the PKCS #8 private key
+    pub key: String,
+    // generate a certificate into cert and key if they do not exist, in memory if
+    // they are empty, its fingerprint is logged for clients to pin
+    pub self_signed: bool,
+}
+
+impl TlsConfigs {
+    pub fn is_enabled(&self) -> bool {
+        self.self_signed || !self.cert.is_empty()
+    }
+}
+
+/// Chains the client builds ahead of requests, see `comp::pool`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct PoolConfigs {
+    // warm chains kept per profile in use, 0 disables the pool
+    pub size: usize,
+    // seconds before a warm chain is replaced
+    pub max_age: u64,
+    // seconds between keepalive pings, less than the header timeout of the servers
+    pub ping: u64,
+}
+
+impl Default for PoolConfigs {
+    fn default() -> PoolConfigs {
+        PoolConfigs {
+            size: 0,
+            max_age: 60,
+            ping: 10,
+        }
+    }
+}
+
+impl PoolConfigs {
+    pub fn max_age(&self) -> Duration {
+        Duration::from_secs(self.max_age)
+    }
+
+    pub fn ping(&self) -> Duration {
+        Duration::from_secs(self.ping.max(1))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AclAction {
+    Allow,
+    Deny,
+}
+
+// empty fields match everything
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AclRule {
+    pub action: AclAction,
+    #[serde(default)]
+    pub cmds: Vec<Cmds>,
+    #[serde(default)]
+    pub cidrs: Vec<String>,
+    // suffix match, "bing.com" matches "bing.com" and "www.bing.com"
+    #[serde(default)]
+    pub domains: Vec<String>,
+    // "80" or "8000-8080"
+    #[serde(default)]
+    pub ports: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteAction {
+    Direct,
+    Block,
+    Proxy,
+}
+
+// empty fields match everything, "file:path" loads a list from disk
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RouteRule {
+    pub action: RouteAction,
+    // chain profile of "proxy", empty means the default chain
+    #[serde(default)]
+    pub profile: String,
+    // "full:", "domain:" (default), "keyword:" or "regexp:" prefixed
+    #[serde(default)]
+    pub domains: Vec<String>,
+    // only match IP literals, domains are not resolved
+    #[serde(default)]
+    pub cidrs: Vec<String>,
+    // "80" or "8000-8080"
+    #[serde(default)]
+    pub ports: Vec<String>,
+    // "http", "socks5", "tcp" or "udp"
+    #[serde(default)]
+    pub protocols: Vec<String>,
+    // names of authenticated users, see "users" of the client
+    #[serde(default)]
+    pub users: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ServerConfigs {
     #[serde(default)]
     pub loglevel: String,
     pub listen: String,
+    // may be empty if `keys` has a primary key
+    #[serde(default)]
+    pub pubkey: String,
+    #[serde(default)]
+    pub secret: String,
+    // more keypairs accepted besides `pubkey`/`secret`, see `keypairs()`
+    #[serde(default)]
+    pub keys: Vec<ServerKey>,
+    // seconds, 0 disables replay protection
+    #[serde(default = "default_replay_window")]
+    pub replay_window: u64,
+    #[serde(default = "default_replay_cache")]
+    pub replay_cache: usize,
+    // headers of old clients, without timestamp and nonce, skip the replay
+    // check while migrating
+    #[serde(default = "default_true")]
+    pub legacy_headers: bool,
+    // pubkeys of clients allowed to use this server, empty means everyone
+    #[serde(default)]
+    pub authorized_clients: Vec<String>,
+    // destination access control, see comm::acl
+    #[serde(default)]
+    pub rules: Vec<AclRule>,
+    #[serde(default = "default_true")]
+    pub block_private: bool,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    // seconds to wait for tunnels to finish on SIGINT/SIGTERM
+    #[serde(default = "default_drain")]
+    pub drain: u64,
+    // address of the Prometheus metrics listener, empty disables it
+    #[serde(default)]
+    pub metrics: String,
+    // address of the wss listener, it runs besides `listen`, empty disables it
+    #[serde(default)]
+    pub tls_listen: String,
+    #[serde(default)]
+    pub tls: TlsConfigs,
+}
+
+/// A keypair the server accepts headers for.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ServerKey {
     pub pubkey: String,
     pub secret: String,
+    // the key given to clients
+    #[serde(default)]
+    pub primary: bool,
+    // "2024-12-31", the key is rejected after this day (UTC), empty means never
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub deprecated_until: String,
+}
+
+impl ServerKey {
+    fn until(&self) -> Result<Option<chrono::NaiveDate>, String> {
+        if self.deprecated_until.is_empty() {
+            return Ok(None);
+        }
+        match chrono::NaiveDate::parse_from_str(&self.deprecated_until, "%Y-%m-%d") {
+            Ok(d) => Ok(Some(d)),
+            Err(_) => Err(format!("invalid date: {}", self.deprecated_until)),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.until() {
+            Ok(Some(d)) => chrono::Utc::now().date_naive() > d,
+            Ok(None) => false,
+            Err(_) => true,
+        }
+    }
+}
+
+impl ServerConfigs {
+    /// Every keypair the server accepts, the primary one first. `pubkey` and
+    /// `secret` are primary unless a key in `keys` is marked so.
+    pub fn keypairs(&self) -> Result<Vec<ServerKey>, String> {
+        let mut keys = self.keys.clone();
+        if !self.secret.is_empty() || !self.pubkey.is_empty() {
+            keys.push(ServerKey {
+                pubkey: self.pubkey.to_string(),
+                secret: self.secret.to_string(),
+                primary: !self.keys.iter().any(|k| k.primary),
+                deprecated_until: "".to_string(),
+            });
+        }
+        for k in keys.iter() {
+            if !utils::is_keypair(&k.secret, &k.pubkey) {
+                return Err(format!("invalid keypair: {}", k.pubkey));
+            }
+            k.until()?;
+        }
+        match keys.iter().filter(|k| k.primary).count() {
+            0 => return Err("no primary keypair".to_string()),
+            1 => {}
+            _ => return Err("more than one primary keypair".to_string()),
+        }
+        keys.sort_by_key(|k| !k.primary);
+        Ok(keys)
+    }
+
+    /// Adds a new keypair to `keys`. Clients can switch to it before it is
+    /// marked primary.
+    pub fn stage_key(&mut self) -> ServerKey {
+        let (pubkey, secret) = utils::generate_x25519_keypair();
+        let key = ServerKey {
+            pubkey,
+            secret,
+            ..Default::default()
+        };
+        self.keys.push(key.clone());
+        key
+    }
+}
+
+fn default_drain() -> u64 {
+    30
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_replay_window() -> u64 {
+    120
+}
+
+fn default_replay_cache() -> usize {
+    100_000
 }
 
 impl Default for ServerConfigs {
@@ -97,6 +802,18 @@ impl Default for ServerConfigs {
             listen: "127.0.0.1:3001".to_string(),
             pubkey: "".to_string(),
             secret: "".to_string(),
+            keys: vec![],
+            replay_window: default_replay_window(),
+            replay_cache: default_replay_cache(),
+            legacy_headers: true,
+            authorized_clients: vec![],
+            rules: vec![],
+            block_private: true,
+            timeouts: Timeouts::default(),
+            drain: default_drain(),
+            metrics: "".to_string(),
+            tls_listen: "".to_string(),
+            tls: TlsConfigs::default(),
         }
     }
 }
@@ -106,7 +823,21 @@ pub struct ProxyChain {
     pub next: String,
     pub headers: Vec<EncHeader>,
     pub hashes: Vec<Vec<u8>>,
+    // hashes replied by servers that support the requested features
+    pub acks: Vec<Vec<u8>>,
     pub names: Vec<String>,
+    // ECDH key shared with the last node, used to seal the payload
+    pub key: String,
+    // public key of the last node, to replace the tail header later
+    pub tail_pubkey: String,
+}
+
+impl ProxyChain {
+    // names: [proxy?, inlet, relays.., outlet, target]
+    pub fn hop_name(&self, i: usize) -> &str {
+        let offset = self.names.len() - self.headers.len() - 1;
+        &self.names[offset + i]
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -118,11 +849,61 @@ pub struct ServerInfo {
 
 impl ServerInfo {
     pub fn to_header_frame(&self) -> HeaderFrame {
-        HeaderFrame {
-            cmd: Cmds::Relay,
-            param: self.addr.clone(),
-            padding: utils::rand_padding(),
+        HeaderFrame::new(Cmds::Relay, &self.addr)
+    }
+}
+
+// an account of the client listeners
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ProxyUser {
+    pub name: String,
+    pub password: String,
+}
+
+// empty pools fall back to the ones in ClientConfigs
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChainProfile {
+    pub name: String,
+    // connections accepted on this address use this profile, empty means none
+    #[serde(default)]
+    pub listen: String,
+    #[serde(default)]
+    pub length: usize,
+    #[serde(default)]
+    pub inlets: Vec<ServerInfo>,
+    #[serde(default)]
+    pub outlets: Vec<ServerInfo>,
+    #[serde(default)]
+    pub relays: Vec<ServerInfo>,
+    // node names from inlet to outlet, overrides the random pick
+    #[serde(default)]
+    pub path: Vec<String>,
+}
+
+impl ChainProfile {
+    fn find_node(&self, name: &str) -> Option<&ServerInfo> {
+        self.inlets
+            .iter()
+            .chain(self.relays.iter())
+            .chain(self.outlets.iter())
+            .find(|n| n.name == name)
+    }
+
+    /// Nodes of the fixed path from outlet to inlet.
+    pub fn path_nodes(&self) -> Result<Vec<&ServerInfo>, String> {
+        let mut nodes = vec![];
+        for name in self.path.iter().rev() {
+            match self.find_node(name) {
+                Some(n) => nodes.push(n),
+                None => {
+                    return Err(format!(
+                        "unknown node [{}] in profile [{}]",
+                        name, self.name
+                    ))
+                }
+            }
         }
+        Ok(nodes)
     }
 }
 
@@ -136,6 +917,138 @@ pub struct ClientConfigs {
     pub inlets: Vec<ServerInfo>,
     pub outlets: Vec<ServerInfo>,
     pub relays: Vec<ServerInfo>,
+    #[serde(default)]
+    pub encrypt: bool,
+    // long-term secret that identifies this client to servers, see "authorized_clients"
+    #[serde(default)]
+    pub identity: String,
+    // first match wins, requests without a match go through the default chain
+    #[serde(default)]
+    pub routes: Vec<RouteRule>,
+    #[serde(default)]
+    pub profiles: Vec<ChainProfile>,
+    // seconds between health checks, 0 disables them
+    #[serde(default)]
+    pub health_check: u64,
+    // consecutive failed checks before a server is excluded, 0 never excludes
+    #[serde(default = "default_max_failures")]
+    pub max_failures: u32,
+    // attempts with a new chain before giving up, 1 disables retry
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    // seconds for all attempts of a request, 0 means no limit
+    #[serde(default)]
+    pub retry_deadline: u64,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    // address of the Prometheus metrics listener, empty disables it
+    #[serde(default)]
+    pub metrics: String,
+    // address of the HTTP/JSON admin API, empty disables it
+    #[serde(default)]
+    pub admin: String,
+    // bearer token of the admin API, required unless it only listens on loopback
+    #[serde(default)]
+    pub admin_token: String,
+    // chains per profile that carry multiplexed connect and udp streams, 0 disables it
+    #[serde(default)]
+    pub mux: usize,
+    #[serde(default)]
+    pub pool: PoolConfigs,
+    // "json", "binary" or "legacy", servers of v1.1.4 and older only read legacy
+    #[serde(default = "default_header_format")]
+    pub header: HeaderFormat,
+    // "aes-256-gcm" or "chacha20-poly1305", the AEAD of json and binary headers
+    #[serde(default)]
+    pub cipher: Cipher,
+    // accounts that socks5 and http clients must log in with, empty allows anyone
+    #[serde(default)]
+    pub users: Vec<ProxyUser>,
+    // certificate of the listeners, they take TLS connections only when it is set
+    #[serde(default)]
+    pub tls: TlsConfigs,
+}
+
+fn default_header_format() -> HeaderFormat {
+    HeaderFormat::Json
+}
+
+fn default_max_attempts() -> u32 {
+    1
+}
+
+fn default_max_failures() -> u32 {
+    2
+}
+
+impl ClientConfigs {
+    /// The profile named `name`, empty means the top level pools and length.
+    pub fn profile(&self, name: &str) -> Option<ChainProfile> {
+        let mut profile = if name.is_empty() {
+            ChainProfile {
+                length: self.length,
+                ..Default::default()
+            }
+        } else {
+            self.profiles.iter().find(|p| p.name == name)?.clone()
+        };
+        let fill = |pool: &mut Vec<ServerInfo>, default: &Vec<ServerInfo>| {
+            if pool.is_empty() {
+                *pool = default.clone();
+            }
+        };
+        fill(&mut profile.inlets, &self.inlets);
+        fill(&mut profile.relays, &self.relays);
+        fill(&mut profile.outlets, &self.outlets);
+        Some(profile)
+    }
+
+    /// Checks that every profile name is unique, every path node exists and
+    /// every route refers to a known profile.
+    pub fn check_profiles(&self) -> Result<(), String> {
+        let mut names = std::collections::HashSet::new();
+        for p in self.profiles.iter() {
+            if p.name.is_empty() || !names.insert(p.name.as_str()) {
+                return Err(format!("invalid or duplicate profile name [{}]", p.name));
+            }
+            if let Some(profile) = self.profile(&p.name) {
+                profile.path_nodes()?;
+            }
+        }
+        for r in self.routes.iter() {
+            if !r.profile.is_empty() && !names.contains(r.profile.as_str()) {
+                return Err(format!("unknown profile [{}] in routes", r.profile));
+            }
+        }
+        Ok(())
+    }
+
+    /// Warm chains are replaced before the servers give up on them.
+    pub fn check_pool(&self) -> Result<(), String> {
+        if self.pool.size > 0 && self.pool.max_age >= self.timeouts.warm {
+            return Err(format!(
+                "max_age {} is not less than the warm timeout {}",
+                self.pool.max_age, self.timeouts.warm
+            ));
+        }
+        Ok(())
+    }
+
+    /// The cipher can be used with the header format.
+    pub fn check_header(&self) -> Result<(), String> {
+        if self.header == HeaderFormat::Legacy && self.cipher != Cipher::Aes256Gcm {
+            return Err("legacy headers only use aes-256-gcm".to_string());
+        }
+        Ok(())
+    }
+
+    /// The identity is empty or a base64 x25519 secret key.
+    pub fn check_identity(&self) -> Result<(), String> {
+        if !self.identity.is_empty() && utils::b64_to_secret(&self.identity).is_none() {
+            return Err("identity is not a base64 32 bytes secret key".to_string());
+        }
+        Ok(())
+    }
 }
 
 impl Default for ClientConfigs {
@@ -148,6 +1061,24 @@ impl Default for ClientConfigs {
             inlets: vec![],
             outlets: vec![],
             relays: vec![],
+            encrypt: false,
+            identity: "".to_string(),
+            routes: vec![],
+            profiles: vec![],
+            health_check: 0,
+            max_failures: default_max_failures(),
+            max_attempts: default_max_attempts(),
+            retry_deadline: 0,
+            timeouts: Timeouts::default(),
+            metrics: "".to_string(),
+            admin: "".to_string(),
+            admin_token: "".to_string(),
+            mux: 0,
+            pool: PoolConfigs::default(),
+            header: default_header_format(),
+            cipher: Cipher::default(),
+            users: vec![],
+            tls: TlsConfigs::default(),
         }
     }
 }
@@ -156,6 +1087,35 @@ impl Default for ClientConfigs {
 mod tests {
     use super::*;
 
+    #[test]
+    fn status_tests() {
+        let text = Status::HostUnreachable.to_message();
+        assert_eq!(Status::from_message(&text), Some(Status::HostUnreachable));
+        assert_eq!(Status::from_message("bad"), None);
+        assert_eq!(Status::Denied.socks5_reply(), 0x02);
+        assert!(Status::Denied.http_status().starts_with("403"));
+        assert!(Status::TtlExpired.http_status().starts_with("504"));
+
+        use std::io::{Error, ErrorKind};
+        let from = |kind| Status::from_io_error(&Error::from(kind));
+        assert_eq!(
+            from(ErrorKind::ConnectionRefused),
+            Status::ConnectionRefused
+        );
+        assert_eq!(from(ErrorKind::TimedOut), Status::TtlExpired);
+        assert_eq!(from(ErrorKind::NotFound), Status::HostUnreachable);
+
+        // failures of a node are retried on another chain, answers about the
+        // target are not
+        let retryable = |status| DialError::new(status, "test").is_retryable();
+        assert!(retryable(Status::NextHopUnreachable));
+        assert!(retryable(Status::TtlExpired));
+        assert!(retryable(Status::GeneralFailure));
+        assert!(!retryable(Status::Denied));
+        assert!(!retryable(Status::ConnectionRefused));
+        assert!(!retryable(Status::HostUnreachable));
+    }
+
     #[test]
     fn header_tests() {
         let (pk1, pri1) = utils::generate_x25519_keypair();
@@ -174,12 +1134,28 @@ mod tests {
 
         let pubkey2 = utils::b64_to_pubkey(&pk2).unwrap();
         let bytes = secret1.diffie_hellman(&pubkey2).to_bytes();
-        let key = base64::encode(&bytes);
+        let key = base64::encode(bytes);
 
-        let (ehf, hash) = hf.encrypt(&pubkey1.to_bytes(), &key).unwrap();
+        let (ehf, hash) = hf
+            .encrypt(
+                &pubkey1.to_bytes(),
+                &key,
+                HeaderFormat::Json,
+                Cipher::Aes256Gcm,
+                0,
+            )
+            .unwrap();
         let (hf2, hash2) = ehf.decrypt(&ehf.pubkey, &key).unwrap();
 
-        let (ehf2, hash3) = hf2.encrypt(&pubkey1.to_bytes(), &key).unwrap();
+        let (ehf2, hash3) = hf2
+            .encrypt(
+                &pubkey1.to_bytes(),
+                &key,
+                HeaderFormat::Json,
+                Cipher::Aes256Gcm,
+                0,
+            )
+            .unwrap();
 
         assert!(hash.eq(&hash2));
         assert!(hash.eq(&hash3));
@@ -190,4 +1166,226 @@ mod tests {
         assert_eq!(hf.param, hf2.param);
         assert_eq!(hf.padding, hf2.padding);
     }
+
+    #[test]
+    fn binary_header_tests() {
+        let identity = utils::generate_secret();
+        let server = utils::generate_secret();
+        let ephemeral = [7u8; 32];
+        let key = base64::encode(
+            server
+                .diffie_hellman(&PublicKey::from(ephemeral))
+                .to_bytes(),
+        );
+
+        let mut hf = HeaderFrame::new(Cmds::UdpAssoc, "bing.com:443");
+        hf.e2e = true;
+        hf.sign(&identity, &PublicKey::from(&server), &ephemeral);
+        let (ehf, hash) = hf
+            .encrypt(&ephemeral, &key, HeaderFormat::Binary, Cipher::Aes256Gcm, 2)
+            .unwrap();
+
+        let buf = ehf.to_bytes();
+        assert_eq!(buf[..3], [HEADER_V1, Cipher::Aes256Gcm as u8, 2]);
+        let ehf2 = EncHeader::from_bytes(&buf).unwrap();
+        assert_eq!(ehf2.format, HeaderFormat::Binary);
+        let (hf2, hash2) = ehf2.decrypt(&ehf2.pubkey, &key).unwrap();
+        assert_eq!(hash, hash2);
+        // over the raw bytes of the ephemeral key and the plaintext
+        let plaintext = hf.to_bytes().unwrap();
+        let label = b"thomas header v1 s2c hop 2";
+        assert_eq!(hash, utils::sha256_parts(&[label, &ephemeral, &plaintext]));
+        assert_eq!(hf2.cmd, Cmds::UdpAssoc);
+        assert_eq!(hf2.param, hf.param);
+        assert_eq!(hf2.timestamp, hf.timestamp);
+        assert_eq!(hf2.nonce, hf.nonce);
+        assert_eq!(hf2.client, hf.client);
+        assert_eq!(hf2.padding, hf.padding);
+        assert!(hf2.e2e && hf2.status);
+        assert!(hf2.verify(&server, &ephemeral));
+
+        // the key is bound to the hop
+        let mut moved = ehf2.clone();
+        moved.hop = 1;
+        assert!(moved.decrypt(&ephemeral, &key).is_none());
+
+        let (chacha, hash3) = hf
+            .encrypt(
+                &ephemeral,
+                &key,
+                HeaderFormat::Binary,
+                Cipher::ChaCha20Poly1305,
+                0,
+            )
+            .unwrap();
+        let chacha = EncHeader::from_bytes(&chacha.to_bytes()).unwrap();
+        assert_eq!(chacha.cipher, Cipher::ChaCha20Poly1305);
+        let (hf3, hash4) = chacha.decrypt(&ephemeral, &key).unwrap();
+        assert_eq!(hash3, hash4);
+        assert_eq!(hf3.param, hf.param);
+        let mut wrong = chacha.clone();
+        wrong.cipher = Cipher::Aes256Gcm;
+        assert!(wrong.decrypt(&ephemeral, &key).is_none());
+
+        // a json header can not be read as binary and vice versa
+        let (json, _) = hf
+            .encrypt(
+                &ephemeral,
+                &key,
+                HeaderFormat::Json,
+                Cipher::ChaCha20Poly1305,
+                0,
+            )
+            .unwrap();
+        assert_eq!(json.cipher, Cipher::ChaCha20Poly1305);
+        let mut wrong = json.clone();
+        wrong.format = HeaderFormat::Binary;
+        assert!(wrong.decrypt(&ephemeral, &key).is_none());
+        assert!(EncHeader::from_bytes(&buf[..40]).is_none());
+        let mut v2 = buf.clone();
+        v2[0] = 0x02;
+        assert!(EncHeader::from_bytes(&v2).is_none());
+        let mut unknown = buf.clone();
+        unknown[1] = 0x09;
+        assert!(EncHeader::from_bytes(&unknown).is_none());
+    }
+
+    #[test]
+    fn json_header_tests() {
+        let server = utils::generate_secret();
+        let ephemeral = [7u8; 32];
+        let key = base64::encode(
+            server
+                .diffie_hellman(&PublicKey::from(ephemeral))
+                .to_bytes(),
+        );
+        let hf = HeaderFrame::new(Cmds::Connect, "bing.com:443");
+
+        // versioned json takes the cipher and binds the key to the hop
+        for cipher in [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305] {
+            let (ehf, hash) = hf
+                .encrypt(&ephemeral, &key, HeaderFormat::Json, cipher, 1)
+                .unwrap();
+            let text = ehf.to_string().unwrap();
+            let ehf2 = EncHeader::from_text(&text).unwrap();
+            assert_eq!(ehf2.format, HeaderFormat::Json);
+            assert_eq!((ehf2.cipher, ehf2.hop), (cipher, 1));
+            let (hf2, hash2) = ehf2.decrypt(&ephemeral, &key).unwrap();
+            assert_eq!(hash, hash2);
+            assert_eq!(hf2.nonce, hf.nonce);
+            let mut moved = ehf2.clone();
+            moved.hop = 0;
+            assert!(moved.decrypt(&ephemeral, &key).is_none());
+            // not readable with the legacy key
+            moved.hop = 1;
+            moved.format = HeaderFormat::Legacy;
+            assert!(moved.decrypt(&ephemeral, &key).is_none());
+        }
+
+        // headers of old clients have no version, cipher or hop
+        let (legacy, hash) = hf
+            .encrypt(&ephemeral, &key, HeaderFormat::Legacy, Cipher::Aes256Gcm, 0)
+            .unwrap();
+        let mut old = serde_json::to_value(&legacy).unwrap();
+        for field in ["version", "cipher", "hop"] {
+            old.as_object_mut().unwrap().remove(field);
+        }
+        let ehf = EncHeader::from_text(&old.to_string()).unwrap();
+        assert_eq!(ehf.format, HeaderFormat::Legacy);
+        let (hf2, hash2) = ehf.decrypt(&ephemeral, &key).unwrap();
+        assert_eq!(hash, hash2);
+        assert_eq!(hf2.param, hf.param);
+        let text = String::from_utf8(serde_json::to_vec(&hf).unwrap()).unwrap();
+        assert_eq!(hash, utils::sha256(&format!("{ephemeral:?}{text}")));
+
+        let mut v2 = serde_json::to_value(&legacy).unwrap();
+        v2["version"] = 2.into();
+        assert!(EncHeader::from_text(&v2.to_string()).is_none());
+
+        let mut cfg = ClientConfigs::default();
+        assert!(cfg.check_header().is_ok());
+        cfg.header = HeaderFormat::Legacy;
+        assert!(cfg.check_header().is_ok());
+        cfg.cipher = Cipher::ChaCha20Poly1305;
+        assert!(cfg.check_header().is_err());
+    }
+
+    #[test]
+    fn pool_configs_tests() {
+        let mut cfg = ClientConfigs::default();
+        cfg.pool.max_age = 600;
+        assert!(cfg.check_pool().is_ok());
+        cfg.pool.size = 1;
+        assert!(cfg.check_pool().is_err());
+        cfg.timeouts.warm = 601;
+        assert!(cfg.check_pool().is_ok());
+
+        // configs of older versions get the default
+        let t: Timeouts = serde_json::from_str(r#"{"header": 30}"#).unwrap();
+        assert_eq!(t.warm, 120);
+    }
+
+    #[test]
+    fn proof_tests() {
+        let identity = utils::generate_secret();
+        let server = utils::generate_secret();
+        let ephemeral = [7u8; 32];
+
+        let mut hf = HeaderFrame::new(Cmds::Connect, "bing.com:443");
+        assert!(!hf.verify(&server, &ephemeral));
+        hf.sign(&identity, &PublicKey::from(&server), &ephemeral);
+        assert!(hf.verify(&server, &ephemeral));
+        assert_eq!(
+            hf.client,
+            base64::encode(PublicKey::from(&identity).as_bytes())
+        );
+
+        // proof is bound to the server, the ephemeral key and the nonce
+        assert!(!hf.verify(&utils::generate_secret(), &ephemeral));
+        assert!(!hf.verify(&server, &[8u8; 32]));
+        hf.nonce = utils::rand_vec8(16);
+        assert!(!hf.verify(&server, &ephemeral));
+    }
+
+    #[test]
+    fn keypairs_tests() {
+        let (pubkey, secret) = utils::generate_x25519_keypair();
+        let mut cfg = ServerConfigs {
+            pubkey: pubkey.to_string(),
+            secret,
+            ..Default::default()
+        };
+        let keys = cfg.keypairs().unwrap();
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0].primary);
+
+        let staged = cfg.stage_key();
+        let keys = cfg.keypairs().unwrap();
+        assert_eq!(keys[0].pubkey, pubkey);
+        assert_eq!(keys[1], staged);
+
+        // the staged key becomes primary and the old one is phased out
+        cfg.keys[0].primary = true;
+        cfg.keys.push(ServerKey {
+            pubkey: cfg.pubkey.to_string(),
+            secret: cfg.secret.to_string(),
+            primary: false,
+            deprecated_until: "2000-01-01".to_string(),
+        });
+        cfg.pubkey = "".to_string();
+        cfg.secret = "".to_string();
+        let keys = cfg.keypairs().unwrap();
+        assert_eq!(keys[0].pubkey, staged.pubkey);
+        assert!(keys[1].is_expired());
+
+        cfg.keys[1].deprecated_until = "tomorrow".to_string();
+        assert!(cfg.keypairs().is_err());
+        cfg.keys[1].deprecated_until = "".to_string();
+        assert!(!cfg.keys[1].is_expired());
+        cfg.keys[1].primary = true;
+        assert!(cfg.keypairs().is_err());
+        cfg.keys[1].pubkey = staged.pubkey;
+        assert!(cfg.keypairs().is_err());
+        assert!(ServerConfigs::default().keypairs().is_err());
+    }
 }
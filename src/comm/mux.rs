@@ -0,0 +1,718 @@
+use crate::comm::{
+    models::{Cmds, Status, Timeouts},
+    tunnel::{Tunnel, TunnelStats},
+};
+use async_std::{channel, future::timeout};
+use async_tungstenite::tungstenite::{Error, Message, Result};
+use futures::{future::select, Sink, SinkExt, Stream, StreamExt};
+use log::*;
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+};
+
+// frame: [kind: u8][stream id: u32 big endian][payload]
+const OPEN: u8 = 0x01;
+const REPLY: u8 = 0x02;
+const DATA: u8 = 0x03;
+const CLOSE: u8 = 0x04;
+const WINDOW: u8 = 0x05;
+
+/// Bytes a stream may send before the peer grants more.
+pub const WINDOW_SIZE: u32 = 256 * 1024;
+
+/// Streams a session carries at the same time.
+pub const MAX_STREAMS: usize = 1024;
+
+// frames waiting for the socket writer before streams hold back their data
+const MAX_QUEUED: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Frame {
+    // payload: [cmd: u8][target]
+    Open(u32, Cmds, String),
+    // payload: the status as json
+    Reply(u32, Status),
+    Data(u32, Vec<u8>),
+    Close(u32),
+    // payload: bytes the peer may send more, u32 big endian
+    Window(u32, u32),
+}
+
+impl Frame {
+    fn encode(self) -> Vec<u8> {
+        let (kind, id, payload) = match self {
+            Frame::Open(id, cmd, target) => {
+                let mut p = vec![cmd as u8];
+                p.extend(target.into_bytes());
+                (OPEN, id, p)
+            }
+            Frame::Reply(id, status) => (REPLY, id, status.to_message().into_bytes()),
+            Frame::Data(id, data) => (DATA, id, data),
+            Frame::Close(id) => (CLOSE, id, vec![]),
+            Frame::Window(id, n) => (WINDOW, id, n.to_be_bytes().to_vec()),
+        };
+        let mut buf = Vec::with_capacity(5 + payload.len());
+        buf.push(kind);
+        buf.extend(id.to_be_bytes());
+        buf.extend(payload);
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Frame> {
+        if buf.len() < 5 {
+            return None;
+        }
+        let id = u32::from_be_bytes(buf[1..5].try_into().ok()?);
+        let payload = &buf[5..];
+        let frame = match buf[0] {
+            OPEN => {
+                let cmd = Cmds::from_u8(*payload.first()?)?;
+                let target = String::from_utf8(payload[1..].to_vec()).ok()?;
+                Frame::Open(id, cmd, target)
+            }
+            REPLY => Frame::Reply(
+                id,
+                Status::from_message(std::str::from_utf8(payload).ok()?)?,
+            ),
+            DATA => Frame::Data(id, payload.to_vec()),
+            CLOSE => Frame::Close(id),
+            WINDOW => Frame::Window(id, u32::from_be_bytes(payload.try_into().ok()?)),
+            _ => return None,
+        };
+        Some(frame)
+    }
+}
+
+// send credit of a stream
+#[derive(Default)]
+struct Window(Mutex<WindowState>);
+
+#[derive(Default)]
+struct WindowState {
+    credit: i64,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+impl Window {
+    fn new(credit: u32) -> Window {
+        let w = Window::default();
+        w.0.lock().unwrap().credit = credit as i64;
+        w
+    }
+
+    fn grant(&self, n: u32) {
+        let mut s = self.0.lock().unwrap();
+        s.credit += n as i64;
+        if let Some(w) = s.waker.take() {
+            w.wake();
+        }
+    }
+
+    fn close(&self) {
+        let mut s = self.0.lock().unwrap();
+        s.closed = true;
+        if let Some(w) = s.waker.take() {
+            w.wake();
+        }
+    }
+
+    // takes up to `max` bytes of credit, waits while there is none
+    fn poll_take(&self, cx: &mut Context<'_>, max: usize) -> Poll<Result<usize>> {
+        let mut s = self.0.lock().unwrap();
+        if s.closed {
+            return Poll::Ready(Err(Error::ConnectionClosed));
+        }
+        if s.credit > 0 {
+            let n = s.credit.min(max as i64);
+            s.credit -= n;
+            return Poll::Ready(Ok(n as usize));
+        }
+        s.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+struct Slot {
+    // holds at most a window of data, see `Shared::dispatch`
+    data: channel::Sender<Vec<u8>>,
+    window: Arc<Window>,
+    // bytes received and not granted back to the peer yet
+    received: Arc<AtomicU32>,
+    // answers `Session::open`
+    reply: Option<channel::Sender<Status>>,
+}
+
+// state of a session shared with its streams
+struct Shared {
+    out: channel::Sender<Vec<u8>>,
+    slots: Mutex<HashMap<u32, Slot>>,
+    next_id: AtomicU32,
+    // streams waiting for the writer to catch up
+    blocked: Mutex<Vec<Waker>>,
+}
+
+impl Shared {
+    fn send(&self, frame: Frame) -> bool {
+        self.out.try_send(frame.encode()).is_ok()
+    }
+
+    // data waits while the writer is `MAX_QUEUED` frames behind, other frames
+    // are few and never wait
+    fn poll_writable(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.out.len() < MAX_QUEUED || self.out.is_closed() {
+            return Poll::Ready(());
+        }
+        self.blocked.lock().unwrap().push(cx.waker().clone());
+        // the writer may have caught up meanwhile
+        if self.out.len() < MAX_QUEUED {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+
+    fn wake_blocked(&self) {
+        for w in self.blocked.lock().unwrap().drain(..) {
+            w.wake();
+        }
+    }
+
+    // None if `id` is in use or there are `MAX_STREAMS` streams
+    fn register(
+        self: &Arc<Self>,
+        id: u32,
+        reply: Option<channel::Sender<Status>>,
+    ) -> Option<MuxStream> {
+        let mut slots = self.slots.lock().unwrap();
+        if slots.contains_key(&id) || slots.len() >= MAX_STREAMS {
+            return None;
+        }
+        let (tx, rx) = channel::unbounded();
+        let window = Arc::new(Window::new(WINDOW_SIZE));
+        let received = Arc::new(AtomicU32::new(0));
+        let slot = Slot {
+            data: tx,
+            window: window.clone(),
+            received: received.clone(),
+            reply,
+        };
+        slots.insert(id, slot);
+        Some(MuxStream {
+            id,
+            shared: self.clone(),
+            data: rx,
+            window,
+            received,
+            unacked: 0,
+            pending: vec![],
+            closed: false,
+            stop: None,
+            chain: vec![],
+            stats: None,
+        })
+    }
+
+    // false if the peer broke the protocol and the session has to be closed
+    fn dispatch(self: &Arc<Self>, frame: Frame, opened: Option<&channel::Sender<Opened>>) -> bool {
+        if let Frame::Open(id, cmd, target) = frame {
+            let tx = match opened {
+                Some(tx) => tx,
+                None => {
+                    self.send(Frame::Reply(id, Status::Denied));
+                    return true;
+                }
+            };
+            if self.slots.lock().unwrap().contains_key(&id) {
+                warn!("mux stream {} opened twice", id);
+                return false;
+            }
+            match self.register(id, None) {
+                // the queue is as long as the limit of streams
+                Some(stream) => {
+                    let _ = tx.try_send((stream, cmd, target));
+                }
+                None => {
+                    warn!("too many mux streams, refuse stream {}", id);
+                    self.send(Frame::Reply(id, Status::GeneralFailure));
+                }
+            }
+            return true;
+        }
+        let mut slots = self.slots.lock().unwrap();
+        match frame {
+            Frame::Open(..) => {}
+            Frame::Reply(id, status) => {
+                if let Some(tx) = slots.get_mut(&id).and_then(|s| s.reply.take()) {
+                    let _ = tx.try_send(status);
+                }
+            }
+            // a sender never sends empty data, it would not count against the window
+            Frame::Data(id, data) if data.is_empty() => {
+                warn!("empty data on mux stream {}", id);
+                return false;
+            }
+            Frame::Data(id, data) => {
+                if let Some(s) = slots.get(&id) {
+                    let n = data.len() as u32;
+                    let received = s.received.fetch_add(n, Ordering::Relaxed);
+                    if received.saturating_add(n) > WINDOW_SIZE {
+                        warn!("mux stream {} overruns its window", id);
+                        return false;
+                    }
+                    let _ = s.data.try_send(data);
+                }
+            }
+            Frame::Close(id) => {
+                if let Some(s) = slots.remove(&id) {
+                    s.window.close();
+                }
+            }
+            Frame::Window(id, n) => {
+                if let Some(s) = slots.get(&id) {
+                    s.window.grant(n);
+                }
+            }
+        }
+        true
+    }
+
+    // ends every stream of the session
+    fn shutdown(&self) {
+        self.out.close();
+        for (_, s) in self.slots.lock().unwrap().drain() {
+            s.window.close();
+        }
+        self.wake_blocked();
+    }
+}
+
+type Opened = (MuxStream, Cmds, String);
+
+/// Many streams multiplexed over one tunnel.
+///
+/// Each stream has an id, its own close and a send window that the peer
+/// grows as it consumes data. The client opens streams and the outlet
+/// accepts them, every frame travels as one binary message of the tunnel.
+pub struct Session {
+    shared: Arc<Shared>,
+    opened: channel::Receiver<Opened>,
+    chain: Vec<String>,
+}
+
+impl Session {
+    /// Runs the session over `tunnel` until it closes. Streams are only
+    /// accepted if `accept` is set, others get `Status::Denied`.
+    pub fn new(tunnel: Tunnel, accept: bool, t: &Timeouts) -> Session {
+        let (out_tx, out_rx) = channel::unbounded();
+        let (opened_tx, opened) = channel::bounded(MAX_STREAMS);
+        let shared = Arc::new(Shared {
+            out: out_tx,
+            slots: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(0),
+            blocked: Mutex::new(vec![]),
+        });
+        // drops the empty target of the mux command
+        let mut chain = Tunnel::chain(&tunnel).to_vec();
+        if chain.last().is_some_and(|n| n.is_empty()) {
+            chain.pop();
+        }
+        let opened_tx = if accept { Some(opened_tx) } else { None };
+        async_std::task::spawn(run(tunnel, shared.clone(), out_rx, opened_tx, *t));
+        Session {
+            shared,
+            opened,
+            chain,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.out.is_closed()
+    }
+
+    /// Number of open streams.
+    pub fn streams(&self) -> usize {
+        self.shared.slots.lock().unwrap().len()
+    }
+
+    /// Hops of the tunnel, from the first one to the outlet.
+    pub fn chain(&self) -> &[String] {
+        &self.chain
+    }
+
+    /// Asks the outlet to run `cmd` on `target` and returns the stream once
+    /// it answers.
+    pub async fn open(
+        &self,
+        cmd: Cmds,
+        target: &str,
+        t: &Timeouts,
+    ) -> std::result::Result<MuxStream, Status> {
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (tx, rx) = channel::bounded(1);
+        let mut stream = match self.shared.register(id, Some(tx)) {
+            Some(s) => s,
+            None => return Err(Status::GeneralFailure),
+        };
+        stream.chain = self.chain.clone();
+        stream.chain.push(target.to_string());
+        if !self.shared.send(Frame::Open(id, cmd, target.to_string())) {
+            return Err(Status::NextHopUnreachable);
+        }
+        match timeout(t.connect() + t.header(), rx.recv()).await {
+            Ok(Ok(Status::Ok)) => Ok(stream),
+            Ok(Ok(status)) => Err(status),
+            Ok(Err(_)) => Err(Status::NextHopUnreachable),
+            Err(_) => Err(Status::TtlExpired),
+        }
+    }
+
+    /// The next stream opened by the peer with its command and target, None
+    /// once the session is closed.
+    pub async fn accept(&self) -> Option<Opened> {
+        self.opened.recv().await.ok()
+    }
+}
+
+async fn run(
+    tunnel: Tunnel,
+    shared: Arc<Shared>,
+    out: channel::Receiver<Vec<u8>>,
+    opened: Option<channel::Sender<Opened>>,
+    t: Timeouts,
+) {
+    let (mut w, mut r) = tunnel.split();
+    let writer = async {
+        while let Ok(buf) = out.recv().await {
+            if out.len() < MAX_QUEUED {
+                shared.wake_blocked();
+            }
+            if w.send(Message::Binary(buf)).await.is_err() {
+                break;
+            }
+        }
+    };
+    let reader = async {
+        while let Some(Ok(msg)) = r.next().await {
+            let buf = match msg {
+                Message::Binary(buf) if !buf.is_empty() => buf,
+                Message::Ping(_) | Message::Pong(_) => continue,
+                _ => break,
+            };
+            let valid = match Frame::decode(&buf) {
+                Some(frame) => shared.dispatch(frame, opened.as_ref()),
+                None => false,
+            };
+            if !valid {
+                warn!("invalid mux frame, close the session");
+                break;
+            }
+        }
+    };
+    select(Box::pin(writer), Box::pin(reader)).await;
+    shared.shutdown();
+    drop(opened);
+    if let Ok(mut tunnel) = w.reunite(r) {
+        let _ = timeout(t.close(), tunnel.close()).await;
+    }
+    debug!("mux session closed");
+}
+
+/// A stream of a `Session`, read and written like a tunnel.
+pub struct MuxStream {
+    id: u32,
+    shared: Arc<Shared>,
+    data: channel::Receiver<Vec<u8>>,
+    window: Arc<Window>,
+    // bytes received and not granted back, shared with the slot
+    received: Arc<AtomicU32>,
+    // bytes read and not granted back to the peer yet
+    unacked: u32,
+    // data of the last message beyond the window, sent as the peer grants more
+    pending: Vec<u8>,
+    // the close frame is sent
+    closed: bool,
+    stop: Option<channel::Receiver<()>>,
+    chain: Vec<String>,
+    stats: Option<Arc<TunnelStats>>,
+}
+
+impl MuxStream {
+    /// Answers the open request of the peer, false if the session is closed.
+    pub fn reply(&self, status: Status) -> bool {
+        self.shared.send(Frame::Reply(self.id, status))
+    }
+
+    /// Once `stop` is closed, the stream is closed and ends.
+    pub fn with_stop(mut self, stop: channel::Receiver<()>) -> MuxStream {
+        self.stop = Some(stop);
+        self
+    }
+
+    /// Counts the payload bytes into `stats` from now on.
+    pub fn with_stats(mut self, stats: Arc<TunnelStats>) -> MuxStream {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Hops of the session followed by the target.
+    pub fn chain(&self) -> &[String] {
+        &self.chain
+    }
+
+    fn close(&mut self) {
+        if !self.closed {
+            self.closed = true;
+            self.shared.send(Frame::Close(self.id));
+        }
+    }
+
+    // sends the pending data as the window allows, Ready once all of it is sent
+    fn poll_send_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        while !self.pending.is_empty() {
+            if self.shared.poll_writable(cx).is_pending() {
+                return Poll::Pending;
+            }
+            let n = match self.window.poll_take(cx, self.pending.len()) {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            let rest = self.pending.split_off(n);
+            let data = std::mem::replace(&mut self.pending, rest);
+            if !self.shared.send(Frame::Data(self.id, data)) {
+                return Poll::Ready(Err(Error::ConnectionClosed));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_stop(&mut self, cx: &mut Context<'_>) -> bool {
+        if let Some(stop) = self.stop.as_mut() {
+            if let Poll::Ready(None) = Pin::new(stop).poll_next(cx) {
+                self.close();
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.close();
+        self.shared.slots.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Stream for MuxStream {
+    type Item = Result<Message>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.poll_stop(cx) {
+            return Poll::Ready(None);
+        }
+        match Pin::new(&mut this.data).poll_next(cx) {
+            Poll::Ready(Some(data)) => {
+                this.unacked += data.len() as u32;
+                if this.unacked >= WINDOW_SIZE / 2 {
+                    // released before the grant reaches the peer
+                    this.received.fetch_sub(this.unacked, Ordering::Relaxed);
+                    this.shared.send(Frame::Window(this.id, this.unacked));
+                    this.unacked = 0;
+                }
+                if let Some(stats) = this.stats.as_ref() {
+                    let n = data.len() as u64;
+                    stats.bytes_in.fetch_add(n, Ordering::Relaxed);
+                }
+                Poll::Ready(Some(Ok(Message::Binary(data))))
+            }
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Sink<Message> for MuxStream {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        if this.closed {
+            return Poll::Ready(Err(Error::ConnectionClosed));
+        }
+        this.poll_send_pending(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<()> {
+        let this = self.get_mut();
+        let data = match item {
+            Message::Binary(data) => data,
+            Message::Text(txt) => txt.into_bytes(),
+            Message::Ping(_) | Message::Pong(_) => return Ok(()),
+            Message::Close(_) => vec![],
+        };
+        // an empty message ends the stream like it ends a tunnel
+        if data.is_empty() {
+            this.close();
+            return Ok(());
+        }
+        if let Some(stats) = this.stats.as_ref() {
+            let n = data.len() as u64;
+            stats.bytes_out.fetch_add(n, Ordering::Relaxed);
+        }
+        // sent by the flush, never more than the window at once
+        this.pending = data;
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_send_pending(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let r = match this.poll_send_pending(cx) {
+            Poll::Ready(r) => r,
+            Poll::Pending => return Poll::Pending,
+        };
+        this.close();
+        Poll::Ready(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_tests() {
+        let frames = vec![
+            Frame::Open(1, Cmds::Connect, "bing.com:443".to_string()),
+            Frame::Reply(1, Status::Denied),
+            Frame::Data(7, b"hello".to_vec()),
+            Frame::Close(u32::MAX),
+            Frame::Window(2, WINDOW_SIZE),
+        ];
+        for f in frames.into_iter() {
+            assert_eq!(Frame::decode(&f.clone().encode()), Some(f));
+        }
+        assert_eq!(Frame::decode(&[DATA, 0, 0]), None);
+        assert_eq!(Frame::decode(&[OPEN, 0, 0, 0, 1, 0x09]), None);
+        assert_eq!(Frame::decode(&[WINDOW, 0, 0, 0, 1, 0]), None);
+        assert_eq!(Frame::decode(&[0x7f, 0, 0, 0, 1]), None);
+    }
+
+    fn shared() -> (Arc<Shared>, channel::Receiver<Vec<u8>>) {
+        let (out_tx, out) = channel::unbounded();
+        let shared = Arc::new(Shared {
+            out: out_tx,
+            slots: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(0),
+            blocked: Mutex::new(vec![]),
+        });
+        (shared, out)
+    }
+
+    #[test]
+    fn dispatch_tests() {
+        let (shared, out) = shared();
+        let (tx, opened) = channel::bounded(MAX_STREAMS);
+        let open = |id| Frame::Open(id, Cmds::Connect, "bing.com:443".to_string());
+        assert!(shared.dispatch(open(1), Some(&tx)));
+        let (mut stream, _, _) = opened.try_recv().unwrap();
+        assert!(!shared.dispatch(open(1), Some(&tx)));
+
+        // a window of data is taken, more is an overrun until it is read
+        let window = vec![0u8; WINDOW_SIZE as usize];
+        assert!(shared.dispatch(Frame::Data(1, window), None));
+        assert!(!shared.dispatch(Frame::Data(1, vec![0]), None));
+        async_std::task::block_on(stream.next()).unwrap().unwrap();
+        assert_eq!(
+            Frame::decode(&out.try_recv().unwrap()),
+            Some(Frame::Window(1, WINDOW_SIZE))
+        );
+        assert!(shared.dispatch(Frame::Data(1, vec![0]), None));
+        assert!(!shared.dispatch(Frame::Data(1, vec![]), None));
+
+        // streams over the limit are refused
+        let mut streams = vec![];
+        for id in 2..=MAX_STREAMS as u32 {
+            assert!(shared.dispatch(open(id), Some(&tx)));
+            streams.push(opened.try_recv().unwrap());
+        }
+        assert!(shared.dispatch(open(9999), Some(&tx)));
+        assert!(opened.try_recv().is_err());
+        assert_eq!(
+            Frame::decode(&out.try_recv().unwrap()),
+            Some(Frame::Reply(9999, Status::GeneralFailure))
+        );
+    }
+
+    #[test]
+    fn window_tests() {
+        use futures::FutureExt;
+
+        let (sender, out) = shared();
+        let (receiver, _) = shared();
+        let mut stream = sender.register(1, None).unwrap();
+        // never read
+        let _peer = receiver.register(1, None).unwrap();
+
+        // messages that do not divide the window
+        let mut sent = 0;
+        while let Some(r) = stream.send(Message::binary(vec![7u8; 5000])).now_or_never() {
+            r.unwrap();
+            sent += 5000;
+        }
+        assert!(sent < WINDOW_SIZE as usize);
+        let mut got = 0;
+        while let Ok(buf) = out.try_recv() {
+            let frame = Frame::decode(&buf).unwrap();
+            if let Frame::Data(_, data) = &frame {
+                got += data.len();
+            }
+            assert!(receiver.dispatch(frame, None));
+        }
+        assert_eq!(got, WINDOW_SIZE as usize);
+
+        // the rest of the message goes once the peer grants more
+        sender.dispatch(Frame::Window(1, WINDOW_SIZE), None);
+        async_std::task::block_on(stream.flush()).unwrap();
+        match Frame::decode(&out.try_recv().unwrap()) {
+            Some(Frame::Data(1, data)) => assert_eq!(got + data.len(), sent + 5000),
+            f => panic!("unexpected frame: {:?}", f),
+        }
+    }
+
+    #[test]
+    fn queue_tests() {
+        use futures::FutureExt;
+
+        let (shared, out) = shared();
+        let mut stream = shared.register(1, None).unwrap();
+        for _ in 0..MAX_QUEUED {
+            assert!(shared.send(Frame::Close(2)));
+        }
+        // the data waits for the writer, control frames do not
+        assert!(stream
+            .send(Message::binary(b"hello".to_vec()))
+            .now_or_never()
+            .is_none());
+        assert!(shared.send(Frame::Window(2, 1)));
+        assert_eq!(out.len(), MAX_QUEUED + 1);
+
+        out.try_recv().unwrap();
+        out.try_recv().unwrap();
+        shared.wake_blocked();
+        async_std::task::block_on(stream.flush()).unwrap();
+        assert_eq!(out.len(), MAX_QUEUED);
+    }
+}
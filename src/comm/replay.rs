@@ -0,0 +1,233 @@
+use crate::comm::models::HeaderFrame;
+use log::*;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rejection {
+    Stale,
+    Duplicate,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Stale => write!(f, "timestamp out of window"),
+            Rejection::Duplicate => write!(f, "duplicate nonce"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct NonceCache {
+    // client pubkey -> nonces seen from it
+    seen: HashMap<[u8; 32], HashSet<Vec<u8>>>,
+    // (expire at, client pubkey, nonce) ordered by expiry
+    order: BTreeSet<(i64, [u8; 32], Vec<u8>)>,
+}
+
+impl NonceCache {
+    // drops the nonces whose headers are out of the window at `now`
+    fn remove_expired(&mut self, now: i64) {
+        while let Some((expire, _, _)) = self.order.first() {
+            if *expire >= now {
+                break;
+            }
+            self.remove_first();
+        }
+    }
+
+    // drops the nonce that expires first
+    fn remove_first(&mut self) {
+        if let Some((_, pubkey, nonce)) = self.order.pop_first() {
+            if let Some(nonces) = self.seen.get_mut(&pubkey) {
+                nonces.remove(&nonce);
+                if nonces.is_empty() {
+                    self.seen.remove(&pubkey);
+                }
+            }
+        }
+    }
+}
+
+/// Rejects header frames that are too old or have been seen before.
+pub struct ReplayGuard {
+    window: i64,
+    capacity: usize,
+    cache: Mutex<NonceCache>,
+    // accept headers without timestamp and nonce
+    legacy: bool,
+    stale: AtomicU64,
+    duplicate: AtomicU64,
+    // nonces dropped from a full cache before they expired
+    evicted: AtomicU64,
+    legacy_accepted: AtomicU64,
+}
+
+impl ReplayGuard {
+    /// `window` is in seconds, 0 disables the check. At most `capacity` nonces are
+    /// kept, a full cache drops the one that expires first.
+    /// Headers of old clients, without timestamp and nonce, pass if `legacy`
+    /// is set and are counted apart.
+    pub fn new(window: u64, capacity: usize, legacy: bool) -> ReplayGuard {
+        ReplayGuard {
+            window: window as i64,
+            capacity,
+            cache: Mutex::new(NonceCache::default()),
+            legacy,
+            stale: AtomicU64::new(0),
+            duplicate: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+            legacy_accepted: AtomicU64::new(0),
+        }
+    }
+
+    pub fn check(&self, pubkey: &[u8; 32], header: &HeaderFrame) -> Result<(), Rejection> {
+        self.check_at(chrono::Utc::now().timestamp(), pubkey, header)
+    }
+
+    fn check_at(&self, now: i64, pubkey: &[u8; 32], header: &HeaderFrame) -> Result<(), Rejection> {
+        if self.window < 1 {
+            return Ok(());
+        }
+        if self.legacy && header.nonce.is_empty() && header.timestamp == 0 {
+            if self.legacy_accepted.fetch_add(1, Ordering::Relaxed) == 0 {
+                warn!("accept headers of old clients without replay protection");
+            }
+            return Ok(());
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+        cache.remove_expired(now);
+
+        if header.nonce.is_empty() || (now - header.timestamp).abs() > self.window {
+            self.stale.fetch_add(1, Ordering::Relaxed);
+            return Err(Rejection::Stale);
+        }
+
+        if let Some(true) = cache.seen.get(pubkey).map(|n| n.contains(&header.nonce)) {
+            self.duplicate.fetch_add(1, Ordering::Relaxed);
+            return Err(Rejection::Duplicate);
+        }
+        // rejecting new headers would lock every client out, the header of an
+        // evicted nonce can only be replayed until it leaves the window, the
+        // earliest of the cache
+        while cache.order.len() >= self.capacity.max(1) {
+            cache.remove_first();
+            let n = self.evicted.fetch_add(1, Ordering::Relaxed) + 1;
+            if n.is_power_of_two() {
+                warn!("replay cache is full, {n} nonces evicted before they expired");
+            }
+        }
+        let expire = header.timestamp + self.window;
+        cache
+            .seen
+            .entry(*pubkey)
+            .or_default()
+            .insert(header.nonce.clone());
+        cache.order.insert((expire, *pubkey, header.nonce.clone()));
+        Ok(())
+    }
+
+    /// Nonces evicted from a full cache so far.
+    pub fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// Headers of old clients accepted so far.
+    pub fn legacy_accepted(&self) -> u64 {
+        self.legacy_accepted.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of (stale, duplicate) rejections so far.
+    pub fn rejected(&self) -> (u64, u64) {
+        (
+            self.stale.load(Ordering::Relaxed),
+            self.duplicate.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comm::models::{Cmds, ServerConfigs};
+
+    #[test]
+    fn replay_guard_tests() {
+        let guard = ReplayGuard::new(60, 2, false);
+        let now = chrono::Utc::now().timestamp();
+        let k1 = [1u8; 32];
+        let k2 = [2u8; 32];
+
+        let h1 = HeaderFrame::new(Cmds::Connect, "bing.com:443");
+        assert_eq!(guard.check_at(now, &k1, &h1), Ok(()));
+        assert_eq!(guard.check_at(now, &k1, &h1), Err(Rejection::Duplicate));
+        // the same nonce from another client is a different header
+        assert_eq!(guard.check_at(now, &k2, &h1), Ok(()));
+
+        let mut h2 = HeaderFrame::new(Cmds::Connect, "bing.com:443");
+        h2.timestamp = now - 61;
+        assert_eq!(guard.check_at(now, &k1, &h2), Err(Rejection::Stale));
+        h2.timestamp = now + 61;
+        assert_eq!(guard.check_at(now, &k1, &h2), Err(Rejection::Stale));
+        h2.nonce = vec![];
+        h2.timestamp = now;
+        assert_eq!(guard.check_at(now, &k1, &h2), Err(Rejection::Stale));
+        assert_eq!(guard.rejected(), (3, 1));
+
+        // expired nonces are evicted and then rejected by timestamp
+        assert_eq!(guard.check_at(now + 61, &k1, &h1), Err(Rejection::Stale));
+        assert!(guard.cache.lock().unwrap().order.is_empty());
+
+        // a full cache drops the nonce that expires first
+        let full = ReplayGuard::new(60, 2, false);
+        let mut h3 = HeaderFrame::new(Cmds::Connect, "bing.com:443");
+        h3.timestamp = now - 10;
+        let h4 = HeaderFrame::new(Cmds::Connect, "bing.com:443");
+        assert_eq!(full.check_at(now, &k1, &h1), Ok(()));
+        assert_eq!(full.check_at(now, &k1, &h3), Ok(()));
+        assert_eq!(full.check_at(now, &k1, &h4), Ok(()));
+        assert_eq!(full.evicted(), 1);
+        assert_eq!(full.check_at(now, &k1, &h1), Err(Rejection::Duplicate));
+        assert_eq!(full.check_at(now, &k1, &h4), Err(Rejection::Duplicate));
+        assert_eq!(full.check_at(now, &k1, &h3), Ok(()));
+        assert_eq!(full.evicted(), 2);
+        assert_eq!(full.rejected(), (0, 2));
+
+        let disabled = ReplayGuard::new(0, 2, false);
+        assert_eq!(disabled.check_at(now, &k1, &h1), Ok(()));
+        assert_eq!(disabled.check_at(now, &k1, &h1), Ok(()));
+    }
+
+    #[test]
+    fn legacy_header_tests() {
+        // a server with the default configs takes headers of old clients
+        let cfg = ServerConfigs::default();
+        let guard = ReplayGuard::new(cfg.replay_window, cfg.replay_cache, cfg.legacy_headers);
+        let now = chrono::Utc::now().timestamp();
+        let k1 = [1u8; 32];
+        let mut old = HeaderFrame::new(Cmds::Connect, "bing.com:443");
+        old.timestamp = 0;
+        old.nonce = vec![];
+        assert_eq!(guard.check_at(now, &k1, &old), Ok(()));
+        assert_eq!(guard.check_at(now, &k1, &old), Ok(()));
+        assert_eq!(guard.legacy_accepted(), 2);
+        assert_eq!(guard.rejected(), (0, 0));
+
+        // only without both timestamp and nonce
+        old.timestamp = now - 3600;
+        assert_eq!(guard.check_at(now, &k1, &old), Err(Rejection::Stale));
+
+        let strict = ReplayGuard::new(cfg.replay_window, cfg.replay_cache, false);
+        old.timestamp = 0;
+        assert_eq!(strict.check_at(now, &k1, &old), Err(Rejection::Stale));
+        assert_eq!(strict.legacy_accepted(), 0);
+    }
+}
@@ -0,0 +1,280 @@
+use crate::comm::{
+    acl::{match_domain, parse_port_range, Cidr},
+    models::{RouteAction, RouteRule},
+};
+use regex::Regex;
+use std::net::IpAddr;
+
+/// What the client does with a request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Route {
+    Direct,
+    Block,
+    // name of the chain profile, empty means the default chain
+    Proxy(String),
+}
+
+/// A request accepted by the listener.
+#[derive(Debug, Clone)]
+pub struct Request<'a> {
+    // "http" or "socks5"
+    pub protocol: &'a str,
+    // "tcp" or "udp"
+    pub network: &'a str,
+    pub host: &'a str,
+    pub port: u16,
+    // the authenticated user, empty if the listener has no users
+    pub user: &'a str,
+}
+
+#[derive(Debug)]
+enum Domain {
+    Full(String),
+    Suffix(String),
+    Keyword(String),
+    Regex(Regex),
+}
+
+impl Domain {
+    fn parse(s: &str) -> Result<Domain, String> {
+        let (kind, value) = s.split_once(':').unwrap_or(("domain", s));
+        let value = value.trim();
+        match kind {
+            "full" => Ok(Domain::Full(value.to_lowercase())),
+            "domain" => Ok(Domain::Suffix(value.to_lowercase())),
+            "keyword" => Ok(Domain::Keyword(value.to_lowercase())),
+            "regexp" => match Regex::new(value) {
+                Ok(r) => Ok(Domain::Regex(r)),
+                Err(e) => Err(format!("invalid regexp {value}: {e}")),
+            },
+            _ => Err(format!("invalid domain: {s}")),
+        }
+    }
+
+    fn is_match(&self, host: &str) -> bool {
+        match self {
+            Domain::Full(d) => host == d,
+            Domain::Suffix(d) => match_domain(d, host),
+            Domain::Keyword(k) => host.contains(k.as_str()),
+            Domain::Regex(r) => r.is_match(host),
+        }
+    }
+}
+
+// one entry per line, empty lines and lines starting with '#' are skipped
+fn load_list(path: &str) -> Result<Vec<String>, String> {
+    let content =
+        std::fs::read_to_string(path).map_err(|e| format!("failed to read {path}: {e}"))?;
+    let list = content
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.to_string())
+        .collect();
+    Ok(list)
+}
+
+fn expand(entries: &[String]) -> Result<Vec<String>, String> {
+    let mut r = vec![];
+    for e in entries.iter() {
+        match e.strip_prefix("file:") {
+            Some(path) => r.extend(load_list(path)?),
+            None => r.push(e.to_string()),
+        }
+    }
+    Ok(r)
+}
+
+#[derive(Debug)]
+struct Rule {
+    route: Route,
+    domains: Vec<Domain>,
+    cidrs: Vec<Cidr>,
+    ports: Vec<(u16, u16)>,
+    protocols: Vec<String>,
+    users: Vec<String>,
+}
+
+impl Rule {
+    fn parse(cfg: &RouteRule) -> Result<Rule, String> {
+        let mut domains = vec![];
+        for d in expand(&cfg.domains)?.iter() {
+            domains.push(Domain::parse(d)?);
+        }
+        let mut cidrs = vec![];
+        for c in expand(&cfg.cidrs)?.iter() {
+            cidrs.push(Cidr::parse(c).ok_or(format!("invalid cidr: {c}"))?);
+        }
+        let mut ports = vec![];
+        for p in cfg.ports.iter() {
+            ports.push(parse_port_range(p).ok_or(format!("invalid port range: {p}"))?);
+        }
+        let route = match cfg.action {
+            RouteAction::Direct => Route::Direct,
+            RouteAction::Block => Route::Block,
+            RouteAction::Proxy => Route::Proxy(cfg.profile.clone()),
+        };
+        Ok(Rule {
+            route,
+            domains,
+            cidrs,
+            ports,
+            protocols: cfg.protocols.iter().map(|p| p.to_lowercase()).collect(),
+            users: cfg.users.clone(),
+        })
+    }
+
+    // every non-empty condition must match, domains and cidrs match either
+    fn is_match(&self, req: &Request, host: &str, ip: Option<&IpAddr>) -> bool {
+        if !self.protocols.is_empty()
+            && !self
+                .protocols
+                .iter()
+                .any(|p| p == req.protocol || p == req.network)
+        {
+            return false;
+        }
+        if !self.users.is_empty() && !self.users.iter().any(|u| u == req.user) {
+            return false;
+        }
+        if !self.ports.is_empty()
+            && !self
+                .ports
+                .iter()
+                .any(|(lo, hi)| *lo <= req.port && req.port <= *hi)
+        {
+            return false;
+        }
+        if self.domains.is_empty() && self.cidrs.is_empty() {
+            return true;
+        }
+        match ip {
+            Some(ip) => self.cidrs.iter().any(|c| c.contains(ip)),
+            None => self.domains.iter().any(|d| d.is_match(host)),
+        }
+    }
+}
+
+/// Decides whether a request goes direct, gets blocked or which chain it uses.
+///
+/// Rules are checked in order and the first match wins. Requests without a
+/// match go through the default chain.
+#[derive(Debug, Default)]
+pub struct Router {
+    rules: Vec<Rule>,
+}
+
+impl Router {
+    pub fn new(rules: &[RouteRule]) -> Result<Router, String> {
+        let mut parsed = vec![];
+        for r in rules.iter() {
+            parsed.push(Rule::parse(r)?);
+        }
+        Ok(Router { rules: parsed })
+    }
+
+    pub fn route(&self, req: &Request) -> Route {
+        let host = req
+            .host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .trim_end_matches('.')
+            .to_lowercase();
+        let ip = host.parse::<IpAddr>().ok();
+        for rule in self.rules.iter() {
+            if rule.is_match(req, &host, ip.as_ref()) {
+                return rule.route.clone();
+            }
+        }
+        Route::Proxy("".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(action: RouteAction, domains: &[&str], cidrs: &[&str], ports: &[&str]) -> RouteRule {
+        let to_vec = |v: &[&str]| v.iter().map(|s| s.to_string()).collect();
+        RouteRule {
+            action,
+            profile: "".to_string(),
+            domains: to_vec(domains),
+            cidrs: to_vec(cidrs),
+            ports: to_vec(ports),
+            protocols: vec![],
+            users: vec![],
+        }
+    }
+
+    fn req<'a>(protocol: &'a str, host: &'a str, port: u16) -> Request<'a> {
+        Request {
+            protocol,
+            network: "tcp",
+            host,
+            port,
+            user: "",
+        }
+    }
+
+    #[test]
+    fn router_tests() {
+        let path = std::env::temp_dir().join(format!("thomas-geo-{}.txt", std::process::id()));
+        std::fs::write(&path, "# lan\n10.0.0.0/8\n\n192.168.0.0/16\n").unwrap();
+
+        let mut udp = rule(RouteAction::Block, &[], &[], &[]);
+        udp.protocols = vec!["udp".to_string()];
+        let mut proxy = rule(RouteAction::Proxy, &["keyword:google"], &[], &[]);
+        proxy.profile = "us".to_string();
+        let mut alice = rule(RouteAction::Direct, &[], &[], &["8080"]);
+        alice.users = vec!["alice".to_string()];
+        let rules = vec![
+            rule(
+                RouteAction::Direct,
+                &["full:bing.com", "cn", "regexp:^api\\d+\\.example\\.org$"],
+                &[&format!("file:{}", path.display()), "::1"],
+                &[],
+            ),
+            rule(RouteAction::Block, &[], &[], &["25", "6881-6889"]),
+            alice,
+            proxy,
+            udp,
+        ];
+        let router = Router::new(&rules).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let direct = Route::Direct;
+        let default = Route::Proxy("".to_string());
+        assert_eq!(router.route(&req("http", "bing.com", 443)), direct);
+        assert_eq!(router.route(&req("http", "www.bing.com", 443)), default);
+        assert_eq!(router.route(&req("socks5", "www.baidu.CN", 80)), direct);
+        assert_eq!(
+            router.route(&req("socks5", "api12.example.org", 80)),
+            direct
+        );
+        assert_eq!(router.route(&req("socks5", "api.example.org", 80)), default);
+        assert_eq!(router.route(&req("socks5", "10.1.2.3", 80)), direct);
+        assert_eq!(router.route(&req("socks5", "[::1]", 80)), direct);
+        assert_eq!(router.route(&req("socks5", "8.8.8.8", 25)), Route::Block);
+        assert_eq!(router.route(&req("http", "x.com", 6888)), Route::Block);
+        assert_eq!(
+            router.route(&req("http", "www.google.com", 443)),
+            Route::Proxy("us".to_string())
+        );
+
+        let mut r = req("socks5", "x.com", 8080);
+        assert_eq!(router.route(&r), default);
+        r.user = "alice";
+        assert_eq!(router.route(&r), direct);
+
+        let mut r = req("socks5", "", 0);
+        r.network = "udp";
+        assert_eq!(router.route(&r), Route::Block);
+        assert_eq!(Router::default().route(&r), default);
+
+        let bad = rule(RouteAction::Direct, &["regexp:("], &[], &[]);
+        assert!(Router::new(&[bad]).is_err());
+        let missing = rule(RouteAction::Direct, &["file:/no/such/file"], &[], &[]);
+        assert!(Router::new(&[missing]).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use crate::comm::models::TlsConfigs;
+use async_native_tls::{TlsAcceptor, TlsConnector, TlsStream};
+use async_std::net::TcpStream;
+use log::*;
+use native_tls::{Certificate, Identity};
+use std::{io, path::Path};
+
+/// An acceptor with the certificate chain and the private key in `cfg`, and
+/// the fingerprint of its certificate.
+pub fn acceptor(cfg: &TlsConfigs) -> Result<(TlsAcceptor, String), String> {
+    let (cert, key) = match cfg.self_signed {
+        true => self_signed(cfg)?,
+        false => (read(&cfg.cert)?, read(&cfg.key)?),
+    };
+    let der = Certificate::from_pem(&cert)
+        .and_then(|c| c.to_der())
+        .map_err(|e| format!("invalid certificate: {}", e))?;
+    let identity =
+        Identity::from_pkcs8(&cert, &key).map_err(|e| format!("invalid certificate: {}", e))?;
+    let acceptor =
+        native_tls::TlsAcceptor::new(identity).map_err(|e| format!("tls acceptor: {}", e))?;
+    Ok((TlsAcceptor::from(acceptor), fingerprint(&der)))
+}
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("read {}: {}", path, e))
+}
+
+// readable by the owner only on unix, also if the file was there
+fn write_private(path: &str, data: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+    let mut opts = std::fs::OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    let mut file = opts.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(data)
+}
+
+// the cert and key files if they exist, else a new certificate that is saved to
+// them, or only kept in memory if they are not set
+fn self_signed(cfg: &TlsConfigs) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let saved = !cfg.cert.is_empty() && !cfg.key.is_empty();
+    if saved && Path::new(&cfg.cert).exists() && Path::new(&cfg.key).exists() {
+        return Ok((read(&cfg.cert)?, read(&cfg.key)?));
+    }
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+        .map_err(|e| format!("generate certificate: {}", e))?;
+    let pem = cert
+        .serialize_pem()
+        .map_err(|e| format!("generate certificate: {}", e))?;
+    let key = cert.serialize_private_key_pem();
+    if saved {
+        std::fs::write(&cfg.cert, &pem).map_err(|e| format!("write {}: {}", cfg.cert, e))?;
+        write_private(&cfg.key, key.as_bytes()).map_err(|e| format!("write {}: {}", cfg.key, e))?;
+        // of the saved pem, each serialization is signed anew
+        let der = Certificate::from_pem(pem.as_bytes())
+            .and_then(|c| c.to_der())
+            .map_err(|e| format!("invalid certificate: {}", e))?;
+        info!(
+            "self-signed certificate saved to {}, fingerprint {}",
+            cfg.cert,
+            fingerprint(&der)
+        );
+    }
+    Ok((pem.into_bytes(), key.into_bytes()))
+}
+
+/// SHA-256 of a DER certificate as colon separated hex, like
+/// `openssl x509 -fingerprint -sha256`.
+pub fn fingerprint(der: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    let hash = Sha256::digest(der);
+    let hex: Vec<String> = hash.iter().map(|b| format!("{:02X}", b)).collect();
+    hex.join(":")
+}
+
+/// The pinned fingerprint in the query of a "wss://" url, for example
+/// "wss://1.2.3.4/?fingerprint=AB:CD:...".
+pub fn pin(url: &str) -> Option<String> {
+    let u = url::Url::parse(url).ok()?;
+    if u.scheme() != "wss" {
+        return None;
+    }
+    let (_, v) = u.query_pairs().find(|(k, _)| k == "fingerprint")?;
+    Some(v.to_string())
+}
+
+// the same hex digits, separators and case aside
+fn same_fingerprint(a: &str, b: &str) -> bool {
+    let digits = |s: &str| -> String {
+        s.chars()
+            .filter(|c| c.is_ascii_hexdigit())
+            .map(|c| c.to_ascii_uppercase())
+            .collect()
+    };
+    digits(a) == digits(b)
+}
+
+/// TLS to a server whose certificate has the fingerprint `pin`. Its name and
+/// issuer are not checked, so self-signed certificates work.
+pub async fn connect_pinned(
+    host: &str,
+    tcp_stream: TcpStream,
+    pin: &str,
+) -> io::Result<TlsStream<TcpStream>> {
+    let connector = TlsConnector::new()
+        .danger_accept_invalid_certs(true)
+        .danger_accept_invalid_hostnames(true);
+    let stream = connector
+        .connect(host, tcp_stream)
+        .await
+        .map_err(io::Error::other)?;
+    let der = match stream.peer_certificate() {
+        Ok(Some(cert)) => cert.to_der().map_err(io::Error::other)?,
+        _ => return Err(io::Error::other("no certificate")),
+    };
+    let actual = fingerprint(&der);
+    if !same_fingerprint(&actual, pin) {
+        let msg = format!("certificate fingerprint mismatch: {}", actual);
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
+    }
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_signed_tests() {
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        let cfg = TlsConfigs {
+            cert: dir
+                .join(format!("thomas-ss-cert-{}.pem", id))
+                .display()
+                .to_string(),
+            key: dir
+                .join(format!("thomas-ss-key-{}.pem", id))
+                .display()
+                .to_string(),
+            self_signed: true,
+        };
+        // generated once, then loaded with the same fingerprint
+        let (_, first) = acceptor(&cfg).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&cfg.key).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let (_, second) = acceptor(&cfg).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.len(), 32 * 3 - 1);
+
+        let (_, other) = acceptor(&TlsConfigs {
+            self_signed: true,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_ne!(first, other);
+        let _ = std::fs::remove_file(&cfg.cert);
+        let _ = std::fs::remove_file(&cfg.key);
+
+        assert!(acceptor(&TlsConfigs {
+            cert: cfg.cert.to_string(),
+            key: cfg.key.to_string(),
+            self_signed: false,
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn pin_tests() {
+        let pin = "ab:cd:01";
+        let url = format!("wss://1.2.3.4:3443/?fingerprint={}", pin);
+        assert_eq!(super::pin(&url).unwrap(), pin);
+        assert!(super::pin("wss://1.2.3.4:3443/").is_none());
+        assert!(super::pin("ws://1.2.3.4:3001/?fingerprint=ab").is_none());
+        assert!(same_fingerprint("AB:CD:01", "abcd01"));
+        assert!(!same_fingerprint("AB:CD:01", "AB:CD:02"));
+    }
+}
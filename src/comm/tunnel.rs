@@ -0,0 +1,390 @@
+use crate::comm::utils;
+use aes_gcm::{
+    aead::{Aead, NewAead},
+    Aes256Gcm,
+};
+use async_std::channel;
+use async_tungstenite::{
+    async_std::ConnectStream,
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Error, Message, Result,
+    },
+    WebSocketStream,
+};
+use futures::{Sink, Stream};
+use std::{
+    convert::TryInto,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+const SEQ_LEN: usize = 8;
+
+// the first plaintext byte of a sealed frame
+const FRAME_DATA: u8 = 0x00;
+const FRAME_END: u8 = 0x01;
+
+// sealed frame: [seq: u64 big endian][aes-256-gcm ciphertext of [flag][data]]
+struct FrameCipher {
+    cipher: Aes256Gcm,
+    seq: u64,
+}
+
+impl FrameCipher {
+    // `label` is the direction, "c2s" or "s2c"
+    fn new(key: &str, label: &str) -> Option<FrameCipher> {
+        let okm = utils::hkdf_sha256(key, &format!("thomas tunnel v1 {label}"))?;
+        Some(FrameCipher {
+            cipher: Aes256Gcm::new_varkey(&okm).ok()?,
+            seq: 0,
+        })
+    }
+
+    fn nonce(seq: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&seq.to_be_bytes());
+        nonce
+    }
+
+    // `end` marks the last frame of the direction, it carries no data
+    fn seal(&mut self, data: &[u8], end: bool) -> Option<Vec<u8>> {
+        let seq = self.seq;
+        let mut plaintext = vec![if end { FRAME_END } else { FRAME_DATA }];
+        plaintext.extend(data);
+        let ciphertext = self
+            .cipher
+            .encrypt(&Self::nonce(seq).into(), plaintext.as_ref())
+            .ok()?;
+        self.seq += 1;
+        let mut frame = seq.to_be_bytes().to_vec();
+        frame.extend(ciphertext);
+        Some(frame)
+    }
+
+    // returns the data and whether it is the end frame
+    fn open(&mut self, frame: &[u8]) -> Option<(Vec<u8>, bool)> {
+        if frame.len() < SEQ_LEN {
+            return None;
+        }
+        let seq = u64::from_be_bytes(frame[..SEQ_LEN].try_into().ok()?);
+        if seq != self.seq {
+            return None;
+        }
+        let mut plaintext = self
+            .cipher
+            .decrypt(&Self::nonce(seq).into(), &frame[SEQ_LEN..])
+            .ok()?;
+        let end = match plaintext.first() {
+            Some(&FRAME_DATA) => false,
+            Some(&FRAME_END) => true,
+            _ => return None,
+        };
+        self.seq += 1;
+        plaintext.remove(0);
+        Some((plaintext, end))
+    }
+}
+
+/// A websocket after the chain handshake.
+///
+/// A sealed tunnel encrypts every binary and text message between the client
+/// and the outlet, so inlets and relays only see ciphertext. A plain tunnel
+/// passes messages through unchanged. Empty binary messages are the
+/// end-of-stream marker, a sealed tunnel sends it as a sealed end frame, also
+/// before its close frame, and reports a close without it as an error.
+pub struct Tunnel {
+    ws: WebSocketStream<ConnectStream>,
+    sealer: Option<FrameCipher>,
+    opener: Option<FrameCipher>,
+    // the end frame of a sealed tunnel was sent / received
+    end_sent: bool,
+    end_received: bool,
+    // closed by the owner to end the tunnel, see `with_stop`
+    stop: Option<channel::Receiver<()>>,
+    stop_reason: &'static str,
+    stopped: bool,
+    // hops of a client tunnel as logged, from the first one to the target
+    chain: Vec<String>,
+    stats: Option<Arc<TunnelStats>>,
+}
+
+/// Payload bytes through a tunnel.
+#[derive(Debug, Default)]
+pub struct TunnelStats {
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+}
+
+fn payload_len(msg: &Message) -> usize {
+    match msg {
+        Message::Binary(data) => data.len(),
+        Message::Text(txt) => txt.len(),
+        _ => 0,
+    }
+}
+
+impl Tunnel {
+    fn new(
+        ws: WebSocketStream<ConnectStream>,
+        sealer: Option<FrameCipher>,
+        opener: Option<FrameCipher>,
+    ) -> Tunnel {
+        Tunnel {
+            ws,
+            sealer,
+            opener,
+            end_sent: false,
+            end_received: false,
+            stop: None,
+            stop_reason: "",
+            stopped: false,
+            chain: vec![],
+            stats: None,
+        }
+    }
+
+    pub fn plain(ws: WebSocketStream<ConnectStream>) -> Tunnel {
+        Tunnel::new(ws, None, None)
+    }
+
+    /// `key` is the ECDH key of the outlet's header frame, None if it is not
+    /// base64.
+    pub fn sealed(
+        ws: WebSocketStream<ConnectStream>,
+        key: &str,
+        is_client: bool,
+    ) -> Option<Tunnel> {
+        let (tx, rx) = if is_client {
+            ("c2s", "s2c")
+        } else {
+            ("s2c", "c2s")
+        };
+        let sealer = FrameCipher::new(key, tx)?;
+        let opener = FrameCipher::new(key, rx)?;
+        Some(Tunnel::new(ws, Some(sealer), Some(opener)))
+    }
+
+    /// Once `stop` is closed, the tunnel sends a close frame with `reason` to
+    /// the peer and ends the stream, so pumps reading from it finish.
+    pub fn with_stop(mut self, stop: channel::Receiver<()>, reason: &'static str) -> Tunnel {
+        self.stop = Some(stop);
+        self.stop_reason = reason;
+        self
+    }
+
+    pub fn with_chain(mut self, chain: Vec<String>) -> Tunnel {
+        self.chain = chain;
+        self
+    }
+
+    /// Counts the payload bytes into `stats` from now on.
+    pub fn with_stats(mut self, stats: Arc<TunnelStats>) -> Tunnel {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Hops of a client tunnel, from the first one to the target.
+    pub fn chain(&self) -> &[String] {
+        &self.chain
+    }
+
+    fn poll_stop(&mut self, cx: &mut Context<'_>) -> bool {
+        if self.stopped {
+            return true;
+        }
+        let stop = match self.stop.as_mut() {
+            Some(s) => s,
+            None => return false,
+        };
+        if let Poll::Ready(None) = Pin::new(stop).poll_next(cx) {
+            self.stopped = true;
+            let frame = CloseFrame {
+                code: CloseCode::Away,
+                reason: self.stop_reason.into(),
+            };
+            if let Poll::Ready(Ok(_)) = Pin::new(&mut self.ws).poll_ready(cx) {
+                let _ = Pin::new(&mut self.ws).start_send(Message::Close(Some(frame)));
+                let _ = Pin::new(&mut self.ws).poll_flush(cx);
+            }
+        }
+        self.stopped
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.sealer.is_some()
+    }
+
+    pub fn into_inner(self) -> WebSocketStream<ConnectStream> {
+        self.ws
+    }
+
+    fn seal(&mut self, msg: Message) -> Result<Message> {
+        let sealer = match self.sealer.as_mut() {
+            Some(s) => s,
+            None => return Ok(msg),
+        };
+        let data = match msg {
+            Message::Binary(data) => data,
+            Message::Text(txt) => txt.into_bytes(),
+            _ => return Ok(msg),
+        };
+        let end = data.is_empty();
+        if end && self.end_sent {
+            return Err(Error::Protocol("end frame already sent".into()));
+        }
+        match sealer.seal(&data, end) {
+            Some(frame) => {
+                self.end_sent = self.end_sent || end;
+                Ok(Message::Binary(frame))
+            }
+            None => Err(Error::Protocol("failed to seal frame".into())),
+        }
+    }
+
+    fn open(&mut self, msg: Message) -> Result<Message> {
+        let opener = match self.opener.as_mut() {
+            Some(o) => o,
+            None => return Ok(msg),
+        };
+        match msg {
+            Message::Binary(_) if self.end_received => {
+                Err(Error::Protocol("frame after the end frame".into()))
+            }
+            Message::Binary(data) => match opener.open(&data) {
+                Some((_, true)) => {
+                    self.end_received = true;
+                    Ok(Message::Binary(vec![]))
+                }
+                Some((plaintext, false)) => Ok(Message::Binary(plaintext)),
+                None => Err(Error::Protocol("failed to open sealed frame".into())),
+            },
+            Message::Text(_) => Err(Error::Protocol("unsealed frame".into())),
+            Message::Close(_) if !self.end_received => Err(truncated()),
+            _ => Ok(msg),
+        }
+    }
+
+    // sends the end frame of a sealed tunnel once, before it closes
+    fn poll_send_end(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if self.sealer.is_none() || self.end_sent {
+            return Poll::Ready(Ok(()));
+        }
+        match Pin::new(&mut self.ws).poll_ready(cx) {
+            Poll::Ready(Ok(_)) => {}
+            r => return r,
+        }
+        let msg = self.seal(Message::Binary(vec![]))?;
+        Pin::new(&mut self.ws).start_send(msg)?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn truncated() -> Error {
+    Error::Protocol("sealed tunnel closed without its end frame".into())
+}
+
+impl Stream for Tunnel {
+    type Item = Result<Message>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.poll_stop(cx) {
+            return Poll::Ready(None);
+        }
+        match Pin::new(&mut this.ws).poll_next(cx) {
+            // reported once, the stream ends after the error
+            Poll::Ready(None) if this.opener.is_some() && !this.end_received => {
+                this.end_received = true;
+                Poll::Ready(Some(Err(truncated())))
+            }
+            Poll::Ready(Some(Ok(msg))) => {
+                let msg = this.open(msg);
+                if let (Some(stats), Ok(m)) = (this.stats.as_ref(), msg.as_ref()) {
+                    let n = payload_len(m) as u64;
+                    stats.bytes_in.fetch_add(n, Ordering::Relaxed);
+                }
+                Poll::Ready(Some(msg))
+            }
+            r => r,
+        }
+    }
+}
+
+impl Sink<Message> for Tunnel {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().ws).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<()> {
+        let this = self.get_mut();
+        if let Some(stats) = this.stats.as_ref() {
+            let n = payload_len(&item) as u64;
+            stats.bytes_out.fetch_add(n, Ordering::Relaxed);
+        }
+        let msg = this.seal(item)?;
+        Pin::new(&mut this.ws).start_send(msg)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().ws).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        match this.poll_send_end(cx) {
+            Poll::Ready(Ok(_)) => Pin::new(&mut this.ws).poll_close(cx),
+            r => r,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_cipher_tests() {
+        let key = "v16H1K1N/zP+WU4MxlLY9/RcdOSKKC8pcMpJchHIqBw=";
+        let mut sealer = FrameCipher::new(key, "c2s").unwrap();
+        let mut opener = FrameCipher::new(key, "c2s").unwrap();
+        let mut wrong = FrameCipher::new(key, "s2c").unwrap();
+        assert!(FrameCipher::new("not base64", "c2s").is_none());
+
+        let f1 = sealer.seal(b"hello", false).unwrap();
+        let f2 = sealer.seal(b"hello", false).unwrap();
+        assert_ne!(f1, f2);
+        assert!(wrong.open(&f1).is_none());
+
+        // out of order frames are rejected
+        assert!(opener.open(&f2).is_none());
+        assert_eq!(opener.open(&f1).unwrap(), (b"hello".to_vec(), false));
+        assert!(opener.open(&f1).is_none());
+        assert_eq!(opener.open(&f2).unwrap(), (b"hello".to_vec(), false));
+
+        let mut f3 = sealer.seal(b"world", false).unwrap();
+        let n = f3.len();
+        f3[n - 1] ^= 0x01;
+        assert!(opener.open(&f3).is_none());
+    }
+
+    #[test]
+    fn end_frame_tests() {
+        let key = "v16H1K1N/zP+WU4MxlLY9/RcdOSKKC8pcMpJchHIqBw=";
+        let mut sealer = FrameCipher::new(key, "s2c").unwrap();
+        let mut opener = FrameCipher::new(key, "s2c").unwrap();
+
+        // the end frame is authenticated like data, an empty data frame is not an end
+        let data = sealer.seal(b"", false).unwrap();
+        let end = sealer.seal(b"", true).unwrap();
+        assert_eq!(opener.open(&data).unwrap(), (vec![], false));
+        assert_eq!(opener.open(&end).unwrap(), (vec![], true));
+        assert!(opener.open(&end).is_none());
+    }
+}
@@ -1,8 +1,10 @@
+use crate::comm::models::Cipher;
 use aes_gcm::{
-    aead::{generic_array::GenericArray, Aead, NewAead},
+    aead::{Aead, NewAead},
     Aes256Gcm,
 };
-use rand::Rng; // Or `Aes128Gcm`
+use chacha20poly1305::ChaCha20Poly1305;
+use rand::Rng;
 use rand_chacha::rand_core::SeedableRng;
 use rand_chacha::ChaCha20Rng;
 use std::convert::TryInto;
@@ -29,25 +31,25 @@ pub fn is_keypair(secret: &str, pubkey: &str) -> bool {
         let pubstr = base64::encode(key.as_bytes());
         return pubstr.eq(pubkey);
     }
-    return false;
+    false
 }
 
 pub fn b64_to_secret(b64: &str) -> Option<StaticSecret> {
-    if let Ok(arr) = base64::decode(&b64) {
-        let bytes: [u8; 32] = arr.try_into().unwrap();
+    if let Ok(arr) = base64::decode(b64) {
+        let bytes: [u8; 32] = arr.try_into().ok()?;
         let secret = StaticSecret::from(bytes);
         return Some(secret);
     }
-    return None;
+    None
 }
 
 pub fn b64_to_pubkey(b64: &str) -> Option<PublicKey> {
-    if let Ok(arr) = base64::decode(&b64) {
-        let bytes: [u8; 32] = arr.try_into().unwrap();
+    if let Ok(arr) = base64::decode(b64) {
+        let bytes: [u8; 32] = arr.try_into().ok()?;
         let pubkey = PublicKey::from(bytes);
         return Some(pubkey);
     }
-    return None;
+    None
 }
 
 #[cfg(test)]
@@ -71,10 +73,10 @@ mod tests_dh {
                 let derived = PublicKey::from(&prikey);
                 assert_eq!(pubstr, base64::encode(derived.as_bytes()));
             } else {
-                assert!(false);
+                panic!("invalid public key");
             }
         } else {
-            assert!(false);
+            panic!("invalid private key");
         }
     }
 }
@@ -101,6 +103,17 @@ pub fn addr_to_vec(socket_addr: std::net::SocketAddr) -> Vec<u8> {
     res
 }
 
+// "bing.com:443" or "[::1]:443" => (host, port)
+pub fn split_host_port(addr: &str) -> Option<(String, u16)> {
+    let (host, port) = addr.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_string(), port))
+}
+
 pub fn get_addr(link: &str) -> Result<String, Error> {
     if let Ok(url) = Url::parse(link) {
         if let Some(host) = url.host() {
@@ -109,11 +122,18 @@ pub fn get_addr(link: &str) -> Result<String, Error> {
             }
             match url.scheme() {
                 "https" | "wss" => return Ok(format!("{host}:443")),
-                "http" | "ws" | _ => return Ok(format!("{host}:80")),
+                _ => return Ok(format!("{host}:80")),
             }
         }
     }
-    return Err(Error::new(ErrorKind::InvalidInput, "parse addr failed"));
+    Err(Error::new(ErrorKind::InvalidInput, "parse addr failed"))
+}
+
+pub fn init_ssl_cert_env_vars() {
+    // SAFETY: called from main() before any other thread is spawned
+    unsafe {
+        openssl_probe::init_openssl_env_vars();
+    }
 }
 
 pub fn register_ctrl_c_handler() {
@@ -124,21 +144,109 @@ pub fn register_ctrl_c_handler() {
     .expect("error setting Ctrl-C handler");
 }
 
-pub fn parse_cmd_args(is_server: bool) -> Option<String> {
+/// Calls `f` on the first SIGINT or SIGTERM (Ctrl+C on windows) and exits
+/// on the second one. SIGHUP is left for `on_reload`.
+pub fn on_shutdown_signal<F>(f: F)
+where
+    F: Fn() + Send + 'static,
+{
+    let mut received = false;
+    let mut handle = move || {
+        if received {
+            println!("exit now");
+            std::process::exit(0);
+        }
+        received = true;
+        f();
+    };
+
+    #[cfg(unix)]
+    {
+        use signal_hook::{
+            consts::{SIGINT, SIGTERM},
+            iterator::Signals,
+        };
+        let mut signals = Signals::new([SIGINT, SIGTERM]).expect("error setting signal handler");
+        std::thread::spawn(move || {
+            for _ in signals.forever() {
+                handle();
+            }
+        });
+    }
+    #[cfg(not(unix))]
+    {
+        let handle = std::sync::Mutex::new(handle);
+        ctrlc::set_handler(move || (handle.lock().unwrap())())
+            .expect("error setting Ctrl-C handler");
+    }
+}
+
+fn modified(path: &str) -> Option<std::time::SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Calls `f` with the content of the config file `path` on SIGHUP (unix only)
+/// or when the file is modified. Without a file, SIGHUP is ignored.
+pub fn on_reload<F>(path: Option<String>, f: F)
+where
+    F: Fn(String) + Send + Sync + 'static,
+{
+    let f = std::sync::Arc::new(move |path: &str| match std::fs::read_to_string(path) {
+        Ok(config) => f(config),
+        Err(e) => log::error!("failed to read {}: {}", path, e),
+    });
+
+    #[cfg(unix)]
+    {
+        use signal_hook::{consts::SIGHUP, iterator::Signals};
+        let mut signals = Signals::new([SIGHUP]).expect("error setting signal handler");
+        let (path, f) = (path.clone(), f.clone());
+        std::thread::spawn(move || {
+            for _ in signals.forever() {
+                match path.as_ref() {
+                    Some(p) => f(p),
+                    None => log::warn!("no config file to reload"),
+                }
+            }
+        });
+    }
+
+    if let Some(path) = path {
+        std::thread::spawn(move || {
+            let mut last = modified(&path);
+            loop {
+                std::thread::sleep(std::time::Duration::from_secs(2));
+                let m = modified(&path);
+                if m.is_some() && m != last {
+                    last = m;
+                    f(&path);
+                }
+            }
+        });
+    }
+}
+
+/// Returns the config and the file it was loaded from.
+pub fn parse_cmd_args(is_server: bool) -> Option<(String, Option<String>)> {
     use clap::AppSettings;
     use std::io::Read;
 
     let name = crate::comm::cons::PKG_NAME;
     let ty = if is_server { "server" } else { "client" };
     let title = format!("{} {}", name, ty);
-    let matches = clap::App::new(title)
+    let app = clap::App::new(title)
         .setting(AppSettings::ArgRequiredElseHelp)
         .version(crate::comm::cons::VERSION)
         .author(crate::comm::cons::AUTHORS)
         .args_from_usage("-c, --config=[FILE] 'Load config from file'")
         .args_from_usage("-s, --stdin 'Read config from stdin'")
-        .args_from_usage("--key 'Generate random keypairs'")
-        .get_matches();
+        .args_from_usage("--key 'Generate random keypairs'");
+    let app = match is_server {
+        true => app
+            .args_from_usage("--stage-key 'Add a new keypair to the config and print the config'"),
+        false => app,
+    };
+    let matches = app.get_matches();
 
     if matches.occurrences_of("key") > 0 {
         let (pubkey, prikey) = generate_x25519_keypair();
@@ -147,52 +255,97 @@ pub fn parse_cmd_args(is_server: bool) -> Option<String> {
         return None;
     }
 
+    let stage = is_server && matches.occurrences_of("stage-key") > 0;
     let mut config = String::new();
+    let mut path = None;
     if matches.occurrences_of("stdin") > 0 {
-        println!("read config from stdin:");
-        if let Ok(_) = std::io::stdin().read_to_string(&mut config) {
-            return Some(config);
+        if !stage {
+            println!("read config from stdin:");
         }
-    }
-    if let Some(p) = matches.value_of("config") {
-        println!("load config from file: {}", p);
-        if let Ok(config) = std::fs::read_to_string(p) {
-            return Some(config);
+        if std::io::stdin().read_to_string(&mut config).is_err() {
+            return None;
         }
+    } else if let Some(p) = matches.value_of("config") {
+        if !stage {
+            println!("load config from file: {}", p);
+        }
+        config = std::fs::read_to_string(p).ok()?;
+        path = Some(p.to_string());
+    } else {
+        return None;
     }
-    None
+
+    if stage {
+        stage_key(&config);
+        return None;
+    }
+    Some((config, path))
+}
+
+// prints `config` with a new keypair in "keys" for the operator to save
+fn stage_key(config: &str) {
+    let mut cfgs: crate::comm::models::ServerConfigs = match serde_json::from_str(config) {
+        Ok(c) => c,
+        Err(e) => return eprintln!("parse config fail: {}", e),
+    };
+    let key = cfgs.stage_key();
+    eprintln!("staged pubkey: {}", key.pubkey);
+    eprintln!("give it to clients, then mark it primary and set deprecated_until on the old key");
+    println!("{}", serde_json::to_string_pretty(&cfgs).unwrap());
 }
 
-fn insecure_random_bytes(len: usize) -> Vec<u8> {
-    use rand::prelude::*;
+/// Random bytes from a CSPRNG seeded by the OS, for nonces and keys.
+pub fn secure_random_bytes(len: usize) -> Vec<u8> {
+    use rand_chacha::rand_core::RngCore;
     let mut data = vec![0u8; len];
-    rand::thread_rng().fill_bytes(&mut data);
+    ChaCha20Rng::from_entropy().fill_bytes(&mut data);
     data
 }
 
-pub fn aes_decrypt(nonce: &Vec<u8>, ciphertext: &Vec<u8>, key: &str) -> Option<String> {
-    let hash = sha256(key);
-    let key = GenericArray::from_slice(&hash);
-    let cipher = Aes256Gcm::new(key);
-    let plaintext = cipher.decrypt(GenericArray::from_slice(nonce), ciphertext.as_ref());
-    if let Ok(arr) = plaintext {
-        if let Ok(s) = std::str::from_utf8(&arr) {
-            return Some(s.to_string());
+/// HKDF-SHA256 of the base64 ECDH `key`, `info` labels what the key is for.
+pub fn hkdf_sha256(key: &str, info: &str) -> Option<Vec<u8>> {
+    let ikm = base64::decode(key).ok()?;
+    let mut okm = vec![0u8; 32];
+    hkdf::Hkdf::<sha2::Sha256>::new(None, &ikm)
+        .expand(info.as_bytes(), &mut okm)
+        .ok()?;
+    Some(okm)
+}
+
+pub fn aead_decrypt(
+    cipher: Cipher,
+    key: &[u8],
+    nonce: &[u8],
+    ciphertext: &[u8],
+) -> Option<Vec<u8>> {
+    let nonce: [u8; 12] = nonce.try_into().ok()?;
+    match cipher {
+        Cipher::Aes256Gcm => {
+            let c = Aes256Gcm::new_varkey(key).ok()?;
+            c.decrypt(&nonce.into(), ciphertext).ok()
+        }
+        Cipher::ChaCha20Poly1305 => {
+            let c = ChaCha20Poly1305::new_varkey(key).ok()?;
+            c.decrypt(&nonce.into(), ciphertext).ok()
         }
     }
-    None
 }
 
-pub fn aes_encrypt(text: &str, key: &str) -> Option<(Vec<u8>, Vec<u8>)> {
-    let hash = sha256(key);
-    let key = GenericArray::from_slice(&hash);
-    let cipher = Aes256Gcm::new(key);
-    let nonce = insecure_random_bytes(96 / 8);
-    let n = GenericArray::from_slice(&nonce); // 96-bits; unique per message
-    if let Ok(r) = cipher.encrypt(n, text.as_bytes()) {
-        return Some((nonce, r));
-    }
-    None
+/// Encrypts `data` with a random nonce, returns the nonce and the ciphertext.
+pub fn aead_encrypt(cipher: Cipher, key: &[u8], data: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let nonce = secure_random_bytes(96 / 8);
+    let n: [u8; 12] = nonce.clone().try_into().ok()?; // 96-bits; unique per message
+    let r = match cipher {
+        Cipher::Aes256Gcm => {
+            let c = Aes256Gcm::new_varkey(key).ok()?;
+            c.encrypt(&n.into(), data).ok()?
+        }
+        Cipher::ChaCha20Poly1305 => {
+            let c = ChaCha20Poly1305::new_varkey(key).ok()?;
+            c.encrypt(&n.into(), data).ok()?
+        }
+    };
+    Some((nonce, r))
 }
 
 pub fn sha256(text: &str) -> Vec<u8> {
@@ -203,6 +356,16 @@ pub fn sha256(text: &str) -> Vec<u8> {
     bytes.to_vec()
 }
 
+/// SHA-256 of `parts` one after the other.
+pub fn sha256_parts(parts: &[&[u8]]) -> Vec<u8> {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    for p in parts {
+        hasher.update(p);
+    }
+    hasher.finalize().to_vec()
+}
+
 pub fn rand_vec8(len: usize) -> Vec<u8> {
     (0..len).map(|_| rand::random::<u8>()).collect()
 }
@@ -226,11 +389,23 @@ mod tests {
         let key = "123456中he文llo".to_string();
         let text = "hello中文1234".to_string();
 
-        let (nonce, ciphertext) = aes_encrypt(&text, &key).unwrap();
-        let r = aes_decrypt(&nonce, &ciphertext, &key).unwrap();
+        let key = hkdf_sha256(&base64::encode(key), "test").unwrap();
 
-        println!("text: {text} decrpted: {r}");
-        assert_eq!(text, r);
+        for cipher in [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305]
+            .iter()
+            .copied()
+        {
+            let (nonce, ciphertext) = aead_encrypt(cipher, &key, text.as_bytes()).unwrap();
+            let r = aead_decrypt(cipher, &key, &nonce, &ciphertext).unwrap();
+            let r = String::from_utf8(r).unwrap();
+
+            println!("text: {text} decrpted: {r}");
+            assert_eq!(text, r);
+        }
+        let (nonce, ciphertext) = aead_encrypt(Cipher::Aes256Gcm, &key, text.as_bytes()).unwrap();
+        assert!(aead_decrypt(Cipher::ChaCha20Poly1305, &key, &nonce, &ciphertext).is_none());
+        let other = hkdf_sha256(&base64::encode("other"), "test").unwrap();
+        assert!(aead_decrypt(Cipher::Aes256Gcm, &other, &nonce, &ciphertext).is_none());
     }
 
     #[test]
@@ -245,6 +420,17 @@ mod tests {
         // panic: get_addr_wraper("bing.com", "bing.com");
     }
 
+    #[test]
+    fn split_host_port_test() {
+        let r = split_host_port("bing.com:443").unwrap();
+        assert_eq!(r, ("bing.com".to_string(), 443));
+        let r = split_host_port("[::1]:80").unwrap();
+        assert_eq!(r, ("::1".to_string(), 80));
+        assert!(split_host_port("bing.com").is_none());
+        assert!(split_host_port(":80").is_none());
+        assert!(split_host_port("bing.com:http").is_none());
+    }
+
     fn get_addr_wraper(url: &str, exp: &str) {
         print!("src: [{url}] exp: [{exp}] ");
         let addr = get_addr(url).unwrap();
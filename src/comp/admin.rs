@@ -0,0 +1,426 @@
+use crate::comp::{health, listener::Client};
+use async_std::{
+    future::timeout,
+    io::{ReadExt, WriteExt},
+    net::{TcpListener, TcpStream},
+    task,
+};
+use futures::StreamExt;
+use log::*;
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+
+// the longest request head that is read
+const MAX_HEAD: usize = 8192;
+
+/// A server of the configs with its probe results.
+#[derive(Serialize, Debug, Clone)]
+pub struct NodeState {
+    // usable in random chains
+    pub healthy: bool,
+    #[serde(flatten)]
+    pub health: health::NodeHealth,
+}
+
+struct Response {
+    code: u16,
+    body: serde_json::Value,
+}
+
+fn ok(body: serde_json::Value) -> Response {
+    Response { code: 200, body }
+}
+
+fn error(code: u16, msg: String) -> Response {
+    Response {
+        code,
+        body: json!({ "error": msg }),
+    }
+}
+
+fn reason(code: u16) -> &'static str {
+    match code {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        _ => "Internal Server Error",
+    }
+}
+
+fn nodes(client: &Client) -> Vec<NodeState> {
+    let dialer = client.dialer();
+    let snapshot = dialer.health().snapshot();
+    health::all_nodes(dialer.configs())
+        .into_iter()
+        .map(|n| {
+            let mut h = snapshot
+                .iter()
+                .find(|h| h.addr == n.addr)
+                .cloned()
+                .unwrap_or_default();
+            h.name = n.name.to_string();
+            h.addr = n.addr.to_string();
+            NodeState {
+                healthy: dialer.health().is_healthy(&n) && !h.disabled,
+                health: h,
+            }
+        })
+        .collect()
+}
+
+fn set_disabled(client: &Client, name: &str, disabled: bool) -> Response {
+    let dialer = client.dialer();
+    let matched: Vec<_> = health::all_nodes(dialer.configs())
+        .into_iter()
+        .filter(|n| n.name == name)
+        .collect();
+    if matched.is_empty() {
+        return error(404, format!("unknown node: {}", name));
+    }
+    for n in matched.iter() {
+        dialer.health().set_disabled(n, disabled);
+    }
+    ok(json!({ "name": name, "disabled": disabled }))
+}
+
+fn reload(client: &Client, path: Option<&str>) -> Response {
+    let path = match path {
+        Some(p) => p,
+        None => return error(400, "configs are not loaded from a file".to_string()),
+    };
+    let config = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => return error(500, format!("failed to read {}: {}", path, e)),
+    };
+    match client.reload_config(&config) {
+        Ok(_) => ok(json!({ "reloaded": path })),
+        Err(e) => error(400, e),
+    }
+}
+
+fn route(client: &Client, path: Option<&str>, method: &str, url: &str) -> Response {
+    let parts: Vec<&str> = url.trim_start_matches('/').split('/').collect();
+    match (method, parts.as_slice()) {
+        ("GET", ["connections"]) => ok(json!(client.conns().list())),
+        ("DELETE", ["connections", id]) => match id.parse::<u64>() {
+            Ok(id) if client.conns().kill(id) => ok(json!({ "killed": id })),
+            Ok(id) => error(404, format!("unknown connection: {}", id)),
+            Err(_) => error(400, format!("invalid id: {}", id)),
+        },
+        ("GET", ["nodes"]) => ok(json!(nodes(client))),
+        ("POST", ["nodes", name, "disable"]) => set_disabled(client, name, true),
+        ("POST", ["nodes", name, "enable"]) => set_disabled(client, name, false),
+        ("GET", ["profile"]) => {
+            let dialer = client.dialer();
+            let names: Vec<&str> = dialer
+                .configs()
+                .profiles
+                .iter()
+                .map(|p| p.name.as_str())
+                .collect();
+            ok(json!({ "active": client.profile(), "profiles": names }))
+        }
+        ("POST", ["profile", name]) => match client.switch_profile(name) {
+            Ok(_) => ok(json!({ "active": name })),
+            Err(e) => error(400, e),
+        },
+        ("POST", ["reload"]) => reload(client, path),
+        _ => error(404, format!("no such endpoint: {} {}", method, url)),
+    }
+}
+
+// the value of header `name` in a request head
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().skip(1).find_map(|line| {
+        let (k, v) = line.split_once(':')?;
+        match k.trim().eq_ignore_ascii_case(name) {
+            true => Some(v.trim()),
+            false => None,
+        }
+    })
+}
+
+// localhost or an IP, a name that resolves to this host can not be trusted
+fn is_local_host(host: &str) -> bool {
+    let url = match url::Url::parse(&format!("http://{}", host)) {
+        Ok(u) => u,
+        Err(_) => return false,
+    };
+    match url.host() {
+        Some(url::Host::Domain(d)) => d.eq_ignore_ascii_case("localhost"),
+        Some(_) => true,
+        None => false,
+    }
+}
+
+fn same_bytes(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// rejects requests without the token, and ones a web page could have sent
+fn check_request(head: &str, token: &str) -> Result<(), Response> {
+    let host = header(head, "Host").unwrap_or_default();
+    if !is_local_host(host) {
+        return Err(error(403, format!("host not allowed: {}", host)));
+    }
+    if let Some(origin) = header(head, "Origin") {
+        if origin != format!("http://{}", host) {
+            return Err(error(403, format!("origin not allowed: {}", origin)));
+        }
+    }
+    if !token.is_empty() {
+        let auth = header(head, "Authorization").unwrap_or_default();
+        if !same_bytes(auth.as_bytes(), format!("Bearer {}", token).as_bytes()) {
+            return Err(error(401, "invalid token".to_string()));
+        }
+    }
+    Ok(())
+}
+
+// reads up to the blank line after the headers, the body is not used
+async fn read_head(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut head = vec![];
+    let mut buff = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_HEAD {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "request head too long",
+            ));
+        }
+        let n = stream.read(&mut buff).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend(&buff[..n]);
+    }
+    Ok(String::from_utf8_lossy(&head).to_string())
+}
+
+async fn handle_request(
+    mut stream: TcpStream,
+    client: &Client,
+    path: Option<&str>,
+) -> std::io::Result<()> {
+    let dialer = client.dialer();
+    let cfg = dialer.configs();
+    let head = match timeout(cfg.timeouts.header(), read_head(&mut stream)).await {
+        Ok(r) => r?,
+        Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, e)),
+    };
+    let mut line = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = line.next().unwrap_or_default();
+    let url = line.next().unwrap_or_default();
+    debug!("admin: {} {}", method, url);
+
+    let resp = match check_request(&head, &cfg.admin_token) {
+        Ok(_) => route(client, path, method, url),
+        Err(resp) => {
+            warn!("admin: reject {} {}: {}", method, url, resp.body["error"]);
+            resp
+        }
+    };
+    let body = resp.body.to_string();
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        resp.code,
+        reason(resp.code),
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await
+}
+
+/// Serves the admin API on `listener`. `path` is the config file to reload.
+pub async fn run(listener: TcpListener, client: Arc<Client>, path: Option<String>) {
+    let mut incoming = listener.incoming();
+    while let Some(conn) = incoming.next().await {
+        let stream = match conn {
+            Ok(s) => s,
+            Err(e) => {
+                // e.g. out of file descriptors, wait for some to be closed
+                warn!("admin accept error: {}", e);
+                task::sleep(std::time::Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let (client, path) = (client.clone(), path.clone());
+        task::spawn(async move {
+            if let Err(e) = handle_request(stream, &client, path.as_deref()).await {
+                debug!("admin request failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Refuses to listen on a non-loopback address without `admin_token`.
+pub async fn serv(addr: String, client: Arc<Client>, path: Option<String>) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            error!("failed to listen on {}: {}", addr, e);
+            return;
+        }
+    };
+    let loopback = match listener.local_addr() {
+        Ok(a) => a.ip().is_loopback(),
+        Err(_) => false,
+    };
+    if !loopback && client.dialer().configs().admin_token.is_empty() {
+        error!(
+            "admin api on {} is not loopback, it needs admin_token",
+            addr
+        );
+        return;
+    }
+    info!("admin api on http://{}", addr);
+    run(listener, client, path).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comm::models;
+
+    async fn request(addr: &str, method: &str, url: &str) -> (u16, serde_json::Value) {
+        send(
+            addr,
+            &format!("{} {} HTTP/1.1\r\nHost: {}\r\n\r\n", method, url, addr),
+        )
+        .await
+    }
+
+    async fn send(addr: &str, req: &str) -> (u16, serde_json::Value) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        // the head in two writes
+        let (a, b) = req.split_at(req.len() / 2);
+        stream.write_all(a.as_bytes()).await.unwrap();
+        stream.flush().await.unwrap();
+        task::sleep(std::time::Duration::from_millis(20)).await;
+        stream.write_all(b.as_bytes()).await.unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+        let code = resp[9..12].parse().unwrap();
+        let body = resp.split("\r\n\r\n").nth(1).unwrap();
+        (code, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn admin_tests() {
+        task::block_on(async {
+            let node = |name: &str| models::ServerInfo {
+                name: name.to_string(),
+                addr: format!("ws://{}.local:3001", name),
+                pubkey: "".to_string(),
+            };
+            let mut cfg = models::ClientConfigs {
+                length: 0,
+                outlets: vec![node("a"), node("b")],
+                ..Default::default()
+            };
+            cfg.profiles = vec![models::ChainProfile {
+                name: "us".to_string(),
+                ..Default::default()
+            }];
+            let path =
+                std::env::temp_dir().join(format!("thomas-admin-{}.json", std::process::id()));
+            cfg.length = 2;
+            std::fs::write(&path, serde_json::to_string(&cfg).unwrap()).unwrap();
+            cfg.length = 0;
+
+            let client = Arc::new(Client::new(cfg).unwrap());
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let file = Some(path.display().to_string());
+            task::spawn(run(listener, client.clone(), file));
+
+            let (code, body) = request(&addr, "GET", "/connections").await;
+            assert_eq!(code, 200);
+            assert_eq!(body, json!([]));
+            let (code, _) = request(&addr, "DELETE", "/connections/9").await;
+            assert_eq!(code, 404);
+
+            let (code, _) = request(&addr, "POST", "/nodes/a/disable").await;
+            assert_eq!(code, 200);
+            let (_, body) = request(&addr, "GET", "/nodes").await;
+            assert_eq!(body[0]["name"], "a");
+            assert_eq!(body[0]["disabled"], true);
+            assert_eq!(body[0]["healthy"], false);
+            assert_eq!(body[1]["healthy"], true);
+            let (code, _) = request(&addr, "POST", "/nodes/x/enable").await;
+            assert_eq!(code, 404);
+
+            let (code, _) = request(&addr, "POST", "/profile/eu").await;
+            assert_eq!(code, 400);
+            let (code, _) = request(&addr, "POST", "/profile/us").await;
+            assert_eq!(code, 200);
+            let (_, body) = request(&addr, "GET", "/profile").await;
+            assert_eq!(body["active"], "us");
+
+            let (code, _) = request(&addr, "POST", "/reload").await;
+            assert_eq!(code, 200);
+            assert_eq!(client.dialer().configs().length, 2);
+            std::fs::remove_file(&path).unwrap();
+            let (code, _) = request(&addr, "POST", "/reload").await;
+            assert_eq!(code, 500);
+
+            // requests a web page could send through a rebound name or cross origin
+            let req = format!("GET /nodes HTTP/1.1\r\nHost: evil.com:{}\r\n\r\n", 9090);
+            assert_eq!(send(&addr, &req).await.0, 403);
+            let req = format!(
+                "POST /reload HTTP/1.1\r\nHost: {}\r\nOrigin: http://evil.com\r\n\r\n",
+                addr
+            );
+            assert_eq!(send(&addr, &req).await.0, 403);
+            let req = format!(
+                "GET /nodes HTTP/1.1\r\nhost: {}\r\norigin: http://{}\r\n\r\n",
+                addr, addr
+            );
+            assert_eq!(send(&addr, &req).await.0, 200);
+        });
+    }
+
+    #[test]
+    fn admin_token_tests() {
+        task::block_on(async {
+            let cfg = models::ClientConfigs {
+                admin_token: "s3cret".to_string(),
+                timeouts: models::Timeouts {
+                    header: 1,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let client = Arc::new(Client::new(cfg).unwrap());
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            task::spawn(run(listener, client.clone(), None));
+
+            assert_eq!(request(&addr, "GET", "/connections").await.0, 401);
+            let req = |token: &str| {
+                format!(
+                    "GET /connections HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer {}\r\n\r\n",
+                    addr, token
+                )
+            };
+            assert_eq!(send(&addr, &req("wrong")).await.0, 401);
+            assert_eq!(send(&addr, &req("s3cret")).await.0, 200);
+
+            // a request head that never ends is dropped
+            let mut stream = TcpStream::connect(&addr).await.unwrap();
+            stream
+                .write_all(b"GET /connections HTTP/1.1\r\n")
+                .await
+                .unwrap();
+            let mut buff = vec![];
+            assert_eq!(stream.read_to_end(&mut buff).await.unwrap(), 0);
+
+            // no token, no listener on other addresses
+            let open = Arc::new(Client::new(models::ClientConfigs::default()).unwrap());
+            serv("0.0.0.0:0".to_string(), open, None).await;
+        });
+    }
+}
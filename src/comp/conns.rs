@@ -0,0 +1,219 @@
+use crate::comm::{
+    models::{Cmds, Timeouts},
+    mux::MuxStream,
+    tunnel::{Tunnel, TunnelStats},
+};
+use async_std::{channel, task};
+use futures::future::{select, Future};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+/// A proxied connection as listed by the admin API.
+#[derive(Serialize, Debug, Clone)]
+pub struct ConnInfo {
+    pub id: u64,
+    pub cmd: String,
+    pub target: String,
+    pub profile: String,
+    // the authenticated user of the listener, empty without users
+    pub user: String,
+    pub chain: Vec<String>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // seconds since the tunnel was established
+    pub age: u64,
+}
+
+/// A tunnel or a mux stream that the admin API can list and kill.
+pub trait Trackable: Sized {
+    fn chain(&self) -> &[String];
+    fn with_stats(self, stats: Arc<TunnelStats>) -> Self;
+    fn with_stop(self, stop: channel::Receiver<()>) -> Self;
+}
+
+impl Trackable for Tunnel {
+    fn chain(&self) -> &[String] {
+        Tunnel::chain(self)
+    }
+
+    fn with_stats(self, stats: Arc<TunnelStats>) -> Self {
+        Tunnel::with_stats(self, stats)
+    }
+
+    fn with_stop(self, stop: channel::Receiver<()>) -> Self {
+        Tunnel::with_stop(self, stop, "killed by admin")
+    }
+}
+
+impl Trackable for MuxStream {
+    fn chain(&self) -> &[String] {
+        MuxStream::chain(self)
+    }
+
+    fn with_stats(self, stats: Arc<TunnelStats>) -> Self {
+        MuxStream::with_stats(self, stats)
+    }
+
+    fn with_stop(self, stop: channel::Receiver<()>) -> Self {
+        MuxStream::with_stop(self, stop)
+    }
+}
+
+struct Entry {
+    cmd: String,
+    target: String,
+    profile: String,
+    user: String,
+    chain: Vec<String>,
+    started: Instant,
+    stats: Arc<TunnelStats>,
+    kill: channel::Sender<()>,
+}
+
+/// Connections of the client that go through a chain.
+#[derive(Default)]
+pub struct Conns {
+    next_id: AtomicU64,
+    entries: Mutex<BTreeMap<u64, Entry>>,
+}
+
+impl Conns {
+    /// Lists `tunnel` until the returned `Conn` is dropped.
+    pub fn track<T: Trackable>(
+        self: &Arc<Self>,
+        tunnel: T,
+        cmd: &Cmds,
+        target: &str,
+        profile: &str,
+        user: &str,
+    ) -> (T, Conn) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let stats = Arc::new(TunnelStats::default());
+        let (kill, killed) = channel::bounded(1);
+        let entry = Entry {
+            cmd: format!("{:?}", cmd),
+            target: target.to_string(),
+            profile: profile.to_string(),
+            user: user.to_string(),
+            chain: tunnel.chain().to_vec(),
+            started: Instant::now(),
+            stats: stats.clone(),
+            kill,
+        };
+        self.entries.lock().unwrap().insert(id, entry);
+        let tunnel = tunnel.with_stats(stats).with_stop(killed.clone());
+        let conn = Conn {
+            id,
+            conns: self.clone(),
+            killed,
+        };
+        (tunnel, conn)
+    }
+
+    pub fn list(&self) -> Vec<ConnInfo> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .map(|(id, e)| ConnInfo {
+                id: *id,
+                cmd: e.cmd.to_string(),
+                target: e.target.to_string(),
+                profile: e.profile.to_string(),
+                user: e.user.to_string(),
+                chain: e.chain.clone(),
+                bytes_in: e.stats.bytes_in.load(Ordering::Relaxed),
+                bytes_out: e.stats.bytes_out.load(Ordering::Relaxed),
+                age: e.started.elapsed().as_secs(),
+            })
+            .collect()
+    }
+
+    /// Closes the tunnel of connection `id`, false if there is no such connection.
+    pub fn kill(&self, id: u64) -> bool {
+        match self.entries.lock().unwrap().get(&id) {
+            Some(e) => e.kill.close(),
+            None => false,
+        }
+    }
+}
+
+/// A tracked connection, unlisted when dropped.
+pub struct Conn {
+    id: u64,
+    conns: Arc<Conns>,
+    killed: channel::Receiver<()>,
+}
+
+impl Conn {
+    /// Runs `pump` until it finishes. After a kill the pump gets the close
+    /// timeout to shut down and is dropped after that.
+    pub async fn run<F>(self, pump: F, t: &Timeouts)
+    where
+        F: Future<Output = ()>,
+    {
+        let killed = async {
+            let _ = self.killed.recv().await;
+            task::sleep(t.close()).await;
+        };
+        select(Box::pin(pump), Box::pin(killed)).await;
+    }
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        self.conns.entries.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comp::{dialer::ChainDialer, testing};
+    use async_tungstenite::tungstenite::Message;
+    use futures::{SinkExt, StreamExt};
+
+    #[test]
+    fn track_tests() {
+        task::block_on(async {
+            let target = testing::echo_server().await;
+            let (_, _, relay) = testing::start_relay("relay").await;
+            let (_, _, outlet) = testing::start_relay("outlet").await;
+            let dialer = ChainDialer::new(crate::comm::models::ClientConfigs {
+                length: 1,
+                relays: vec![relay],
+                outlets: vec![outlet],
+                ..Default::default()
+            });
+            let cmd = Cmds::Connect;
+            let tunnel = dialer.dial(cmd.clone(), &target).await.unwrap();
+
+            // listed with its chain and bytes while open
+            let conns = Arc::new(Conns::default());
+            let (mut tunnel, conn) = conns.track(tunnel, &cmd, &target, "", "alice");
+            let hello = Message::binary(b"hello".to_vec());
+            tunnel.send(hello).await.unwrap();
+            tunnel.next().await.unwrap().unwrap();
+            let list = conns.list();
+            assert_eq!(list.len(), 1);
+            let chain = ["relay", "outlet", &target].map(|s| s.to_string());
+            assert_eq!(list[0].chain, chain);
+            assert_eq!(list[0].user, "alice");
+            assert_eq!((list[0].bytes_in, list[0].bytes_out), (5, 5));
+
+            // a kill stops the pump and unlists it
+            let t = dialer.configs().timeouts;
+            let pump = async move { while let Some(Ok(_)) = tunnel.next().await {} };
+            assert!(conns.kill(list[0].id));
+            assert!(!conns.kill(list[0].id + 1));
+            conn.run(pump, &t).await;
+            assert!(conns.list().is_empty());
+        });
+    }
+}
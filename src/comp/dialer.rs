@@ -1,121 +1,573 @@
 use crate::{
-    comm::{cons::CONN_TIMEOUT, infrs, models, utils},
-    comp,
+    comm::{
+        infrs,
+        metrics::{self, Failure},
+        models::{self, DialError, Status},
+        tunnel::Tunnel,
+        utils,
+    },
+    comp::{self, health::Health},
 };
-use async_std::{future::timeout, stream::StreamExt};
+use async_std::{future::timeout, net::TcpStream, stream::StreamExt};
 use async_tungstenite::{
-    async_std::{client_async_tls, connect_async, ConnectStream},
-    tungstenite::{Error, Message, Result},
+    async_std::ConnectStream,
+    tungstenite::{Error, Message},
     WebSocketStream,
 };
 use futures::SinkExt;
 use log::*;
 use rand::prelude::SliceRandom;
+use std::{
+    convert::TryFrom,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use x25519_dalek::PublicKey;
 
-pub async fn dial(
+/// Opens connections through a randomly built proxy chain.
+#[derive(Clone)]
+pub struct ChainDialer {
+    cfg: Arc<models::ClientConfigs>,
+    health: Arc<Health>,
+}
+
+impl ChainDialer {
+    pub fn new(cfg: models::ClientConfigs) -> ChainDialer {
+        let health = Arc::new(Health::new(cfg.max_failures));
+        ChainDialer {
+            cfg: Arc::new(cfg),
+            health,
+        }
+    }
+
+    /// A dialer using `cfg` that keeps the health of the servers probed so far,
+    /// unless `max_failures` changed.
+    pub fn reload(&self, cfg: models::ClientConfigs) -> ChainDialer {
+        if cfg.max_failures != self.cfg.max_failures {
+            return ChainDialer::new(cfg);
+        }
+        ChainDialer {
+            cfg: Arc::new(cfg),
+            health: self.health.clone(),
+        }
+    }
+
+    pub fn configs(&self) -> &models::ClientConfigs {
+        &self.cfg
+    }
+
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    /// Handshakes with `node` alone, which answers without connecting anywhere.
+    pub async fn ping(&self, node: &models::ServerInfo) -> Result<(), DialError> {
+        let profile = models::ChainProfile {
+            outlets: vec![node.clone()],
+            ..Default::default()
+        };
+        let tail = models::HeaderFrame::new(models::Cmds::Ping, "");
+        let tunnel = dial_core(&self.cfg, &self.health, &profile, &[], tail).await?;
+        infrs::close_ws_stream(tunnel.into_inner(), &self.cfg.timeouts).await;
+        Ok(())
+    }
+
+    /// Connects to `target` ("host:port") and returns the tunnel as a byte stream.
+    pub async fn connect(&self, target: &str) -> Result<infrs::ChainStream, DialError> {
+        let ws_stream = self.dial(models::Cmds::Connect, target).await?;
+        Ok(infrs::ChainStream::new(ws_stream))
+    }
+
+    /// Sends `cmd` to the last node of a new chain and returns the tunnel.
+    pub async fn dial(&self, cmd: models::Cmds, target: &str) -> Result<Tunnel, DialError> {
+        self.dial_with("", cmd, target).await
+    }
+
+    /// Same as `dial` but builds the chain from the named profile, empty means the default chain.
+    pub async fn dial_with(
+        &self,
+        profile: &str,
+        cmd: models::Cmds,
+        target: &str,
+    ) -> Result<Tunnel, DialError> {
+        let cfg = &self.cfg;
+        let profile = match cfg.profile(profile) {
+            Some(p) => p,
+            None => {
+                warn!("unknown profile [{}]", profile);
+                return Err(DialError::new(Status::GeneralFailure, ""));
+            }
+        };
+        let deadline = match cfg.retry_deadline {
+            0 => None,
+            secs => Some(Instant::now() + Duration::from_secs(secs)),
+        };
+
+        // nodes that failed in previous attempts
+        let mut excluded = vec![];
+        let mut attempt = 1;
+        loop {
+            let mut tail = models::HeaderFrame::new(cmd.clone(), target);
+            tail.e2e = cfg.encrypt;
+            let dialing = dial_core(cfg, &self.health, &profile, &excluded, tail);
+            let r = match deadline {
+                Some(d) => {
+                    let left = d.saturating_duration_since(Instant::now());
+                    match timeout(left, dialing).await {
+                        Ok(r) => r,
+                        Err(_) => Err(DialError::new(Status::TtlExpired, "")),
+                    }
+                }
+                None => dialing.await,
+            };
+            let e = match r {
+                Ok(s) => return Ok(s),
+                Err(e) => e,
+            };
+            let expired = deadline.is_some_and(|d| Instant::now() >= d);
+            if attempt >= cfg.max_attempts || expired || !e.is_retryable() {
+                info!("failed to connect {}: {}", target, e);
+                metrics::dial_failed(&e.status);
+                return Err(e);
+            }
+            info!("attempt {} to {} failed: {}, retry", attempt, target, e);
+            if !e.hop.is_empty() {
+                excluded.push(e.hop);
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Builds a chain from the named profile up to its last node, see `WarmChain`.
+    pub async fn warm(&self, profile: &str) -> Result<WarmChain, DialError> {
+        let profile = match self.cfg.profile(profile) {
+            Some(p) => p,
+            None => return Err(DialError::new(Status::GeneralFailure, "")),
+        };
+        warm_core(&self.cfg, &self.health, &profile).await
+    }
+
+    /// Connects to `target` and pumps data between it and `local` until either side closes.
+    pub async fn relay(&self, local: TcpStream, target: &str) -> Result<(), DialError> {
+        let ws_stream = self.dial(models::Cmds::Connect, target).await?;
+        infrs::pump_ws_tcp(local, ws_stream, &self.cfg.timeouts).await;
+        Ok(())
+    }
+}
+
+async fn connect_proxy(proxy: &str, next: &str) -> std::io::Result<TcpStream> {
+    let inner = comp::proxy::InnerProxy::from_proxy_str(proxy)?;
+    Ok(inner.connect_async(next).await?.into_inner())
+}
+
+async fn read_reply(
+    ws_stream: &mut WebSocketStream<ConnectStream>,
+    hop: &str,
+) -> Result<Message, DialError> {
+    loop {
+        match ws_stream.next().await {
+            // answers to the keepalive pings of a warm chain
+            Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => continue,
+            Some(Ok(msg)) => return Ok(msg),
+            _ => {
+                warn!("[{}] closed the connection", hop);
+                return Err(DialError::new(Status::GeneralFailure, hop));
+            }
+        }
+    }
+}
+
+// one header round-trip, returns whether the server accepts to seal the payload
+async fn send_header(
+    ws_stream: &mut WebSocketStream<ConnectStream>,
+    chain: &models::ProxyChain,
+    i: usize,
+    e2e: bool,
+) -> Result<bool, DialError> {
+    let last = chain.headers.len() - 1;
+    let hop = chain.hop_name(i);
+    let header = &chain.headers[i];
+    let msg = match header.format {
+        models::HeaderFormat::Binary => Message::binary(header.to_bytes()),
+        models::HeaderFormat::Json | models::HeaderFormat::Legacy => match header.to_string() {
+            Some(h) => Message::text(h),
+            None => {
+                warn!("failed to serialize header");
+                return Err(DialError::new(Status::GeneralFailure, hop));
+            }
+        },
+    };
+    if ws_stream.send(msg).await.is_err() {
+        warn!("send header error");
+        return Err(DialError::new(Status::GeneralFailure, hop));
+    }
+
+    let hash = read_reply(ws_stream, hop).await?.into_data();
+    if chain.hashes[i].eq(&hash) {
+        // old servers do not send status
+        if i == last && e2e {
+            warn!("[{}] does not support payload encryption", hop);
+            return Err(DialError::new(Status::GeneralFailure, hop));
+        }
+        return Ok(false);
+    }
+    if !chain.acks[i].eq(&hash) {
+        warn!("proxy [{}] reply with incorrect hash", hop);
+        metrics::handshake_failed(Failure::BadHash);
+        return Err(DialError::new(Status::GeneralFailure, hop));
+    }
+
+    let msg = read_reply(ws_stream, hop).await?;
+    let status = match msg.to_text().ok().and_then(models::Status::from_message) {
+        Some(s) => s,
+        None => {
+            warn!("[{}] reply with invalid status", hop);
+            return Err(DialError::new(Status::GeneralFailure, hop));
+        }
+    };
+    match status {
+        Status::Ok => Ok(i == last && e2e),
+        Status::NextHopUnreachable if i < last => {
+            let next = chain.hop_name(i + 1);
+            warn!("[{}] reports {}: [{}]", hop, status, next);
+            Err(DialError::new(status, next))
+        }
+        _ => {
+            warn!("[{}] reports {}", hop, status);
+            Err(DialError::new(status, hop))
+        }
+    }
+}
+
+// sends the headers of `hops`, returns whether the payload is sealed
+async fn handshake(
+    ws_stream: &mut WebSocketStream<ConnectStream>,
+    chain: &models::ProxyChain,
+    hops: Range<usize>,
+    e2e: bool,
+    t: &models::Timeouts,
+) -> Result<bool, DialError> {
+    let mut sealed = false;
+    for i in hops {
+        match timeout(t.header(), send_header(ws_stream, chain, i, e2e)).await {
+            Ok(r) => sealed = r?,
+            Err(_) => {
+                let hop = chain.hop_name(i);
+                warn!("[{}] reply timeout", hop);
+                metrics::handshake_failed(Failure::Timeout);
+                return Err(DialError::new(Status::TtlExpired, hop));
+            }
+        }
+    }
+    Ok(sealed)
+}
+
+// connects to the first node, directly or through the front proxy
+async fn connect_first(
     cfg: &models::ClientConfigs,
-    cmd: models::Cmds,
-    target: &str,
-) -> Result<WebSocketStream<ConnectStream>> {
-    let tail = models::HeaderFrame {
-        cmd,
-        param: target.to_string(),
-        padding: utils::rand_padding(),
+    chain: &models::ProxyChain,
+) -> Result<WebSocketStream<ConnectStream>, DialError> {
+    let t = &cfg.timeouts;
+    let first = chain.hop_name(0);
+    let conn = if cfg.proxy.is_empty() {
+        infrs::connect_ws(&chain.next, t).await
+    } else {
+        let tcp_stream = match timeout(t.connect(), connect_proxy(&cfg.proxy, &chain.next)).await {
+            Ok(Ok(s)) => s,
+            Ok(Err(e)) => {
+                warn!("fail to connect [{}] through proxy: {}", first, e);
+                return Err(DialError::new(Status::NextHopUnreachable, "proxy"));
+            }
+            Err(_) => {
+                warn!("connect [{}] through proxy timeout", first);
+                return Err(DialError::new(Status::TtlExpired, "proxy"));
+            }
+        };
+        infrs::handshake_ws(&chain.next, tcp_stream, t).await
     };
-    match dial_core(&cfg, tail).await {
-        Ok(s) => Ok(s),
+    match conn {
+        Ok(ws_stream) => Ok(ws_stream),
+        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+            warn!("connect proxy server [{}]: {}", first, e);
+            metrics::handshake_failed(Failure::Timeout);
+            Err(DialError::new(Status::TtlExpired, first))
+        }
         Err(e) => {
-            info!("failed to connect {}", target);
-            Err(e)
+            warn!("fail to connect proxy server [{}]: {}", first, e);
+            Err(DialError::new(Status::NextHopUnreachable, first))
         }
     }
 }
 
 async fn dial_core(
     cfg: &models::ClientConfigs,
+    health: &Health,
+    profile: &models::ChainProfile,
+    excluded: &[String],
     tail: models::HeaderFrame,
-) -> Result<WebSocketStream<ConnectStream>> {
-    let make_chain_result = make_chain(&cfg, tail);
-    if make_chain_result.is_none() {
-        warn!("can not create proxy chain");
-        return Err(Error::ConnectionClosed);
+) -> Result<Tunnel, DialError> {
+    let e2e = tail.e2e;
+    let ping = tail.cmd == models::Cmds::Ping;
+    let chain = match make_chain(cfg, health, profile, excluded, tail) {
+        Some(c) => c,
+        None => {
+            warn!("can not create proxy chain");
+            return Err(DialError::new(Status::GeneralFailure, ""));
+        }
+    };
+    info!("chain: [{}]", chain.names.join(", "));
+
+    let mut ws_stream = connect_first(cfg, &chain).await?;
+    let hops = 0..chain.headers.len();
+    let r = handshake(&mut ws_stream, &chain, hops, e2e, &cfg.timeouts).await;
+    if r.is_ok() && !ping {
+        metrics::chain_length(chain.headers.len());
+    }
+    match r {
+        Ok(sealed) => into_tunnel(ws_stream, chain, sealed),
+        Err(e) => {
+            infrs::close_ws_stream(ws_stream, &cfg.timeouts).await;
+            Err(e)
+        }
     }
+}
 
-    let chain = make_chain_result.unwrap();
-    info!("chain: [{}]", chain.names.join(", "));
+// wraps a handshaken chain, sealed with the key of its outlet if `sealed`
+fn into_tunnel(
+    ws_stream: WebSocketStream<ConnectStream>,
+    chain: models::ProxyChain,
+    sealed: bool,
+) -> Result<Tunnel, DialError> {
+    if !sealed {
+        return Ok(Tunnel::plain(ws_stream).with_chain(chain.names));
+    }
+    match Tunnel::sealed(ws_stream, &chain.key, true) {
+        Some(tunnel) => Ok(tunnel.with_chain(chain.names)),
+        // the stream is dropped, the outlet sees it reset
+        None => {
+            warn!("invalid payload encryption key");
+            Err(DialError::new(Status::GeneralFailure, ""))
+        }
+    }
+}
 
-    let conn;
-    if cfg.proxy.is_empty() {
-        conn = timeout(CONN_TIMEOUT, connect_async(&chain.next)).await;
-    } else {
-        // info!("host: {}", &first);
-        let s5tcp = comp::proxy::InnerProxy::from_proxy_str(&cfg.proxy)
-            .unwrap()
-            .connect_async(&chain.next)
-            .await
-            .unwrap()
-            .into_inner();
-        conn = timeout(CONN_TIMEOUT, client_async_tls(&chain.next, s5tcp)).await;
-    }
-    if let Ok(Ok((mut ws_stream, _))) = conn {
-        for i in 0..chain.headers.len() {
-            if let Some(header) = chain.headers[i].to_string() {
-                if let Ok(_) = timeout(CONN_TIMEOUT, ws_stream.send(Message::text(header))).await {
-                    if let Ok(Some(Ok(msg))) = timeout(CONN_TIMEOUT, ws_stream.next()).await {
-                        let hash = msg.into_data();
-                        if chain.hashes[i].eq(&hash) {
-                            continue;
-                        } else {
-                            warn!("proxy [{}] reply with incorrect hash", chain.names[i]);
-                        }
-                    } else {
-                        warn!("read hash error");
-                    }
-                } else {
-                    warn!("send header error");
+/// A chain handshaken up to the last node, which waits for its header.
+pub struct WarmChain {
+    ws: WebSocketStream<ConnectStream>,
+    // the tail header is a placeholder until `finish`
+    chain: models::ProxyChain,
+    born: Instant,
+    // pings sent, the payload of the next one
+    pings: u64,
+}
+
+impl WarmChain {
+    pub fn age(&self) -> Duration {
+        self.born.elapsed()
+    }
+
+    /// Sends a ping through the chain to keep it open, false if it is closed
+    /// or a node does not answer within the header timeout.
+    pub async fn ping(&mut self, t: &models::Timeouts) -> bool {
+        self.pings += 1;
+        let payload = self.pings.to_be_bytes().to_vec();
+        // every node answers and relays the answers of the nodes after it
+        let mut waiting = self.chain.headers.len();
+        let ws = &mut self.ws;
+        let pong = async {
+            ws.send(Message::Ping(payload.clone())).await?;
+            while waiting > 0 {
+                match ws.next().await {
+                    Some(Ok(Message::Pong(p))) if p == payload => waiting -= 1,
+                    Some(Ok(Message::Pong(_))) | Some(Ok(Message::Ping(_))) => {}
+                    Some(Ok(_)) => return Err(Error::ConnectionClosed),
+                    Some(Err(e)) => return Err(e),
+                    None => return Err(Error::ConnectionClosed),
                 }
-            } else {
-                warn!("failed to serialize header");
             }
-            infrs::close_ws_stream(ws_stream).await;
-            return Err(Error::ConnectionClosed);
+            Ok(())
+        };
+        matches!(timeout(t.header(), pong).await, Ok(Ok(_)))
+    }
+
+    pub async fn close(self, t: &models::Timeouts) {
+        infrs::close_ws_stream(self.ws, t).await;
+    }
+
+    /// Sends `cmd` to the last node and returns the tunnel.
+    pub async fn finish(
+        mut self,
+        cfg: &models::ClientConfigs,
+        cmd: models::Cmds,
+        target: &str,
+    ) -> Result<Tunnel, DialError> {
+        let mut tail = models::HeaderFrame::new(cmd, target);
+        tail.e2e = cfg.encrypt;
+        if set_tail(cfg, &mut self.chain, tail).is_none() {
+            warn!("can not create the tail header");
+            return Err(DialError::new(Status::GeneralFailure, ""));
+        }
+        let chain = self.chain;
+        let last = chain.headers.len() - 1;
+        let mut ws_stream = self.ws;
+        match handshake(
+            &mut ws_stream,
+            &chain,
+            last..last + 1,
+            cfg.encrypt,
+            &cfg.timeouts,
+        )
+        .await
+        {
+            Ok(sealed) => {
+                info!("chain: [{}]", chain.names.join(", "));
+                metrics::chain_length(chain.headers.len());
+                into_tunnel(ws_stream, chain, sealed)
+            }
+            Err(e) => {
+                infrs::close_ws_stream(ws_stream, &cfg.timeouts).await;
+                Err(e)
+            }
         }
-        return Ok(ws_stream);
     }
-    warn!("fail to connect proxy server");
-    return Err(Error::ConnectionClosed);
 }
 
-fn make_chain(
+// replaces the header of the last node of `chain` with `tail`
+fn set_tail(
     cfg: &models::ClientConfigs,
-    tail: models::HeaderFrame,
-) -> Option<models::ProxyChain> {
-    let secret = utils::generate_secret();
-    let pubkey = PublicKey::from(&secret).to_bytes();
+    chain: &mut models::ProxyChain,
+    mut tail: models::HeaderFrame,
+) -> Option<()> {
+    let last = chain.headers.len().checked_sub(1)?;
+    let pubkey = chain.headers[last].pubkey;
+    if !cfg.identity.is_empty() {
+        let id = utils::b64_to_secret(&cfg.identity)?;
+        let their_pubkey = utils::b64_to_pubkey(&chain.tail_pubkey)?;
+        tail.sign(&id, &their_pubkey, &pubkey);
+    }
+    let (enc_header, hash) = tail.encrypt(&pubkey, &chain.key, cfg.header, cfg.cipher, 0)?;
+    chain.acks[last] = tail.reply_hash(&hash);
+    chain.hashes[last] = hash;
+    chain.headers[last] = enc_header;
+    *chain.names.last_mut()? = tail.param;
+    Some(())
+}
+
+// handshakes every node but the last one of a chain from `profile`
+async fn warm_core(
+    cfg: &models::ClientConfigs,
+    health: &Health,
+    profile: &models::ChainProfile,
+) -> Result<WarmChain, DialError> {
+    let tail = models::HeaderFrame::new(models::Cmds::Connect, "");
+    let chain = match make_chain(cfg, health, profile, &[], tail) {
+        Some(c) => c,
+        None => {
+            warn!("can not create proxy chain");
+            return Err(DialError::new(Status::GeneralFailure, ""));
+        }
+    };
+    let mut ws_stream = connect_first(cfg, &chain).await?;
+    let hops = 0..chain.headers.len() - 1;
+    match handshake(&mut ws_stream, &chain, hops, false, &cfg.timeouts).await {
+        Ok(_) => Ok(WarmChain {
+            ws: ws_stream,
+            chain,
+            born: Instant::now(),
+            pings: 0,
+        }),
+        Err(e) => {
+            infrs::close_ws_stream(ws_stream, &cfg.timeouts).await;
+            Err(e)
+        }
+    }
+}
+
+// healthy nodes not in `excluded`, falls back to all healthy nodes
+fn candidates<'a>(
+    health: &Health,
+    pool: &'a [models::ServerInfo],
+    excluded: &[String],
+) -> Vec<&'a models::ServerInfo> {
+    let healthy = health.filter(pool);
+    let rest: Vec<&models::ServerInfo> = healthy
+        .iter()
+        .filter(|n| !excluded.contains(&n.name))
+        .copied()
+        .collect();
+    if rest.is_empty() {
+        return healthy;
+    }
+    rest
+}
+
+// from outlet to inlet, a fixed path is used even if some nodes are down
+fn pick_nodes<'a>(
+    health: &Health,
+    profile: &'a models::ChainProfile,
+    excluded: &[String],
+) -> Option<Vec<&'a models::ServerInfo>> {
+    if !profile.path.is_empty() {
+        return profile.path_nodes().ok();
+    }
+    let outlets = candidates(health, &profile.outlets, excluded);
+    let relays = candidates(health, &profile.relays, excluded);
+    let inlets = candidates(health, &profile.inlets, excluded);
+    // every server of a pool in use is disabled
+    let disabled = |pool: &[models::ServerInfo], left: &[&models::ServerInfo]| {
+        !pool.is_empty() && left.is_empty()
+    };
+    if disabled(&profile.outlets, &outlets)
+        || (profile.length > 0 && disabled(&profile.relays, &relays))
+        || disabled(&profile.inlets, &inlets)
+    {
+        return None;
+    }
 
     let mut nodes = vec![];
     let mut rng = rand::thread_rng();
-    if let Some(node) = cfg.outlets.choose(&mut rng) {
-        nodes.push(node);
+    if let Some(node) = outlets.choose(&mut rng) {
+        nodes.push(*node);
     }
-    for _ in 0..cfg.length {
-        if let Some(node) = cfg.relays.choose(&mut rng) {
-            nodes.push(node);
+    for _ in 0..profile.length {
+        if let Some(node) = relays.choose(&mut rng) {
+            nodes.push(*node);
         }
     }
-    if let Some(node) = cfg.inlets.choose(&mut rng) {
-        nodes.push(node);
+    if let Some(node) = inlets.choose(&mut rng) {
+        nodes.push(*node);
     }
+    Some(nodes)
+}
 
-    if nodes.len() < 1 {
+fn make_chain(
+    cfg: &models::ClientConfigs,
+    health: &Health,
+    profile: &models::ChainProfile,
+    excluded: &[String],
+    tail: models::HeaderFrame,
+) -> Option<models::ProxyChain> {
+    let secret = utils::generate_secret();
+    let pubkey = PublicKey::from(&secret).to_bytes();
+    let identity = if cfg.identity.is_empty() {
+        None
+    } else {
+        Some(utils::b64_to_secret(&cfg.identity)?)
+    };
+
+    let nodes = pick_nodes(health, profile, excluded)?;
+    if nodes.is_empty() {
         return None;
     }
 
     let mut headers = vec![];
     let mut hashes = vec![];
+    let mut acks = vec![];
     let mut names = vec![];
 
+    let mut tail_key = None;
+    let mut tail_pubkey = String::new();
     let mut prev: Option<&models::ServerInfo> = None;
     let mut frame = tail;
     let mut name = frame.param.to_string();
@@ -127,10 +579,22 @@ fn make_chain(
             }
             prev = Some(node);
 
+            if let Some(id) = identity.as_ref() {
+                frame.sign(id, &their_pubkey, &pubkey);
+            }
             let bytes = secret.diffie_hellman(&their_pubkey).to_bytes();
-            let key = base64::encode(&bytes);
-            if let Some((enc_header, hash)) = frame.encrypt(&pubkey, &key) {
+            let key = base64::encode(bytes);
+            if tail_key.is_none() {
+                tail_key = Some(key.to_string());
+                tail_pubkey = node.pubkey.to_string();
+            }
+            // counted from the outlet, the headers of the next hops are built already
+            let hop = u8::try_from(headers.len()).ok()?;
+            if let Some((enc_header, hash)) =
+                frame.encrypt(&pubkey, &key, cfg.header, cfg.cipher, hop)
+            {
                 headers.insert(0, enc_header);
+                acks.insert(0, frame.reply_hash(&hash));
                 hashes.insert(0, hash);
                 names.insert(0, name.to_string());
             } else {
@@ -148,26 +612,27 @@ fn make_chain(
             next: p.addr.to_string(),
             headers,
             hashes,
+            acks,
             names,
+            key: tail_key.unwrap_or_default(),
+            tail_pubkey,
         });
     }
-    return None;
+    None
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::comp::testing;
+    use async_std::task;
 
     #[test]
     fn make_chain_test() {
         let mut cfg = models::ClientConfigs::default();
 
         let target = "bing.com:443";
-        let tail = models::HeaderFrame {
-            cmd: models::Cmds::Connect,
-            param: target.to_string(),
-            padding: utils::rand_padding(),
-        };
+        let tail = models::HeaderFrame::new(models::Cmds::Connect, target);
 
         let (serv_pub, _) = utils::generate_x25519_keypair();
         cfg.relays = vec![models::ServerInfo {
@@ -176,14 +641,207 @@ mod tests {
             pubkey: serv_pub.to_string(),
         }];
 
-        if let Some(chain) = make_chain(&cfg, tail) {
+        let profile = cfg.profile("").unwrap();
+        if let Some(chain) = make_chain(&cfg, &Health::new(0), &profile, &[], tail) {
             println!("names: [{}]", chain.names.join(", "));
             println!("hashes len: {}", chain.hashes.len());
             println!("headers len: {}", chain.headers.len());
 
             assert_eq!(chain.headers.len(), cfg.length);
         } else {
-            assert!(false);
+            panic!("no chain");
         }
+
+        // a retry avoids the node that failed, unless nothing else is left
+        cfg.length = 0;
+        cfg.outlets = vec![cfg.relays[0].clone(), cfg.relays[0].clone()];
+        cfg.outlets[1].name = "dead".to_string();
+        let profile = cfg.profile("").unwrap();
+        let tail = models::HeaderFrame::new(models::Cmds::Connect, target);
+        let excluded = ["dead".to_string()];
+        for _ in 0..4 {
+            let chain = make_chain(&cfg, &Health::new(0), &profile, &excluded, tail.clone());
+            assert_eq!(chain.unwrap().names[0], "hello");
+        }
+        cfg.outlets.remove(0);
+        let profile = cfg.profile("").unwrap();
+        let chain = make_chain(&cfg, &Health::new(0), &profile, &excluded, tail);
+        assert_eq!(chain.unwrap().names[0], "dead");
+    }
+
+    #[test]
+    fn profile_test() {
+        let node = |name: &str| {
+            let (pubkey, _) = utils::generate_x25519_keypair();
+            models::ServerInfo {
+                name: name.to_string(),
+                addr: format!("ws://{}.local:3001", name),
+                pubkey,
+            }
+        };
+        let cfg = models::ClientConfigs {
+            length: 0,
+            inlets: vec![node("in")],
+            relays: vec![node("r1"), node("r2")],
+            outlets: vec![node("out")],
+            profiles: vec![
+                models::ChainProfile {
+                    name: "long".to_string(),
+                    length: 4,
+                    relays: vec![node("r3")],
+                    ..Default::default()
+                },
+                models::ChainProfile {
+                    name: "fixed".to_string(),
+                    path: vec!["in".to_string(), "r2".to_string(), "out".to_string()],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        assert!(cfg.check_profiles().is_ok());
+        assert!(cfg.check_identity().is_ok());
+        assert!(cfg.profile("none").is_none());
+        let health = Health::new(1);
+
+        let tail = || models::HeaderFrame::new(models::Cmds::Connect, "bing.com:443");
+        let chain = make_chain(&cfg, &health, &cfg.profile("").unwrap(), &[], tail()).unwrap();
+        assert_eq!(chain.names, vec!["in", "out", "bing.com:443"]);
+
+        let chain = make_chain(&cfg, &health, &cfg.profile("long").unwrap(), &[], tail()).unwrap();
+        assert_eq!(chain.names.len(), 7);
+        assert_eq!(chain.names[0], "in");
+        assert_eq!(chain.names[1..5], ["r3", "r3", "r3", "r3"]);
+
+        let chain = make_chain(&cfg, &health, &cfg.profile("fixed").unwrap(), &[], tail()).unwrap();
+        assert_eq!(chain.names, vec!["in", "r2", "out", "bing.com:443"]);
+
+        // failed nodes are skipped unless nothing else is left
+        let excluded = vec!["r1".to_string(), "out".to_string()];
+        let mut default = cfg.profile("").unwrap();
+        default.length = 3;
+        let chain = make_chain(&cfg, &health, &default, &excluded, tail()).unwrap();
+        assert_eq!(
+            chain.names,
+            vec!["in", "r2", "r2", "r2", "out", "bing.com:443"]
+        );
+
+        let mut bad = cfg.clone();
+        bad.identity = base64::encode([1u8; 16]);
+        assert!(bad.check_identity().is_err());
+        bad.identity = base64::encode(utils::generate_secret().to_bytes());
+        assert!(bad.check_identity().is_ok());
+
+        let mut bad = cfg.clone();
+        bad.profiles[1].path.push("nowhere".to_string());
+        assert!(bad.check_profiles().is_err());
+        let mut bad = cfg;
+        bad.routes = vec![models::RouteRule {
+            action: models::RouteAction::Proxy,
+            profile: "short".to_string(),
+            domains: vec![],
+            cidrs: vec![],
+            ports: vec![],
+            protocols: vec![],
+            users: vec![],
+        }];
+        assert!(bad.check_profiles().is_err());
+    }
+
+    #[test]
+    fn header_format_tests() {
+        task::block_on(async {
+            let target = testing::echo_server().await;
+            let (_, _, outlet) = testing::start_relay("outlet").await;
+            let cfg = models::ClientConfigs {
+                length: 0,
+                outlets: vec![outlet],
+                ..Default::default()
+            };
+            // the server tells all of them apart
+            for header in [
+                models::HeaderFormat::Json,
+                models::HeaderFormat::Binary,
+                models::HeaderFormat::Legacy,
+            ] {
+                let dialer = ChainDialer::new(models::ClientConfigs {
+                    header,
+                    ..cfg.clone()
+                });
+                testing::echo_through(&dialer, &target).await;
+            }
+        });
+    }
+
+    #[test]
+    fn sealed_tests() {
+        task::block_on(async {
+            let target = testing::echo_server().await;
+            let (_, _, relay) = testing::start_relay("relay").await;
+            let (_, _, outlet) = testing::start_relay("outlet").await;
+            let cfg = models::ClientConfigs {
+                length: 1,
+                relays: vec![relay],
+                outlets: vec![outlet],
+                encrypt: true,
+                ..Default::default()
+            };
+            for cipher in [models::Cipher::Aes256Gcm, models::Cipher::ChaCha20Poly1305] {
+                let dialer = ChainDialer::new(models::ClientConfigs {
+                    cipher,
+                    ..cfg.clone()
+                });
+                testing::echo_through(&dialer, &target).await;
+                let tunnel = dialer.dial(models::Cmds::Connect, &target).await;
+                assert!(tunnel.unwrap().is_sealed());
+            }
+        });
+    }
+
+    #[test]
+    fn status_tests() {
+        task::block_on(async {
+            let target = testing::echo_server().await;
+            let (_, _, relay) = testing::start_relay("relay").await;
+            let (_, _, outlet) = testing::start_relay_with("outlet", |cfg| {
+                cfg.block_private = false;
+                cfg.rules = vec![models::AclRule {
+                    action: models::AclAction::Deny,
+                    cmds: vec![models::Cmds::Connect],
+                    cidrs: vec![],
+                    domains: vec![],
+                    ports: vec!["1".to_string()],
+                }];
+            })
+            .await;
+
+            // the outlet refuses the target
+            let dialer = ChainDialer::new(models::ClientConfigs {
+                length: 0,
+                outlets: vec![outlet.clone()],
+                ..Default::default()
+            });
+            assert!(dialer.ping(&outlet).await.is_ok());
+            let e = dialer.connect("127.0.0.1:1").await.err().unwrap();
+            assert_eq!(e.status, Status::Denied);
+            assert_eq!(e.hop, "outlet");
+
+            // the outlet is down, the relay reports it
+            let dead = models::ServerInfo {
+                name: "dead".to_string(),
+                addr: format!("ws://{}", testing::closed_addr().await),
+                ..outlet
+            };
+            let dialer = ChainDialer::new(models::ClientConfigs {
+                length: 1,
+                relays: vec![relay],
+                outlets: vec![dead.clone()],
+                ..Default::default()
+            });
+            assert!(dialer.ping(&dead).await.is_err());
+            let e = dialer.connect(&target).await.err().unwrap();
+            assert_eq!(e.status, Status::NextHopUnreachable);
+            assert_eq!(e.hop, "dead");
+        });
     }
 }
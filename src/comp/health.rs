@@ -0,0 +1,220 @@
+use crate::{
+    comm::models::{ClientConfigs, ServerInfo},
+    comp::dialer::ChainDialer,
+};
+use async_std::task;
+use futures::future::join_all;
+use log::*;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+/// Result of the latest probes of a server.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct NodeHealth {
+    pub name: String,
+    pub addr: String,
+    // milliseconds of the last successful handshake
+    pub latency: Option<u64>,
+    // consecutive failed probes
+    pub failures: u32,
+    // unix timestamp of the last probe
+    pub checked: i64,
+    // excluded from chains by hand
+    pub disabled: bool,
+}
+
+/// Health of every server the client knows, keyed by address.
+///
+/// A server is excluded from random chains after `max_failures` consecutive
+/// failed probes and comes back after one successful probe.
+pub struct Health {
+    nodes: RwLock<HashMap<String, NodeHealth>>,
+    max_failures: u32,
+}
+
+impl Health {
+    pub fn new(max_failures: u32) -> Health {
+        Health {
+            nodes: RwLock::new(HashMap::new()),
+            max_failures,
+        }
+    }
+
+    /// Servers that were never probed are healthy.
+    pub fn is_healthy(&self, node: &ServerInfo) -> bool {
+        if self.max_failures < 1 {
+            return true;
+        }
+        match self.nodes.read().unwrap().get(&node.addr) {
+            Some(h) => h.failures < self.max_failures,
+            None => true,
+        }
+    }
+
+    pub fn is_disabled(&self, node: &ServerInfo) -> bool {
+        match self.nodes.read().unwrap().get(&node.addr) {
+            Some(h) => h.disabled,
+            None => false,
+        }
+    }
+
+    /// A disabled server is never used in random chains, even if it is the
+    /// last one of its pool.
+    pub fn set_disabled(&self, node: &ServerInfo, disabled: bool) {
+        let mut nodes = self.nodes.write().unwrap();
+        let h = nodes.entry(node.addr.to_string()).or_default();
+        h.name = node.name.to_string();
+        h.addr = node.addr.to_string();
+        h.disabled = disabled;
+        info!(
+            "[{}] {}",
+            node.name,
+            if disabled { "disabled" } else { "enabled" }
+        );
+    }
+
+    /// Healthy servers of `pool`, or every enabled one if none of them is.
+    pub fn filter<'a>(&self, pool: &'a [ServerInfo]) -> Vec<&'a ServerInfo> {
+        let enabled: Vec<&ServerInfo> = pool.iter().filter(|n| !self.is_disabled(n)).collect();
+        let healthy: Vec<&ServerInfo> = enabled
+            .iter()
+            .filter(|n| self.is_healthy(n))
+            .copied()
+            .collect();
+        if healthy.is_empty() {
+            return enabled;
+        }
+        healthy
+    }
+
+    pub fn record(&self, node: &ServerInfo, latency: Option<Duration>) {
+        let mut nodes = self.nodes.write().unwrap();
+        let h = nodes.entry(node.addr.to_string()).or_default();
+        h.name = node.name.to_string();
+        h.addr = node.addr.to_string();
+        h.checked = chrono::Utc::now().timestamp();
+        match latency {
+            Some(d) => {
+                if h.failures >= self.max_failures && self.max_failures > 0 {
+                    info!("[{}] is back", node.name);
+                }
+                h.latency = Some(d.as_millis() as u64);
+                h.failures = 0;
+            }
+            None => {
+                h.failures += 1;
+                if h.failures == self.max_failures {
+                    warn!("[{}] is down, exclude it from chains", node.name);
+                }
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<NodeHealth> {
+        let mut r: Vec<NodeHealth> = self.nodes.read().unwrap().values().cloned().collect();
+        r.sort_by(|a, b| a.name.cmp(&b.name));
+        r
+    }
+}
+
+/// Every server in the configs, once per address.
+pub fn all_nodes(cfg: &ClientConfigs) -> Vec<ServerInfo> {
+    let mut nodes: Vec<ServerInfo> = vec![];
+    let pools = [&cfg.inlets, &cfg.relays, &cfg.outlets];
+    let profiles = cfg
+        .profiles
+        .iter()
+        .flat_map(|p| [&p.inlets, &p.relays, &p.outlets]);
+    for node in pools.iter().copied().chain(profiles).flatten() {
+        if !nodes.iter().any(|n| n.addr == node.addr) {
+            nodes.push(node.clone());
+        }
+    }
+    nodes
+}
+
+/// Pings every server once, concurrently.
+pub async fn probe(dialer: &ChainDialer) {
+    let nodes = all_nodes(dialer.configs());
+    let pings = nodes.iter().map(|node| async move {
+        let start = Instant::now();
+        let latency = match dialer.ping(node).await {
+            Ok(_) => Some(start.elapsed()),
+            Err(e) => {
+                debug!("ping [{}] failed: {}", node.name, e);
+                None
+            }
+        };
+        dialer.health().record(node, latency);
+    });
+    join_all(pings).await;
+}
+
+/// Probes all servers of the dialer returned by `current` every `interval` forever.
+pub async fn run<F>(current: F, interval: Duration)
+where
+    F: Fn() -> ChainDialer,
+{
+    loop {
+        probe(&current()).await;
+        task::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(name: &str) -> ServerInfo {
+        ServerInfo {
+            name: name.to_string(),
+            addr: format!("ws://{}.local:3001", name),
+            pubkey: "".to_string(),
+        }
+    }
+
+    #[test]
+    fn health_tests() {
+        let health = Health::new(2);
+        let pool = vec![node("a"), node("b")];
+        assert_eq!(health.filter(&pool).len(), 2);
+
+        health.record(&pool[0], None);
+        assert!(health.is_healthy(&pool[0]));
+        health.record(&pool[0], None);
+        assert!(!health.is_healthy(&pool[0]));
+        let healthy = health.filter(&pool);
+        assert_eq!(healthy.len(), 1);
+        assert_eq!(healthy[0].name, "b");
+
+        // never leave a pool empty
+        health.record(&pool[1], None);
+        health.record(&pool[1], None);
+        assert_eq!(health.filter(&pool).len(), 2);
+
+        health.record(&pool[0], Some(Duration::from_millis(20)));
+        assert!(health.is_healthy(&pool[0]));
+        let snapshot = health.snapshot();
+        assert_eq!(snapshot[0].latency, Some(20));
+        assert_eq!(snapshot[0].failures, 0);
+        assert_eq!(snapshot[1].failures, 2);
+
+        let disabled = Health::new(0);
+        disabled.record(&pool[0], None);
+        assert!(disabled.is_healthy(&pool[0]));
+
+        // disabled servers are left out even if nothing else is left
+        health.set_disabled(&pool[0], true);
+        let left = health.filter(&pool);
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].name, "b");
+        health.set_disabled(&pool[1], true);
+        assert!(health.filter(&pool).is_empty());
+        health.set_disabled(&pool[0], false);
+        assert_eq!(health.filter(&pool).len(), 1);
+    }
+}
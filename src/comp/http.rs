@@ -1,39 +1,692 @@
-use std::io::{Error, ErrorKind};
+use std::{
+    io::{self, Error, ErrorKind},
+    time::Duration,
+};
 
-use crate::comm::utils;
+use crate::comm::{cons::BUFF_LEN, models::ProxyUser, utils};
+use async_std::future::timeout;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-pub fn parse_header(buff: &[u8]) -> std::io::Result<String> {
-    let header = String::from_utf8_lossy(&buff);
-    if header.contains("\r\n\r\n") {
-        let ps: Vec<&str> = header.split([' ', '\r', '\n']).collect();
-        if ps.len() > 1 {
-            let url = ps[1];
-            match utils::get_addr(url) {
-                Ok(addr) => return Ok(addr),
-                Err(_) => return Ok(url.to_string()),
+// longer heads are rejected
+const MAX_HEAD_LEN: usize = 64 * 1024;
+
+// headers of one connection, never forwarded, nor the ones named in "Connection"
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "proxy-connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-authenticate",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Whether `prefix`, the first bytes of a connection, starts a request line:
+/// a method token followed by a space. Any method is proxied, not only the
+/// standard ones.
+pub fn is_method(prefix: &[u8]) -> bool {
+    let is_tchar = |b: &u8| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(b);
+    let token = prefix.split(|b| *b == b' ').next().unwrap_or_default();
+    !token.is_empty() && token.iter().all(is_tchar)
+}
+
+/// Where the body after a head ends.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Body {
+    Empty,
+    Length(u64),
+    // copied with its chunk framing and trailers
+    Chunked,
+    UntilClose,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    // a message with more than one or an invalid Content-Length is rejected,
+    // two hops could read its body differently
+    fn parse<'a>(lines: impl Iterator<Item = &'a str>) -> io::Result<Headers> {
+        let mut headers = vec![];
+        for line in lines.filter(|l| !l.is_empty()) {
+            let (name, value) = line.split_once(':').ok_or_else(|| invalid("header"))?;
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+        let headers = Headers(headers);
+        let lengths: Vec<&str> = headers.all("content-length").collect();
+        if lengths.len() > 1 {
+            return Err(invalid("duplicate content-length"));
+        }
+        let is_digits = |v: &str| !v.is_empty() && v.bytes().all(|b| b.is_ascii_digit());
+        if let Some(v) = lengths.first() {
+            if !is_digits(v) || v.parse::<u64>().is_err() {
+                return Err(invalid("content-length"));
+            }
+        }
+        Ok(headers)
+    }
+
+    fn all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // whether the comma separated values of `name` contain `token`
+    fn has_token(&self, name: &str, token: &str) -> bool {
+        self.0
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .flat_map(|(_, v)| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    // whether chunked is the last transfer coding
+    fn is_chunked(&self) -> bool {
+        let last = self
+            .all("transfer-encoding")
+            .flat_map(|v| v.split(','))
+            .last();
+        last.is_some_and(|t| t.trim().eq_ignore_ascii_case("chunked"))
+    }
+
+    // Transfer-Encoding overrides Content-Length, RFC 7230 3.3.3
+    fn body(&self) -> Option<Body> {
+        if self.is_chunked() {
+            return Some(Body::Chunked);
+        }
+        if self.get("transfer-encoding").is_some() {
+            return Some(Body::UntilClose);
+        }
+        let len = self.get("content-length")?.parse().ok()?;
+        Some(Body::Length(len))
+    }
+
+    // HTTP/1.1 keeps the connection unless told to close, HTTP/1.0 closes unless told to keep it
+    fn keep_alive(&self, version: &str) -> bool {
+        let has = |token| {
+            self.has_token("connection", token) || self.has_token("proxy-connection", token)
+        };
+        match version {
+            "HTTP/1.1" => !has("close"),
+            _ => has("keep-alive"),
+        }
+    }
+
+    // the headers without the hop-by-hop ones, nor Content-Length next to
+    // Transfer-Encoding, nor the ones in `skip`, each followed by CRLF
+    fn end_to_end(&self, skip: &[&str]) -> String {
+        let has_te = self.get("transfer-encoding").is_some();
+        let named: Vec<String> = self
+            .0
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case("connection"))
+            .flat_map(|(_, v)| v.split(','))
+            .map(|t| t.trim().to_lowercase())
+            .collect();
+        let mut r = String::new();
+        for (name, value) in self.0.iter() {
+            let lower = name.to_lowercase();
+            if HOP_BY_HOP.contains(&lower.as_str())
+                || named.contains(&lower)
+                || skip.contains(&lower.as_str())
+                || (has_te && lower == "content-length")
+            {
+                continue;
             }
+            r.push_str(&format!("{name}: {value}\r\n"));
+        }
+        r
+    }
+}
+
+fn invalid(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("invalid http {what}"))
+}
+
+// the request or status line and the header lines of a head
+fn split_head(buff: &[u8]) -> io::Result<(&str, std::str::Lines<'_>)> {
+    let text = std::str::from_utf8(buff).map_err(|_| invalid("head"))?;
+    let mut lines = text.lines();
+    let first = lines.next().ok_or_else(|| invalid("head"))?;
+    Ok((first, lines))
+}
+
+/// Request line and headers of a proxy request.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    // absolute-form, authority-form of CONNECT or origin-form
+    pub target: String,
+    pub version: String,
+    pub headers: Headers,
+}
+
+impl Request {
+    pub fn parse(buff: &[u8]) -> io::Result<Request> {
+        let (first, lines) = split_head(buff)?;
+        let mut parts = first.split_whitespace();
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(m), Some(t), Some(v)) if v.starts_with("HTTP/1.") => (m, t, v),
+            _ => return Err(invalid("request line")),
+        };
+        let headers = Headers::parse(lines)?;
+        // the end of a request body has to be known
+        if headers.get("transfer-encoding").is_some() && !headers.is_chunked() {
+            return Err(invalid("transfer-encoding"));
+        }
+        Ok(Request {
+            method: method.to_uppercase(),
+            target: target.to_string(),
+            version: version.to_string(),
+            headers,
+        })
+    }
+
+    pub fn is_connect(&self) -> bool {
+        self.method == "CONNECT"
+    }
+
+    /// "host:port" to connect to.
+    pub fn addr(&self) -> io::Result<String> {
+        if self.is_connect() {
+            return Ok(with_port(&self.target, 443));
+        }
+        if self.target.contains("://") {
+            return utils::get_addr(&self.target);
+        }
+        match self.headers.get("host") {
+            Some(host) => Ok(with_port(host, 80)),
+            None => Err(invalid("target")),
+        }
+    }
+
+    /// Whether the client keeps the connection for more requests.
+    pub fn keep_alive(&self) -> bool {
+        self.headers.keep_alive(&self.version)
+    }
+
+    pub fn body(&self) -> Body {
+        self.headers.body().unwrap_or(Body::Empty)
+    }
+
+    /// The name of the user in "Proxy-Authorization: Basic", None if it is
+    /// missing or does not match one of `users`.
+    pub fn user(&self, users: &[ProxyUser]) -> Option<String> {
+        let auth = self.headers.get("proxy-authorization")?;
+        let (scheme, token) = auth.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = base64::decode(token.trim()).ok()?;
+        let (name, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+        let user = users
+            .iter()
+            .find(|u| u.name == name && u.password == password)?;
+        Some(user.name.to_string())
+    }
+
+    /// The head sent to the origin: the target in origin-form, without
+    /// hop-by-hop headers and with a Host header, which is the authority of an
+    /// absolute-form target (RFC 7230 5.4).
+    pub fn to_origin(&self) -> Vec<u8> {
+        let (authority, path) = match self.target.split_once("://") {
+            Some((_, rest)) => match rest.find(['/', '?']) {
+                Some(i) => (&rest[..i], &rest[i..]),
+                None => (rest, ""),
+            },
+            None => ("", self.target.as_str()),
+        };
+        let path = match path {
+            "" => "/".to_string(),
+            p if p.starts_with('?') => format!("/{p}"),
+            p => p.to_string(),
+        };
+        let mut head = format!("{} {} {}\r\n", self.method, path, self.version);
+        // without the userinfo
+        let authority = authority.rsplit('@').next().unwrap_or_default();
+        let mut skip = vec![];
+        if !authority.is_empty() {
+            head.push_str(&format!("Host: {authority}\r\n"));
+            skip.push("host");
         }
+        head.push_str(&self.headers.end_to_end(&skip));
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+}
+
+// "host" without a port gets `port`
+fn with_port(host: &str, port: u16) -> String {
+    match utils::split_host_port(host) {
+        Some(_) => host.to_string(),
+        None => format!("{host}:{port}"),
+    }
+}
+
+/// Status line and headers of a response from the origin.
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+impl Response {
+    pub fn parse(buff: &[u8]) -> io::Result<Response> {
+        let (first, lines) = split_head(buff)?;
+        let mut parts = first.splitn(3, ' ');
+        let (version, status) = match (parts.next(), parts.next()) {
+            (Some(v), Some(s)) if v.starts_with("HTTP/1.") => (v, s),
+            _ => return Err(invalid("status line")),
+        };
+        Ok(Response {
+            version: version.to_string(),
+            status: status.parse().map_err(|_| invalid("status"))?,
+            reason: parts.next().unwrap_or_default().to_string(),
+            headers: Headers::parse(lines)?,
+        })
+    }
+
+    /// An interim response, the final one follows.
+    pub fn is_interim(&self) -> bool {
+        (100..200).contains(&self.status)
+    }
+
+    /// Whether the origin keeps the connection for more requests.
+    pub fn keep_alive(&self) -> bool {
+        self.headers.keep_alive(&self.version)
+    }
+
+    /// The body of the response to a `method` request.
+    pub fn body(&self, method: &str) -> Body {
+        if method == "HEAD" || self.is_interim() || self.status == 204 || self.status == 304 {
+            return Body::Empty;
+        }
+        self.headers.body().unwrap_or(Body::UntilClose)
+    }
+
+    /// The head sent to the client, without hop-by-hop headers.
+    pub fn to_client(&self, keep_alive: bool) -> Vec<u8> {
+        let mut head = format!("{} {} {}\r\n", self.version, self.status, self.reason);
+        head.push_str(&self.headers.end_to_end(&[]));
+        if !self.is_interim() {
+            let conn = if keep_alive { "keep-alive" } else { "close" };
+            head.push_str(&format!("Connection: {conn}\r\n"));
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+}
+
+/// A response of the proxy itself, `status` is like "403 Forbidden".
+pub fn response(status: &str, keep_alive: bool) -> Vec<u8> {
+    let conn = if keep_alive { "keep-alive" } else { "close" };
+    let mut r = format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: {conn}\r\n");
+    if status.starts_with("407") {
+        r.push_str("Proxy-Authenticate: Basic realm=\"thomas\"\r\n");
+    }
+    r.push_str("\r\n");
+    r.into_bytes()
+}
+
+fn find(buff: &[u8], pat: &[u8]) -> Option<usize> {
+    buff.windows(pat.len()).position(|w| w == pat)
+}
+
+async fn write_all<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    data: &[u8],
+    idle: Duration,
+) -> io::Result<()> {
+    match timeout(idle, w.write_all(data)).await {
+        Ok(r) => r,
+        Err(_) => Err(Error::new(ErrorKind::TimedOut, "write timeout")),
+    }
+}
+
+/// Reads heads and bodies off a connection. Bytes read ahead of what is
+/// asked for are kept for the next read, so a head may span reads and
+/// requests may be pipelined.
+pub struct Reader<S> {
+    inner: S,
+    buff: Vec<u8>,
+    // for every read and write
+    idle: Duration,
+}
+
+impl<S: AsyncRead + Unpin> Reader<S> {
+    pub fn new(inner: S, idle: Duration) -> Reader<S> {
+        Reader {
+            inner,
+            buff: vec![],
+            idle,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// The connection and the bytes read ahead.
+    pub fn into_parts(self) -> (S, Vec<u8>) {
+        (self.inner, self.buff)
+    }
+
+    // 0 at the end of the stream
+    async fn fill(&mut self) -> io::Result<usize> {
+        let mut chunk = vec![0u8; BUFF_LEN];
+        let n = match timeout(self.idle, self.inner.read(&mut chunk)).await {
+            Ok(r) => r?,
+            Err(_) => return Err(Error::new(ErrorKind::TimedOut, "read timeout")),
+        };
+        self.buff.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+
+    /// The next head including the blank line, None if the stream ends
+    /// before it starts.
+    pub async fn read_head(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            // blank lines between messages are ignored
+            let blank = self
+                .buff
+                .iter()
+                .take_while(|b| **b == b'\r' || **b == b'\n');
+            let n = blank.count();
+            self.buff.drain(..n);
+            if let Some(end) = find(&self.buff, b"\r\n\r\n") {
+                return Ok(Some(self.buff.drain(..end + 4).collect()));
+            }
+            if self.buff.len() > MAX_HEAD_LEN {
+                return Err(invalid("head length"));
+            }
+            if self.fill().await? == 0 {
+                if self.buff.is_empty() {
+                    return Ok(None);
+                }
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    // a line with its line break
+    async fn read_line(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(end) = find(&self.buff, b"\n") {
+                return Ok(self.buff.drain(..end + 1).collect());
+            }
+            if self.buff.len() > MAX_HEAD_LEN {
+                return Err(invalid("line length"));
+            }
+            if self.fill().await? == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    // up to `max` bytes, empty at the end of the stream
+    async fn take(&mut self, max: usize) -> io::Result<Vec<u8>> {
+        if self.buff.is_empty() && self.fill().await? == 0 {
+            return Ok(vec![]);
+        }
+        let n = std::cmp::min(max, self.buff.len());
+        Ok(self.buff.drain(..n).collect())
+    }
+
+    async fn copy_n<W: AsyncWrite + Unpin>(&mut self, mut n: u64, w: &mut W) -> io::Result<()> {
+        while n > 0 {
+            let max = std::cmp::min(n, BUFF_LEN as u64) as usize;
+            let data = self.take(max).await?;
+            if data.is_empty() {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            write_all(w, &data, self.idle).await?;
+            n -= data.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Copies a body to `w` as it is.
+    pub async fn copy_body<W: AsyncWrite + Unpin>(
+        &mut self,
+        body: Body,
+        w: &mut W,
+    ) -> io::Result<()> {
+        match body {
+            Body::Empty => {}
+            Body::Length(n) => self.copy_n(n, w).await?,
+            Body::UntilClose => loop {
+                let data = self.take(BUFF_LEN).await?;
+                if data.is_empty() {
+                    break;
+                }
+                write_all(w, &data, self.idle).await?;
+            },
+            Body::Chunked => loop {
+                // size[;ext] CRLF data CRLF, the last chunk has size 0 and trailers
+                let line = self.read_line().await?;
+                write_all(w, &line, self.idle).await?;
+                let text = String::from_utf8_lossy(&line);
+                let size = text.split(';').next().unwrap_or_default().trim();
+                let size = u64::from_str_radix(size, 16).map_err(|_| invalid("chunk size"))?;
+                if size > 0 {
+                    self.copy_n(size + 2, w).await?;
+                    continue;
+                }
+                loop {
+                    let line = self.read_line().await?;
+                    write_all(w, &line, self.idle).await?;
+                    if line == b"\r\n" || line == b"\n" {
+                        break;
+                    }
+                }
+                break;
+            },
+        }
+        timeout(self.idle, w.flush()).await.unwrap_or(Ok(()))
     }
-    return Err(Error::new(ErrorKind::InvalidData, "parse header failed!"));
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use async_std::task;
+    use futures::io::Cursor;
+
+    fn request(head: &str) -> Request {
+        Request::parse(head.as_bytes()).unwrap()
+    }
 
     #[test]
     fn parse_header_test() {
-        parse_header_wrapper("get http://bing.com/ \r\n\r\n", "bing.com:80");
-        parse_header_wrapper("get https://bing.com/ \r\n\r\n", "bing.com:443");
-        parse_header_wrapper("get http://bing.com:123/ \r\n\r\n", "bing.com:123");
-        parse_header_wrapper("get https://bing.com:123/ \r\n\r\n", "bing.com:123");
-        parse_header_wrapper("get bing.com:123 \r\n\r\n", "bing.com:123");
-        parse_header_wrapper("get bing.com \r\n\r\n", "bing.com");
+        parse_header_wrapper("get http://bing.com/ HTTP/1.1\r\n\r\n", "bing.com:80");
+        parse_header_wrapper("get https://bing.com/ HTTP/1.1\r\n\r\n", "bing.com:443");
+        parse_header_wrapper("get http://bing.com:123/ HTTP/1.1\r\n\r\n", "bing.com:123");
+        parse_header_wrapper("get https://bing.com:123/ HTTP/1.1\r\n\r\n", "bing.com:123");
+        parse_header_wrapper("connect bing.com:123 HTTP/1.1\r\n\r\n", "bing.com:123");
+        parse_header_wrapper("connect bing.com HTTP/1.1\r\n\r\n", "bing.com:443");
+        parse_header_wrapper("get / HTTP/1.1\r\nHost: bing.com\r\n\r\n", "bing.com:80");
+        parse_header_wrapper("get / HTTP/1.1\r\nhost: [::1]:8080\r\n\r\n", "[::1]:8080");
+        assert!(Request::parse(b"get / HTTP/1.1\r\n\r\n")
+            .unwrap()
+            .addr()
+            .is_err());
+        assert!(Request::parse(b"\x16\x03\x01\x02\x00\r\n\r\n").is_err());
     }
 
     fn parse_header_wrapper(header: &str, exp: &str) {
-        let addr = parse_header(header.as_bytes()).unwrap();
+        let addr = request(header).addr().unwrap();
         println!("addr: [{}] exp: [{}]", addr, exp);
         assert_eq!(addr, exp);
     }
+
+    #[test]
+    fn method_tests() {
+        let methods = [
+            "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+        ];
+        for m in methods.iter() {
+            assert!(is_method(format!("{m} / HTTP/1.1").as_bytes()));
+            assert_eq!(request(&format!("{m} / HTTP/1.1\r\n\r\n")).method, *m);
+        }
+        assert!(is_method(b"PROPFIND /"));
+        // a request line split by the peek
+        assert!(is_method(b"OPTI"));
+        assert!(!is_method(b"\x16\x03\x01\x02\x00"));
+        assert!(!is_method(b" / HTTP/1.1"));
+        assert!(!is_method(b"GE\x00"));
+    }
+
+    #[test]
+    fn origin_form_tests() {
+        let req = request(
+            "GET http://bing.com:8080?q=1 HTTP/1.1\r\n\
+             Proxy-Connection: keep-alive\r\n\
+             Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n\
+             Connection: x-trace\r\n\
+             X-Trace: 1\r\n\
+             Accept: */*\r\n\r\n",
+        );
+        assert!(req.keep_alive());
+        assert_eq!(
+            String::from_utf8(req.to_origin()).unwrap(),
+            "GET /?q=1 HTTP/1.1\r\nHost: bing.com:8080\r\nAccept: */*\r\n\r\n"
+        );
+
+        let req = request("POST http://bing.com/a/b HTTP/1.0\r\nHost: bing.com\r\n\r\n");
+        assert!(!req.keep_alive());
+        assert_eq!(
+            String::from_utf8(req.to_origin()).unwrap(),
+            "POST /a/b HTTP/1.0\r\nHost: bing.com\r\n\r\n"
+        );
+        let req = request("GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(!req.keep_alive());
+
+        // the origin gets the host of the target, not the one the client claims
+        let req = request("GET http://a@bing.com/ HTTP/1.1\r\nHost: intranet\r\n\r\n");
+        assert_eq!(req.addr().unwrap(), "bing.com:80");
+        assert_eq!(
+            String::from_utf8(req.to_origin()).unwrap(),
+            "GET / HTTP/1.1\r\nHost: bing.com\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn smuggling_tests() {
+        // chunked wins and Content-Length is not forwarded next to it
+        let req = request(
+            "POST http://bing.com/ HTTP/1.1\r\n\
+             Content-Length: 4\r\n\
+             Transfer-Encoding: chunked\r\n\r\n",
+        );
+        assert_eq!(req.body(), Body::Chunked);
+        assert_eq!(
+            String::from_utf8(req.to_origin()).unwrap(),
+            "POST / HTTP/1.1\r\nHost: bing.com\r\nTransfer-Encoding: chunked\r\n\r\n"
+        );
+        let resp = Response::parse(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Length: 4\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(resp.body("GET"), Body::Chunked);
+        assert!(!String::from_utf8(resp.to_client(true))
+            .unwrap()
+            .contains("Content-Length"));
+
+        // lengths two hops could read differently
+        for head in [
+            "POST / HTTP/1.1\r\nContent-Length: 4\r\nContent-Length: 5\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 4\r\ncontent-length: 4\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 4, 4\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: +4\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n",
+        ] {
+            assert!(Request::parse(head.as_bytes()).is_err(), "{}", head);
+        }
+        let resp = b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n";
+        assert!(Response::parse(resp).is_err());
+        let resp = Response::parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\n");
+        assert_eq!(resp.unwrap().body("GET"), Body::UntilClose);
+    }
+
+    #[test]
+    fn auth_tests() {
+        let users = vec![ProxyUser {
+            name: "alice".to_string(),
+            password: "secret".to_string(),
+        }];
+        let auth = |value: &str| {
+            request(&format!(
+                "GET / HTTP/1.1\r\nProxy-Authorization: {value}\r\n\r\n"
+            ))
+            .user(&users)
+        };
+        let token = base64::encode("alice:secret");
+        assert_eq!(auth(&format!("Basic {token}")), Some("alice".to_string()));
+        assert_eq!(auth(&format!("basic {token}")), Some("alice".to_string()));
+        assert_eq!(auth(&format!("Basic {}", base64::encode("alice:x"))), None);
+        assert_eq!(auth(&format!("Bearer {token}")), None);
+        assert_eq!(request("GET / HTTP/1.1\r\n\r\n").user(&users), None);
+    }
+
+    #[test]
+    fn body_tests() {
+        let req = request("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n");
+        assert_eq!(req.body(), Body::Length(5));
+        let req = request("POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n");
+        assert_eq!(req.body(), Body::Chunked);
+        assert_eq!(request("GET / HTTP/1.1\r\n\r\n").body(), Body::Empty);
+
+        let resp = Response::parse(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n").unwrap();
+        assert_eq!(resp.body("GET"), Body::Length(3));
+        assert_eq!(resp.body("HEAD"), Body::Empty);
+        let resp = Response::parse(b"HTTP/1.0 200 OK\r\n\r\n").unwrap();
+        assert_eq!(resp.body("GET"), Body::UntilClose);
+        assert!(!resp.keep_alive());
+        let resp = Response::parse(b"HTTP/1.1 304 Not Modified\r\n\r\n").unwrap();
+        assert_eq!(resp.body("GET"), Body::Empty);
+        assert!(Response::parse(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .unwrap()
+            .is_interim());
+    }
+
+    #[test]
+    fn reader_tests() {
+        task::block_on(async {
+            let stream = b"\r\nPOST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                           5;x=1\r\nhello\r\n0\r\nX-Sum: 1\r\n\r\n\
+                           GET / HTTP/1.1\r\n\r\nrest"
+                .to_vec();
+            let mut reader = Reader::new(Cursor::new(stream), Duration::from_secs(1));
+            let head = reader.read_head().await.unwrap().unwrap();
+            let req = Request::parse(&head).unwrap();
+            let mut body = vec![];
+            reader.copy_body(req.body(), &mut body).await.unwrap();
+            assert_eq!(body, b"5;x=1\r\nhello\r\n0\r\nX-Sum: 1\r\n\r\n");
+
+            let head = reader.read_head().await.unwrap().unwrap();
+            assert_eq!(head, b"GET / HTTP/1.1\r\n\r\n");
+            let (_, ahead) = reader.into_parts();
+            assert_eq!(ahead, b"rest");
+
+            let mut reader = Reader::new(Cursor::new(b"".to_vec()), Duration::from_secs(1));
+            assert!(reader.read_head().await.unwrap().is_none());
+            let mut reader = Reader::new(Cursor::new(b"GET /".to_vec()), Duration::from_secs(1));
+            assert!(reader.read_head().await.is_err());
+            let mut reader = Reader::new(Cursor::new(b"abc".to_vec()), Duration::from_secs(1));
+            let mut body = vec![];
+            assert!(reader.copy_body(Body::Length(5), &mut body).await.is_err());
+        });
+    }
 }
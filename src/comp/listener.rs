@@ -1,45 +1,455 @@
 use crate::{
-    comm::{infrs, models, utils},
-    comp::{dialer, http, socks5},
+    comm::{
+        infrs::{self, ByteStream, ChainStream, Rewind},
+        metrics, models,
+        mux::MuxStream,
+        router::{Request, Route, Router},
+        tls,
+        tunnel::{Tunnel, TunnelStats},
+        utils,
+    },
+    comp::{
+        admin,
+        conns::{Conn, Conns, Trackable},
+        dialer::ChainDialer,
+        health, http,
+        mux::MuxPool,
+        pool::{self, ChainPool},
+        socks5,
+    },
 };
+use async_std::channel;
 use async_std::{
+    future::timeout,
     net::{TcpListener, TcpStream, UdpSocket},
     task,
 };
 use async_tungstenite::tungstenite::{Error, Message, Result};
-use futures::{join, AsyncReadExt, AsyncWriteExt, SinkExt, StreamExt};
+use futures::{
+    io::{ReadHalf, WriteHalf},
+    join, AsyncReadExt, AsyncWriteExt, Future, Sink, SinkExt, Stream, StreamExt,
+};
 use log::*;
 
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    task::{Context, Poll},
+    time::Duration,
 };
 
-use crate::comm::cons::BUFF_LEN;
+// a chain of its own or a stream over a chain of the mux pool
+enum Remote {
+    Chain(Box<Tunnel>),
+    Mux(MuxStream),
+}
 
-pub fn serv(cfgs: models::ClientConfigs) {
-    let addr = cfgs.listen.to_string();
-    let arc = Arc::new(cfgs);
+impl Stream for Remote {
+    type Item = Result<Message>;
 
-    task::block_on(async {
-        let socket: TcpListener = TcpListener::bind(addr).await.unwrap();
-        info!("listening on {}", socket.local_addr().unwrap());
-
-        while let Some(client) = socket.incoming().next().await {
-            if let Ok(local) = client {
-                let cfg = arc.clone();
-                task::spawn(async {
-                    if let Err(e) = handle_client(cfg, local).await {
-                        error!("{}", e);
-                    }
-                });
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.get_mut() {
+            Remote::Chain(t) => Pin::new(t).poll_next(cx),
+            Remote::Mux(s) => Pin::new(s).poll_next(cx),
+        }
+    }
+}
+
+impl Sink<Message> for Remote {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Remote::Chain(t) => Pin::new(t).poll_ready(cx),
+            Remote::Mux(s) => Pin::new(s).poll_ready(cx),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<()> {
+        match self.get_mut() {
+            Remote::Chain(t) => Pin::new(t).start_send(item),
+            Remote::Mux(s) => Pin::new(s).start_send(item),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Remote::Chain(t) => Pin::new(t).poll_flush(cx),
+            Remote::Mux(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Remote::Chain(t) => Pin::new(t).poll_close(cx),
+            Remote::Mux(s) => Pin::new(s).poll_close(cx),
+        }
+    }
+}
+
+impl Trackable for Remote {
+    fn chain(&self) -> &[String] {
+        match self {
+            Remote::Chain(t) => Trackable::chain(t.as_ref()),
+            Remote::Mux(s) => Trackable::chain(s),
+        }
+    }
+
+    fn with_stats(self, stats: Arc<TunnelStats>) -> Self {
+        match self {
+            Remote::Chain(t) => Remote::Chain(Box::new(Trackable::with_stats(*t, stats))),
+            Remote::Mux(s) => Remote::Mux(Trackable::with_stats(s, stats)),
+        }
+    }
+
+    fn with_stop(self, stop: channel::Receiver<()>) -> Self {
+        match self {
+            Remote::Chain(t) => Remote::Chain(Box::new(Trackable::with_stop(*t, stop))),
+            Remote::Mux(s) => Remote::Mux(Trackable::with_stop(s, stop)),
+        }
+    }
+}
+
+struct ClientContext {
+    dialer: ChainDialer,
+    router: Router,
+    conns: Arc<Conns>,
+    mux: Arc<MuxPool>,
+    pool: Arc<ChainPool>,
+}
+
+impl ClientContext {
+    // `prev` is the context being replaced, its probes and connections are kept
+    fn new(
+        cfgs: models::ClientConfigs,
+        prev: Option<&ClientContext>,
+    ) -> std::result::Result<ClientContext, String> {
+        if let Err(e) = cfgs.check_profiles() {
+            return Err(format!("invalid profiles: {}", e));
+        }
+        if let Err(e) = cfgs.check_identity() {
+            return Err(format!("invalid identity: {}", e));
+        }
+        if let Err(e) = cfgs.check_header() {
+            return Err(format!("invalid header: {}", e));
+        }
+        if let Err(e) = cfgs.check_pool() {
+            return Err(format!("invalid pool: {}", e));
+        }
+        let router = match Router::new(&cfgs.routes) {
+            Ok(r) => r,
+            Err(e) => return Err(format!("invalid routes: {}", e)),
+        };
+        let ctx = match prev {
+            Some(p) => ClientContext {
+                dialer: p.dialer.reload(cfgs),
+                router,
+                conns: p.conns.clone(),
+                mux: p.mux.clone(),
+                pool: p.pool.clone(),
+            },
+            None => ClientContext {
+                dialer: ChainDialer::new(cfgs),
+                router,
+                conns: Arc::default(),
+                mux: Arc::default(),
+                pool: Arc::default(),
+            },
+        };
+        Ok(ctx)
+    }
+
+    // a stream of the mux pool if it is on, else a warm or a new chain
+    async fn dial(
+        &self,
+        profile: &str,
+        cmd: models::Cmds,
+        dest: &str,
+    ) -> std::result::Result<Remote, models::DialError> {
+        let muxed = matches!(cmd, models::Cmds::Connect | models::Cmds::UdpAssoc);
+        if muxed && self.dialer.configs().mux > 0 {
+            let stream = self.mux.open(&self.dialer, profile, cmd, dest).await?;
+            return Ok(Remote::Mux(stream));
+        }
+        let pool = self.pool.take(&self.dialer, profile, cmd.clone(), dest);
+        let tunnel = match pool.await {
+            Some(r) => r?,
+            None => self.dialer.dial_with(profile, cmd, dest).await?,
+        };
+        Ok(Remote::Chain(Box::new(tunnel)))
+    }
+
+    // lists the tunnel in the admin API while `pump` runs
+    async fn track<F, P>(
+        &self,
+        tunnel: Remote,
+        cmd: &models::Cmds,
+        dest: &str,
+        profile: &str,
+        user: &str,
+        pump: F,
+    ) where
+        F: FnOnce(Remote) -> P,
+        P: Future<Output = ()>,
+    {
+        let (tunnel, conn) = self.conns.track(tunnel, cmd, dest, profile, user);
+        conn.run(pump(tunnel), self.timeouts()).await;
+    }
+
+    fn timeouts(&self) -> &models::Timeouts {
+        &self.dialer.configs().timeouts
+    }
+
+    // `profile` is the profile of the listener, used when no rule names one
+    fn route(
+        &self,
+        profile: &str,
+        protocol: &str,
+        user: &str,
+        cmd: &models::Cmds,
+        dest: &str,
+    ) -> Route {
+        // the destination of an udp association is only known per packet
+        let (network, host, port) = match cmd {
+            models::Cmds::UdpAssoc => ("udp", "".to_string(), 0),
+            _ => {
+                let (host, port) = utils::split_host_port(dest).unwrap_or_default();
+                ("tcp", host, port)
             }
+        };
+        let req = Request {
+            protocol,
+            network,
+            host: &host,
+            port,
+            user,
+        };
+        let route = match self.router.route(&req) {
+            Route::Proxy(p) if p.is_empty() => Route::Proxy(profile.to_string()),
+            r => r,
+        };
+        debug!("route {} {} to {:?}", network, dest, route);
+        route
+    }
+}
+
+// the user in front of access log lines
+fn by(user: &str) -> String {
+    match user {
+        "" => "".to_string(),
+        u => format!("[{}] ", u),
+    }
+}
+
+async fn connect_direct(
+    dest: &str,
+    t: &models::Timeouts,
+) -> std::result::Result<TcpStream, models::Status> {
+    match timeout(t.connect(), TcpStream::connect(dest)).await {
+        Ok(Ok(remote)) => Ok(remote),
+        Ok(Err(e)) => {
+            info!("failed to connect {} directly: {}", dest, e);
+            Err(models::Status::from_io_error(&e))
+        }
+        Err(_) => {
+            info!("connect {} directly timeout", dest);
+            Err(models::Status::TtlExpired)
+        }
+    }
+}
+
+/// The client configs in use. A reload swaps them as a whole, connections
+/// keep the configs they started with.
+pub struct Client {
+    ctx: RwLock<Arc<ClientContext>>,
+    // profile of the main listener, switched by the admin API
+    profile: RwLock<String>,
+}
+
+impl Client {
+    /// Fails if a profile, a route or the identity is invalid.
+    pub fn new(cfgs: models::ClientConfigs) -> std::result::Result<Client, String> {
+        let ctx = ClientContext::new(cfgs, None)?;
+        Ok(Client {
+            ctx: RwLock::new(Arc::new(ctx)),
+            profile: RwLock::new("".to_string()),
+        })
+    }
+
+    fn context(&self) -> Arc<ClientContext> {
+        self.ctx.read().unwrap().clone()
+    }
+
+    pub fn dialer(&self) -> ChainDialer {
+        self.context().dialer.clone()
+    }
+
+    pub fn conns(&self) -> Arc<Conns> {
+        self.context().conns.clone()
+    }
+
+    /// Profile used by the main listener, empty means the top-level chain.
+    pub fn profile(&self) -> String {
+        self.profile.read().unwrap().to_string()
+    }
+
+    /// Makes new connections of the main listener use profile `name`.
+    pub fn switch_profile(&self, name: &str) -> std::result::Result<(), String> {
+        if self.dialer().configs().profile(name).is_none() {
+            return Err(format!("unknown profile: {}", name));
+        }
+        *self.profile.write().unwrap() = name.to_string();
+        info!("switch to profile [{}]", name);
+        Ok(())
+    }
+
+    /// Parses `config` as client.json and reloads it.
+    pub fn reload_config(&self, config: &str) -> std::result::Result<(), String> {
+        let cfgs = serde_json::from_str(config).map_err(|e| format!("invalid json: {}", e))?;
+        self.reload(cfgs)
+    }
+
+    /// Validates `cfgs` and uses them for new connections. Listen addresses
+    /// only change on restart.
+    pub fn reload(&self, cfgs: models::ClientConfigs) -> std::result::Result<(), String> {
+        let old = self.context();
+        let ctx = ClientContext::new(cfgs, Some(&old))?;
+        if listen_addrs(ctx.dialer.configs()) != listen_addrs(old.dialer.configs()) {
+            warn!("listen addresses take effect after restart");
+        }
+        if ctx.dialer.configs().tls != old.dialer.configs().tls {
+            warn!("tls certificate takes effect after restart");
+        }
+        if ctx.dialer.configs().profile(&self.profile()).is_none() {
+            warn!(
+                "profile [{}] is gone, use the default chain",
+                self.profile()
+            );
+            *self.profile.write().unwrap() = "".to_string();
+        }
+        *self.ctx.write().unwrap() = Arc::new(ctx);
+        info!("configs reloaded");
+        Ok(())
+    }
+}
+
+fn listen_addrs(cfgs: &models::ClientConfigs) -> Vec<(String, String)> {
+    let mut r = vec![("".to_string(), cfgs.listen.to_string())];
+    for p in cfgs.profiles.iter().filter(|p| !p.listen.is_empty()) {
+        r.push((p.name.to_string(), p.listen.to_string()));
+    }
+    r
+}
+
+async fn accept(client: Arc<Client>, addr: String, profile: String) {
+    let tls = client.dialer().configs().tls.clone();
+    let acceptor = match tls.is_enabled() {
+        true => match tls::acceptor(&tls) {
+            Ok((a, fingerprint)) => {
+                info!("certificate of {}: {}", addr, fingerprint);
+                Some(a)
+            }
+            Err(e) => {
+                error!("failed to listen on {}: {}", addr, e);
+                return;
+            }
+        },
+        false => None,
+    };
+    let socket = match TcpListener::bind(&addr).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("failed to listen on {}: {}", addr, e);
+            return;
+        }
+    };
+    let scheme = if acceptor.is_some() { "tls " } else { "" };
+    match profile.as_str() {
+        "" => info!("listening on {}{}", scheme, addr),
+        p => info!("listening on {}{} with profile [{}]", scheme, addr, p),
+    }
+
+    while let Some(conn) = socket.incoming().next().await {
+        if let Ok(local) = conn {
+            let ctx = client.context();
+            let profile = match profile.as_str() {
+                "" => client.profile(),
+                p => p.to_string(),
+            };
+            let acceptor = acceptor.clone();
+            task::spawn(async move {
+                let r = match acceptor {
+                    Some(acceptor) => {
+                        let handshake = ctx.timeouts().handshake();
+                        match timeout(handshake, acceptor.accept(local)).await {
+                            Ok(Ok(local)) => handle_client(ctx, &profile, local).await,
+                            Ok(Err(e)) => Err(Error::Protocol(format!("tls: {}", e).into())),
+                            Err(_) => Err(Error::Protocol("tls handshake timeout".into())),
+                        }
+                    }
+                    None => handle_client(ctx, &profile, local).await,
+                };
+                if let Err(e) = r {
+                    error!("{}", e);
+                }
+            });
+        }
+    }
+}
+
+pub fn serv(cfgs: models::ClientConfigs, config_path: Option<String>) {
+    let client = match Client::new(cfgs) {
+        Ok(c) => Arc::new(c),
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    let c = client.clone();
+    utils::on_reload(config_path.clone(), move |config| {
+        if let Err(e) = c.reload_config(&config) {
+            error!("keep the old configs: {}", e);
         }
     });
+
+    let addrs = listen_addrs(client.dialer().configs());
+    let health_check = client.dialer().configs().health_check;
+    let metrics_addr = client.dialer().configs().metrics.to_string();
+    let admin_addr = client.dialer().configs().admin.to_string();
+    task::block_on(async {
+        if !metrics_addr.is_empty() {
+            task::spawn(metrics::serv(metrics_addr));
+        }
+        if !admin_addr.is_empty() {
+            task::spawn(admin::serv(admin_addr, client.clone(), config_path));
+        }
+        let c = client.clone();
+        task::spawn(pool::run(client.context().pool.clone(), move || c.dialer()));
+        if health_check > 0 {
+            let c = client.clone();
+            let interval = Duration::from_secs(health_check);
+            task::spawn(health::run(move || c.dialer(), interval));
+        }
+        for (profile, addr) in addrs.iter().skip(1) {
+            task::spawn(accept(
+                client.clone(),
+                addr.to_string(),
+                profile.to_string(),
+            ));
+        }
+        accept(client.clone(), addrs[0].1.to_string(), "".to_string()).await;
+    });
 }
 
-async fn handle_socks5_client(mut local: TcpStream, cfg: &models::ClientConfigs) -> Result<()> {
-    let mut buff = vec![0u8; 2];
+async fn handle_socks5_client<S: ByteStream + 'static>(
+    mut local: S,
+    ctx: &ClientContext,
+    profile: &str,
+) -> Result<()> {
+    let mut buff = [0u8; 2];
 
     local.read_exact(&mut buff[..]).await?;
     if buff[0] != 0x05 {
@@ -48,115 +458,406 @@ async fn handle_socks5_client(mut local: TcpStream, cfg: &models::ClientConfigs)
     }
 
     let methods = buff[1] as usize;
-    let (cmd, dest) = socks5::do_socks5_handshake(&mut local, methods).await?;
+    let users = &ctx.dialer.configs().users;
+    let user = socks5::authenticate(&mut local, methods, users).await?;
+    let (cmd, dest) = socks5::do_socks5_handshake(&mut local).await?;
+    let profile = match ctx.route(profile, "socks5", &user, &cmd, &dest) {
+        Route::Block => {
+            info!("{}block {}", by(&user), dest);
+            socks5::reply(&mut local, models::Status::Denied.socks5_reply()).await;
+            return Ok(());
+        }
+        Route::Direct if cmd == models::Cmds::Connect => {
+            return handle_socks5_direct(local, &user, dest, ctx.timeouts()).await;
+        }
+        Route::Direct => {
+            warn!("{:?} can not go direct, use the proxy chain", cmd);
+            profile.to_string()
+        }
+        Route::Proxy(p) => p,
+    };
+    let _tunnel = metrics::tunnel(&cmd);
     let _ = match cmd {
-        models::Cmds::Connect => handle_socks5_connect(local, cfg, dest).await,
-        models::Cmds::UdpAssoc => handle_udp_assoc(local, cfg, dest).await,
-        models::Cmds::Bind => handle_bind(local, cfg, dest).await,
+        models::Cmds::Connect => handle_socks5_connect(local, ctx, &profile, &user, dest).await,
+        models::Cmds::UdpAssoc => handle_udp_assoc(local, ctx, &profile, &user, dest).await,
+        models::Cmds::Bind => handle_bind(local, ctx, &profile, &user, dest).await,
         _ => Ok(()),
     };
-    return Ok(());
+    Ok(())
 }
 
-async fn handle_http_client(mut local: TcpStream, cfg: &models::ClientConfigs) -> Result<()> {
-    let mut buff = vec![0u8; BUFF_LEN];
-    let n = local.read(&mut buff).await?;
-    let header = &buff[0..n];
-    let addr = http::parse_header(header)?;
+// the origin of the last request on a keep-alive connection, reused while requests
+// go to the same place
+struct Upstream {
+    addr: String,
+    route: Route,
+    reader: http::Reader<Box<dyn ByteStream>>,
+    // listed in the admin API and counted in metrics while in use
+    _conn: Option<(Conn, metrics::TunnelGuard)>,
+}
 
-    info!("connect to {addr}");
-    // info!("with header:\n{}", String::from_utf8_lossy(header));
+impl ClientContext {
+    async fn upstream(
+        &self,
+        addr: &str,
+        route: Route,
+        user: &str,
+    ) -> std::result::Result<Upstream, models::Status> {
+        let t = self.timeouts();
+        let (stream, conn): (Box<dyn ByteStream>, _) = match &route {
+            Route::Proxy(profile) => {
+                let cmd = models::Cmds::Connect;
+                let guard = metrics::tunnel(&cmd);
+                let remote = self
+                    .dial(profile, cmd.clone(), addr)
+                    .await
+                    .map_err(|e| e.status)?;
+                let (remote, conn) = self.conns.track(remote, &cmd, addr, profile, user);
+                (Box::new(ChainStream::new(remote)), Some((conn, guard)))
+            }
+            _ => (Box::new(connect_direct(addr, t).await?), None),
+        };
+        Ok(Upstream {
+            addr: addr.to_string(),
+            route,
+            reader: http::Reader::new(stream, t.idle()),
+            _conn: conn,
+        })
+    }
 
-    let mut remote = dialer::dial(cfg, models::Cmds::Connect, &addr).await?;
-    if header[0] == b'C' {
-        let resp = b"HTTP/1.1 200 Connection Established\r\n\r\n";
-        local.write(resp).await?;
-    } else {
-        let msg = Message::binary(header);
-        remote.send(msg).await?;
+    // the user of a request, empty if there are no users
+    fn http_user(&self, req: &http::Request) -> Option<String> {
+        let users = &self.dialer.configs().users;
+        match users.is_empty() {
+            true => Some("".to_string()),
+            false => req.user(users),
+        }
     }
-    infrs::pump_ws_tcp(local, remote).await;
-    return Ok(());
 }
 
-async fn handle_client(cfgs: Arc<models::ClientConfigs>, mut local: TcpStream) -> Result<()> {
-    let mut buff = vec![0u8; 2];
-    let n = local.peek(&mut buff[0..1]).await?;
+// a forward proxy: every request goes to the host in its target, a connection may
+// carry requests to many hosts, CONNECT turns it into a tunnel
+async fn handle_http_client<S: ByteStream + 'static>(
+    local: S,
+    ctx: &ClientContext,
+    profile: &str,
+) -> Result<()> {
+    let t = ctx.timeouts();
+    let (reader, mut writer) = local.split();
+    let mut reader = http::Reader::new(reader, t.idle());
+    let mut upstream: Option<Upstream> = None;
+
+    loop {
+        let req = match reader.read_head().await {
+            Ok(Some(head)) => http::Request::parse(&head),
+            Ok(None) => break,
+            // too long or not a head at all
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => Err(e),
+            Err(e) => return Err(e.into()),
+        };
+        let req = match req {
+            Ok(r) => r,
+            Err(e) => {
+                writer
+                    .write_all(&http::response("400 Bad Request", false))
+                    .await?;
+                return Err(e.into());
+            }
+        };
+        let mut keep_alive = req.keep_alive();
+        // statuses of the proxy itself, the body of the request is skipped
+        let mut status = None;
+        let mut route = Route::Block;
+        let user = ctx.http_user(&req);
+        let addr = req.addr().unwrap_or_default();
+        match &user {
+            None => {
+                info!("proxy auth required for {}", addr);
+                status = Some("407 Proxy Authentication Required");
+            }
+            Some(_) if addr.is_empty() => status = Some("400 Bad Request"),
+            Some(user) => {
+                let cmd = models::Cmds::Connect;
+                route = ctx.route(profile, "http", user, &cmd, &addr);
+                if route == Route::Block {
+                    info!("{}block {}", by(user), addr);
+                    status = Some(models::Status::Denied.http_status());
+                }
+            }
+        }
+        let user = user.unwrap_or_default();
+        if status.is_none() && req.is_connect() {
+            let (reader, ahead) = reader.into_parts();
+            // both halves come from the same split
+            let local = reader.reunite(writer).unwrap();
+            return handle_http_connect(local, ahead, ctx, profile, &user, route, addr).await;
+        }
+
+        if status.is_none() {
+            info!("{}{} {}", by(&user), req.method, req.target);
+            let reuse = matches!(&upstream, Some(u) if u.addr == addr && u.route == route);
+            if !reuse {
+                upstream = None;
+                match ctx.upstream(&addr, route, &user).await {
+                    Ok(u) => upstream = Some(u),
+                    Err(s) => status = Some(s.http_status()),
+                }
+            }
+        }
+        let up = match (status, upstream.as_mut()) {
+            (None, Some(up)) => up,
+            (status, _) => {
+                let status = status.unwrap_or("502 Bad Gateway");
+                let sink = &mut futures::io::sink();
+                keep_alive = keep_alive && reader.copy_body(req.body(), sink).await.is_ok();
+                writer
+                    .write_all(&http::response(status, keep_alive))
+                    .await?;
+                if !keep_alive {
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+
+        match forward(&mut reader, &mut writer, up, &req).await {
+            Ok((client, origin)) => {
+                keep_alive = keep_alive && client;
+                if !origin {
+                    upstream = None;
+                }
+            }
+            Err(e) => {
+                // the client can not tell a failed request once the response started
+                info!("{} {} failed: {}", req.method, req.target, e);
+                return Ok(());
+            }
+        }
+        if !keep_alive {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// sends a request and its body to `up` and the response back, returns whether
+// the client and the origin keep their connections
+async fn forward<S: ByteStream>(
+    reader: &mut http::Reader<ReadHalf<S>>,
+    writer: &mut WriteHalf<S>,
+    up: &mut Upstream,
+    req: &http::Request,
+) -> std::io::Result<(bool, bool)> {
+    up.reader.get_mut().write_all(&req.to_origin()).await?;
+    reader.copy_body(req.body(), up.reader.get_mut()).await?;
+    loop {
+        let head = match up.reader.read_head().await? {
+            Some(h) => h,
+            None => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+        };
+        let resp = http::Response::parse(&head)?;
+        let body = resp.body(&req.method);
+        // the client sees the end of such a body when the connection closes
+        let until_close = body == http::Body::UntilClose;
+        let keep_alive = req.keep_alive() && !until_close;
+        writer.write_all(&resp.to_client(keep_alive)).await?;
+        if resp.is_interim() {
+            continue;
+        }
+        up.reader.copy_body(body, writer).await?;
+        return Ok((keep_alive, resp.keep_alive() && !until_close));
+    }
+}
+
+// `ahead` is what the client sent after the CONNECT request
+async fn handle_http_connect<S: ByteStream + 'static>(
+    mut local: S,
+    ahead: Vec<u8>,
+    ctx: &ClientContext,
+    profile: &str,
+    user: &str,
+    route: Route,
+    addr: String,
+) -> Result<()> {
+    let t = ctx.timeouts();
+    let cmd = models::Cmds::Connect;
+    let established = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+    let profile = match route {
+        Route::Direct => {
+            info!("{}connect to {} directly", by(user), addr);
+            let mut remote = match connect_direct(&addr, t).await {
+                Ok(r) => r,
+                Err(status) => {
+                    local
+                        .write_all(&http::response(status.http_status(), false))
+                        .await?;
+                    return Ok(());
+                }
+            };
+            local.write_all(established).await?;
+            remote.write_all(&ahead).await?;
+            infrs::pump_tcp_tcp(local, remote, t).await;
+            return Ok(());
+        }
+        Route::Proxy(p) => p,
+        Route::Block => profile.to_string(),
+    };
+
+    info!("{}connect to {}", by(user), addr);
+    let _tunnel = metrics::tunnel(&cmd);
+    let mut remote = match ctx.dial(&profile, cmd.clone(), &addr).await {
+        Ok(r) => r,
+        Err(e) => {
+            local
+                .write_all(&http::response(e.status.http_status(), false))
+                .await?;
+            return Err(e.into());
+        }
+    };
+    local.write_all(established).await?;
+    if !ahead.is_empty() {
+        remote.send(Message::binary(ahead)).await?;
+    }
+    ctx.track(remote, &cmd, &addr, &profile, user, |remote| {
+        infrs::pump_ws_tcp(local, remote, t)
+    })
+    .await;
+    Ok(())
+}
+
+async fn handle_socks5_direct<S: ByteStream>(
+    mut local: S,
+    user: &str,
+    dest: String,
+    t: &models::Timeouts,
+) -> Result<()> {
+    info!("{}connect to {} directly", by(user), dest);
+    match connect_direct(&dest, t).await {
+        Ok(remote) => {
+            socks5::reply(&mut local, models::Status::Ok.socks5_reply()).await;
+            infrs::pump_tcp_tcp(local, remote, t).await;
+        }
+        Err(status) => socks5::reply(&mut local, status.socks5_reply()).await,
+    }
+    Ok(())
+}
+
+async fn handle_client<S: ByteStream + 'static>(
+    ctx: Arc<ClientContext>,
+    profile: &str,
+    mut local: S,
+) -> Result<()> {
+    let mut buff = [0u8; 16];
+    let n = local.read(&mut buff).await?;
     if n < 1 {
         local.close().await?;
         return Err(Error::ConnectionClosed);
     }
 
     let first = buff[0];
-    let cfg = &*cfgs;
-    match buff[0] {
-        0x05 => handle_socks5_client(local, cfg).await,
-        b'C' | b'G' => handle_http_client(local, cfg).await,
+    let ctx = &*ctx;
+    let mut local = Rewind::new(buff[..n].to_vec(), local);
+    match first {
+        0x05 => handle_socks5_client(local, ctx, profile).await,
+        _ if http::is_method(&buff[..n]) => handle_http_client(local, ctx, profile).await,
         _ => {
-            let msg = format!("unknow header: [{first}]");
+            let _ = local
+                .write_all(&http::response("400 Bad Request", false))
+                .await;
+            let msg = format!("unknown protocol: [{first}]");
             Err(Error::Protocol(msg.into()))
         }
     }
 }
 
-async fn handle_bind(local: TcpStream, cfgs: &models::ClientConfigs, dest: String) -> Result<()> {
-    let remote = dialer::dial(&cfgs, models::Cmds::Bind, &dest).await?;
-    info!("bind to {} ok", dest);
-    infrs::pump_ws_tcp(local, remote).await;
-    return Ok(());
+async fn handle_bind<S: ByteStream + 'static>(
+    mut local: S,
+    ctx: &ClientContext,
+    profile: &str,
+    user: &str,
+    dest: String,
+) -> Result<()> {
+    let remote = match ctx.dial(profile, models::Cmds::Bind, &dest).await {
+        Ok(r) => r,
+        Err(e) => {
+            socks5::reply(&mut local, e.status.socks5_reply()).await;
+            return Err(e.into());
+        }
+    };
+    info!("{}bind to {} ok", by(user), dest);
+    let t = ctx.timeouts();
+    ctx.track(
+        remote,
+        &models::Cmds::Bind,
+        &dest,
+        profile,
+        user,
+        |remote| infrs::pump_ws_tcp(local, remote, t),
+    )
+    .await;
+    Ok(())
 }
 
-async fn handle_socks5_connect(
-    mut writer: TcpStream,
-    cfgs: &models::ClientConfigs,
+async fn handle_socks5_connect<S: ByteStream + 'static>(
+    mut writer: S,
+    ctx: &ClientContext,
+    profile: &str,
+    user: &str,
     dest: String,
 ) -> Result<()> {
-    info!("connect to {}", dest);
-    match dialer::dial(&cfgs, models::Cmds::Connect, &dest).await {
+    info!("{}connect to {}", by(user), dest);
+    let cmd = models::Cmds::Connect;
+    match ctx.dial(profile, cmd.clone(), &dest).await {
         Ok(remote) => {
             socks5::reply(&mut writer, 0x00).await;
-            let _ = infrs::pump_ws_tcp(writer, remote).await;
+            let t = ctx.timeouts();
+            ctx.track(remote, &cmd, &dest, profile, user, |remote| {
+                infrs::pump_ws_tcp(writer, remote, t)
+            })
+            .await;
         }
         Err(e) => {
-            socks5::reply(&mut writer, 0x05).await;
-            return Err(e);
+            socks5::reply(&mut writer, e.status.socks5_reply()).await;
+            return Err(e.into());
         }
     }
-    return Ok(());
+    Ok(())
 }
 
-async fn handle_udp_assoc(
-    local: TcpStream,
-    cfgs: &models::ClientConfigs,
+async fn handle_udp_assoc<S: ByteStream + 'static>(
+    local: S,
+    ctx: &ClientContext,
+    profile: &str,
+    user: &str,
     expt: String,
 ) -> Result<()> {
-    info!("udp assoc: {}", expt);
+    info!("{}udp assoc: {}", by(user), expt);
 
-    let mut closer = local.clone();
-    let mut writer = local;
-    if let Ok(socket) = UdpSocket::bind(expt).await {
+    let (mut closer, mut writer) = local.split();
+    if let Ok(socket) = UdpSocket::bind(&expt).await {
         if let Ok(addr) = socket.local_addr() {
             let mut resp = vec![0x05u8, 0x00, 0x00];
             let mut bytes = utils::addr_to_vec(addr);
             resp.append(&mut bytes);
             debug!("bind udp addr: {:?}", resp);
-            if let Ok(_) = writer.write(&resp).await {
+            if writer.write(&resp).await.is_ok() {
                 let sig_send = Arc::new(AtomicBool::new(false));
                 let sig_recv = sig_send.clone();
                 let handle = task::spawn(async move {
-                    let mut buff = vec![0u8; 1];
+                    let mut buff = [0u8; 1];
                     let _ = closer.read_exact(&mut buff[0..1]).await;
                     sig_send.swap(true, Ordering::Relaxed);
-                    let _ = closer.close().await;
                 });
 
-                match dialer::dial(cfgs, models::Cmds::UdpAssoc, &"").await {
-                    Ok(ws_stream) => {
-                        debug!("pumping...");
-                        let _ = infrs::pump_ws_udp_local_client(socket, ws_stream, sig_recv).await;
-                        return Ok(());
-                    }
-                    _ => {}
+                let cmd = models::Cmds::UdpAssoc;
+                if let Ok(ws_stream) = ctx.dial(profile, cmd.clone(), "").await {
+                    debug!("pumping...");
+                    let t = ctx.timeouts();
+                    ctx.track(ws_stream, &cmd, &expt, profile, user, |ws_stream| {
+                        infrs::pump_ws_udp_local_client(socket, ws_stream, sig_recv, t)
+                    })
+                    .await;
+                    return Ok(());
                 }
 
                 let _ = join!(handle);
@@ -166,5 +867,276 @@ async fn handle_udp_assoc(
     // general SOCKS server failure
     socks5::reply(&mut writer, 0x01).await;
     let _ = writer.close().await;
-    return Ok(());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // answers every request with "{name} {method} {path} {body}", and the
+    // proxy headers it should never see
+    async fn origin(name: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        task::spawn(async move {
+            while let Some(Ok(stream)) = listener.incoming().next().await {
+                task::spawn(async move {
+                    let mut writer = stream.clone();
+                    let mut reader = http::Reader::new(stream, Duration::from_secs(5));
+                    while let Ok(Some(head)) = reader.read_head().await {
+                        let req = http::Request::parse(&head).unwrap();
+                        let mut body = vec![];
+                        reader.copy_body(req.body(), &mut body).await.unwrap();
+                        let proxy = req.headers.get("proxy-authorization").unwrap_or_default();
+                        let text = format!(
+                            "{name} {} {} {}{proxy}",
+                            req.method,
+                            req.target,
+                            String::from_utf8_lossy(&body)
+                        );
+                        let resp = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{text}",
+                            text.len()
+                        );
+                        writer.write_all(resp.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    // a client connection to a listener with `cfg`
+    async fn http_client(cfg: models::ClientConfigs) -> http::Reader<TcpStream> {
+        let ctx = Arc::new(ClientContext::new(cfg, None).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(async move {
+            let (local, _) = listener.accept().await.unwrap();
+            let _ = handle_client(ctx, "", local).await;
+        });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        http::Reader::new(stream, Duration::from_secs(5))
+    }
+
+    // sends `req` and reads the response, None if the connection is closed
+    async fn round_trip(conn: &mut http::Reader<TcpStream>, req: &str) -> Option<(u16, String)> {
+        conn.get_mut().write_all(req.as_bytes()).await.unwrap();
+        let head = conn.read_head().await.ok()??;
+        let resp = http::Response::parse(&head).unwrap();
+        let mut body = vec![];
+        conn.copy_body(resp.body("GET"), &mut body).await.unwrap();
+        Some((resp.status, String::from_utf8(body).unwrap()))
+    }
+
+    #[test]
+    fn http_proxy_tests() {
+        task::block_on(async {
+            let (a, b) = (origin("a").await, origin("b").await);
+            let mut cfg = models::ClientConfigs {
+                users: vec![models::ProxyUser {
+                    name: "alice".to_string(),
+                    password: "secret".to_string(),
+                }],
+                ..Default::default()
+            };
+            let mut direct = models::RouteRule {
+                action: models::RouteAction::Direct,
+                profile: "".to_string(),
+                domains: vec![],
+                cidrs: vec![],
+                ports: vec![],
+                protocols: vec![],
+                users: vec!["alice".to_string()],
+            };
+            cfg.routes = vec![direct.clone()];
+            direct.action = models::RouteAction::Block;
+            direct.users = vec![];
+            cfg.routes.push(direct);
+
+            let auth = format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64::encode("alice:secret")
+            );
+            let mut conn = http_client(cfg.clone()).await;
+            let req = format!("GET http://{a}/x?y=1 HTTP/1.1\r\n\r\n");
+            let (status, _) = round_trip(&mut conn, &req).await.unwrap();
+            assert_eq!(status, 407);
+
+            // keep-alive to two origins, the proxy headers are stripped
+            let req = format!("GET http://{a}/x?y=1 HTTP/1.1\r\n{auth}\r\n");
+            let r = round_trip(&mut conn, &req).await.unwrap();
+            assert_eq!(r, (200, "a GET /x?y=1 ".to_string()));
+            let req = format!(
+                "POST http://{b} HTTP/1.1\r\n{auth}Transfer-Encoding: chunked\r\n\r\n\
+                 5\r\nhello\r\n0\r\n\r\n"
+            );
+            let r = round_trip(&mut conn, &req).await.unwrap();
+            assert_eq!(r, (200, "b POST / 5\r\nhello\r\n0\r\n\r\n".to_string()));
+            let req = format!("DELETE http://{a}/ HTTP/1.1\r\n{auth}Connection: close\r\n\r\n");
+            let r = round_trip(&mut conn, &req).await.unwrap();
+            assert_eq!(r, (200, "a DELETE / ".to_string()));
+            assert!(round_trip(&mut conn, &req).await.is_none());
+
+            // the protocol is told by the method of the first request
+            for m in ["PUT", "OPTIONS", "PATCH"].iter() {
+                let mut conn = http_client(cfg.clone()).await;
+                let req = format!("{m} http://{a}/ HTTP/1.1\r\n{auth}\r\n");
+                let r = round_trip(&mut conn, &req).await.unwrap();
+                assert_eq!(r, (200, format!("a {m} / ")));
+            }
+            let mut conn = http_client(cfg.clone()).await;
+            let (status, _) = round_trip(&mut conn, "\x16\x03\x01\x02\x00\r\n\r\n")
+                .await
+                .unwrap();
+            assert_eq!(status, 400);
+
+            // the user is known to the routes
+            cfg.users.push(models::ProxyUser {
+                name: "bob".to_string(),
+                password: "123".to_string(),
+            });
+            let mut conn = http_client(cfg).await;
+            let bob = format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64::encode("bob:123")
+            );
+            let req = format!("GET http://{a}/ HTTP/1.1\r\n{bob}\r\n");
+            let (status, _) = round_trip(&mut conn, &req).await.unwrap();
+            assert_eq!(status, 403);
+        });
+    }
+
+    #[test]
+    fn tls_tests() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        let tls = models::TlsConfigs {
+            cert: dir
+                .join(format!("thomas-cert-{}.pem", id))
+                .display()
+                .to_string(),
+            key: dir
+                .join(format!("thomas-key-{}.pem", id))
+                .display()
+                .to_string(),
+            self_signed: false,
+        };
+        std::fs::write(&tls.cert, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&tls.key, cert.serialize_private_key_pem()).unwrap();
+
+        task::block_on(async {
+            let a = origin("a").await;
+            let addr = {
+                let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                l.local_addr().unwrap().to_string()
+            };
+            let cfg = models::ClientConfigs {
+                listen: addr.to_string(),
+                routes: vec![models::RouteRule {
+                    action: models::RouteAction::Direct,
+                    profile: "".to_string(),
+                    domains: vec![],
+                    cidrs: vec![],
+                    ports: vec![],
+                    protocols: vec![],
+                    users: vec![],
+                }],
+                tls: tls.clone(),
+                ..Default::default()
+            };
+            let client = Arc::new(Client::new(cfg).unwrap());
+            task::spawn(accept(client, addr.to_string(), "".to_string()));
+            let connect = || async {
+                for _ in 0..50 {
+                    if let Ok(stream) = TcpStream::connect(&addr).await {
+                        let connector =
+                            async_native_tls::TlsConnector::new().danger_accept_invalid_certs(true);
+                        return connector.connect("localhost", stream).await.unwrap();
+                    }
+                    task::sleep(Duration::from_millis(20)).await;
+                }
+                panic!("listener is not up");
+            };
+
+            // an http request inside tls
+            let mut conn = http::Reader::new(connect().await, Duration::from_secs(5));
+            let req = format!("GET http://{a}/x HTTP/1.1\r\n\r\n");
+            conn.get_mut().write_all(req.as_bytes()).await.unwrap();
+            let head = conn.read_head().await.unwrap().unwrap();
+            let resp = http::Response::parse(&head).unwrap();
+            let mut body = vec![];
+            conn.copy_body(resp.body("GET"), &mut body).await.unwrap();
+            assert_eq!(body, b"a GET /x ");
+
+            // a socks5 connect inside tls
+            let mut conn = connect().await;
+            let port: u16 = a.rsplit(':').next().unwrap().parse().unwrap();
+            let mut hello = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+            hello.extend(&port.to_be_bytes());
+            conn.write_all(&hello).await.unwrap();
+            let mut resp = [0u8; 12];
+            conn.read_exact(&mut resp).await.unwrap();
+            assert_eq!(resp[..4], [0x05, 0x00, 0x05, 0x00]);
+            let mut conn = http::Reader::new(conn, Duration::from_secs(5));
+            conn.get_mut()
+                .write_all(b"GET /y HTTP/1.1\r\n\r\n")
+                .await
+                .unwrap();
+            let head = conn.read_head().await.unwrap().unwrap();
+            let resp = http::Response::parse(&head).unwrap();
+            let mut body = vec![];
+            conn.copy_body(resp.body("GET"), &mut body).await.unwrap();
+            assert_eq!(body, b"a GET /y ");
+
+            // plain connections are refused
+            let mut plain = TcpStream::connect(&addr).await.unwrap();
+            let req = format!("GET http://{a}/x HTTP/1.1\r\n\r\n");
+            plain.write_all(req.as_bytes()).await.unwrap();
+            let mut buff = vec![];
+            let _ = plain.read_to_end(&mut buff).await;
+            assert!(!buff.starts_with(b"HTTP/1.1 200"));
+        });
+        let _ = std::fs::remove_file(&tls.cert);
+        let _ = std::fs::remove_file(&tls.key);
+    }
+
+    #[test]
+    fn reload_tests() {
+        let node = models::ServerInfo {
+            name: "a".to_string(),
+            addr: "ws://a.local:3001".to_string(),
+            pubkey: "".to_string(),
+        };
+        let cfg = models::ClientConfigs {
+            length: 0,
+            outlets: vec![node.clone()],
+            ..Default::default()
+        };
+        let client = Client::new(cfg.clone()).unwrap();
+        client.dialer().health().record(&node, None);
+
+        let mut bad = cfg.clone();
+        bad.routes = vec![models::RouteRule {
+            action: models::RouteAction::Direct,
+            profile: "".to_string(),
+            domains: vec!["regexp:(".to_string()],
+            cidrs: vec![],
+            ports: vec![],
+            protocols: vec![],
+            users: vec![],
+        }];
+        assert!(client.reload(bad).is_err());
+        assert_eq!(client.dialer().configs().length, 0);
+
+        let mut good = cfg;
+        good.length = 2;
+        client.reload(good).unwrap();
+        let dialer = client.dialer();
+        assert_eq!(dialer.configs().length, 2);
+        // probes survive a reload
+        assert_eq!(dialer.health().snapshot()[0].failures, 1);
+    }
 }
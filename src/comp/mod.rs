@@ -1,10 +1,15 @@
-#[allow(dead_code)]
+pub mod admin;
+pub mod conns;
+pub mod dialer;
+pub mod health;
 pub mod listener;
-
-#[allow(dead_code)]
+pub mod mux;
+pub mod pool;
 pub mod ws;
 
-mod dialer;
 mod http;
 mod proxy;
 mod socks5;
+
+#[cfg(test)]
+pub mod testing;
@@ -0,0 +1,189 @@
+use crate::{
+    comm::{
+        metrics,
+        models::{Cmds, DialError},
+        mux::{MuxStream, Session},
+    },
+    comp::dialer::ChainDialer,
+};
+use log::*;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// Chains kept open to carry the streams of many requests.
+///
+/// Each profile gets up to `mux` chains from the configs, built as requests
+/// come in. A stream goes over the chain with the fewest streams once all of
+/// them are built or being built, requests never wait for the dial of
+/// another one.
+#[derive(Default)]
+pub struct MuxPool {
+    chains: Mutex<HashMap<String, Chains>>,
+}
+
+#[derive(Default)]
+struct Chains {
+    sessions: Vec<Arc<Session>>,
+    // chains being built, the lock is not held while dialing
+    dialing: usize,
+}
+
+// a chain being built for `profile`, counted until dropped
+struct Dialing<'a> {
+    pool: &'a MuxPool,
+    profile: &'a str,
+}
+
+impl Drop for Dialing<'_> {
+    fn drop(&mut self) {
+        let mut chains = self.pool.chains.lock().unwrap();
+        if let Some(c) = chains.get_mut(self.profile) {
+            c.dialing -= 1;
+        }
+    }
+}
+
+impl MuxPool {
+    /// Opens a stream that runs `cmd` on `target` at the outlet of a chain of `profile`.
+    pub async fn open(
+        &self,
+        dialer: &ChainDialer,
+        profile: &str,
+        cmd: Cmds,
+        target: &str,
+    ) -> Result<MuxStream, DialError> {
+        let t = &dialer.configs().timeouts;
+        let session = self.session(dialer, profile).await?;
+        match session.open(cmd, target, t).await {
+            Ok(stream) => Ok(stream),
+            Err(status) => {
+                let hop = session.chain().last().cloned().unwrap_or_default();
+                let e = DialError::new(status, &hop);
+                info!("failed to open stream to {}: {}", target, e);
+                metrics::dial_failed(&status);
+                Err(e)
+            }
+        }
+    }
+
+    // a new session while there are fewer than configured, counting those
+    // being built, else the least busy one
+    async fn session(
+        &self,
+        dialer: &ChainDialer,
+        profile: &str,
+    ) -> Result<Arc<Session>, DialError> {
+        let dialing = {
+            let mut chains = self.chains.lock().unwrap();
+            let c = chains.entry(profile.to_string()).or_default();
+            c.sessions.retain(|s| !s.is_closed());
+            let max = dialer.configs().mux.max(1);
+            if c.sessions.is_empty() || c.sessions.len() + c.dialing < max {
+                c.dialing += 1;
+                Dialing {
+                    pool: self,
+                    profile,
+                }
+            } else {
+                let session = c.sessions.iter().min_by_key(|s| s.streams()).unwrap();
+                return Ok(session.clone());
+            }
+        };
+        let tunnel = dialer.dial_with(profile, Cmds::Mux, "").await?;
+        let t = &dialer.configs().timeouts;
+        let session = Arc::new(Session::new(tunnel, false, t));
+        drop(dialing);
+        let mut chains = self.chains.lock().unwrap();
+        let c = chains.entry(profile.to_string()).or_default();
+        c.sessions.push(session.clone());
+        info!("mux chains of [{}]: {}", profile, c.sessions.len());
+        Ok(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comm::{models, mux::WINDOW_SIZE};
+    use crate::comp::testing;
+    use async_std::task;
+    use async_tungstenite::tungstenite::Message;
+    use futures::{SinkExt, StreamExt};
+
+    #[test]
+    fn open_tests() {
+        task::block_on(async {
+            let target = testing::echo_server().await;
+            let (server, _, outlet) = testing::start_relay("outlet").await;
+            let dialer = ChainDialer::new(models::ClientConfigs {
+                length: 0,
+                outlets: vec![outlet],
+                mux: 1,
+                ..Default::default()
+            });
+
+            // streams multiplexed on one chain, counted with it
+            let pool = MuxPool::default();
+            let mut s1 = pool
+                .open(&dialer, "", Cmds::Connect, &target)
+                .await
+                .unwrap();
+            let s2 = pool
+                .open(&dialer, "", Cmds::Connect, &target)
+                .await
+                .unwrap();
+            assert_eq!(MuxStream::chain(&s2), &["outlet".to_string(), target]);
+            assert_eq!(server.active(), 3);
+
+            // more than a window in flight while the other stream is idle
+            let data: Vec<u8> = (0..3 * WINDOW_SIZE).map(|i| i as u8).collect();
+            let (mut w2, mut r2) = s2.split();
+            let send = async {
+                for chunk in data.chunks(4096) {
+                    w2.send(Message::binary(chunk.to_vec())).await.unwrap();
+                }
+            };
+            let recv = async {
+                let mut got = vec![];
+                while got.len() < data.len() {
+                    match r2.next().await {
+                        Some(Ok(msg)) => got.extend(msg.into_data()),
+                        m => panic!("unexpected message: {:?}", m),
+                    }
+                }
+                got
+            };
+            let (_, got) = futures::join!(send, recv);
+            assert_eq!(got, data);
+
+            s1.send(Message::binary(b"hello".to_vec())).await.unwrap();
+            let msg = s1.next().await.unwrap().unwrap();
+            assert_eq!(msg.into_data(), b"hello");
+        });
+    }
+
+    #[test]
+    fn open_denied_tests() {
+        task::block_on(async {
+            // loopback is blocked by default
+            let target = testing::echo_server().await;
+            let (_, _, outlet) = testing::start_relay_with("outlet", |_| {}).await;
+            let dialer = ChainDialer::new(models::ClientConfigs {
+                length: 0,
+                outlets: vec![outlet],
+                mux: 1,
+                ..Default::default()
+            });
+            let pool = MuxPool::default();
+            let e = pool
+                .open(&dialer, "", Cmds::Connect, &target)
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(e.status, models::Status::Denied);
+            assert_eq!(e.hop, "outlet");
+        });
+    }
+}
@@ -0,0 +1,179 @@
+use crate::{
+    comm::{
+        models::{Cmds, DialError},
+        tunnel::Tunnel,
+    },
+    comp::dialer::{ChainDialer, WarmChain},
+};
+use async_std::{channel, future::timeout, sync::Mutex};
+use log::*;
+use std::{collections::HashMap, sync::Arc};
+
+/// Chains built ahead of requests, so a request only waits for the header
+/// of the last node.
+///
+/// Every profile in use keeps `pool.size` warm chains. They are pinged every
+/// `pool.ping` seconds and replaced once they are older than `pool.max_age`.
+pub struct ChainPool {
+    chains: Mutex<HashMap<String, Vec<WarmChain>>>,
+    // wakes up `run` to refill the pool
+    wake_tx: channel::Sender<()>,
+    wake_rx: channel::Receiver<()>,
+}
+
+impl Default for ChainPool {
+    fn default() -> ChainPool {
+        let (wake_tx, wake_rx) = channel::bounded(1);
+        ChainPool {
+            chains: Mutex::new(HashMap::new()),
+            wake_tx,
+            wake_rx,
+        }
+    }
+}
+
+impl ChainPool {
+    /// Sends `cmd` through a warm chain of `profile`. None if there is no
+    /// warm chain or it failed in a way that a new chain may not.
+    pub async fn take(
+        &self,
+        dialer: &ChainDialer,
+        profile: &str,
+        cmd: Cmds,
+        target: &str,
+    ) -> Option<Result<Tunnel, DialError>> {
+        let cfg = dialer.configs();
+        if cfg.pool.size == 0 {
+            return None;
+        }
+        let warm = {
+            let mut chains = self.chains.lock().await;
+            let list = chains.entry(profile.to_string()).or_default();
+            list.retain(|c| c.age() < cfg.pool.max_age());
+            list.pop()
+        };
+        let _ = self.wake_tx.try_send(());
+        match warm?.finish(cfg, cmd, target).await {
+            Err(e) if e.is_retryable() => {
+                info!("warm chain failed: {}, build a new one", e);
+                None
+            }
+            r => Some(r),
+        }
+    }
+
+    /// Pings the warm chains, replaces the old ones and fills up the pool.
+    pub async fn refresh(&self, dialer: &ChainDialer) {
+        let cfg = dialer.configs();
+        let t = &cfg.timeouts;
+        let profiles: Vec<String> = {
+            let mut chains = self.chains.lock().await;
+            chains.entry("".to_string()).or_default();
+            chains.keys().cloned().collect()
+        };
+        for profile in profiles {
+            // requests use other chains while these are pinged
+            let kept = match self.chains.lock().await.get_mut(&profile) {
+                Some(list) => std::mem::take(list),
+                None => continue,
+            };
+            let size = match cfg.profile(&profile) {
+                Some(_) => cfg.pool.size,
+                None => 0,
+            };
+            let mut alive = vec![];
+            for mut c in kept.into_iter() {
+                if c.age() >= cfg.pool.max_age() || alive.len() >= size {
+                    c.close(t).await;
+                } else if c.ping(t).await {
+                    alive.push(c);
+                }
+            }
+            let missing = size.saturating_sub(alive.len());
+            self.put(&profile, alive).await;
+            for _ in 0..missing {
+                match dialer.warm(&profile).await {
+                    Ok(c) => self.put(&profile, vec![c]).await,
+                    Err(e) => {
+                        debug!("failed to warm a chain of [{}]: {}", profile, e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn put(&self, profile: &str, warm: Vec<WarmChain>) {
+        let mut chains = self.chains.lock().await;
+        chains.entry(profile.to_string()).or_default().extend(warm);
+    }
+
+    /// Number of warm chains of `profile`.
+    #[cfg(test)]
+    pub async fn len(&self, profile: &str) -> usize {
+        let chains = self.chains.lock().await;
+        chains.get(profile).map(|l| l.len()).unwrap_or_default()
+    }
+}
+
+/// Refreshes `pool` with the dialer of `current()` every ping interval and
+/// after a warm chain is taken.
+pub async fn run<F>(pool: Arc<ChainPool>, current: F)
+where
+    F: Fn() -> ChainDialer,
+{
+    loop {
+        let dialer = current();
+        let pool_cfg = dialer.configs().pool;
+        if pool_cfg.size > 0 {
+            pool.refresh(&dialer).await;
+        }
+        let _ = timeout(pool_cfg.ping(), pool.wake_rx.recv()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comm::models;
+    use crate::comp::testing;
+    use async_std::task;
+    use async_tungstenite::tungstenite::Message;
+    use futures::{SinkExt, StreamExt};
+
+    #[test]
+    fn warm_chain_tests() {
+        task::block_on(async {
+            let target = testing::echo_server().await;
+            let (_, _, relay) = testing::start_relay("relay").await;
+            let (_, _, outlet) = testing::start_relay("outlet").await;
+            let mut cfg = models::ClientConfigs {
+                length: 1,
+                relays: vec![relay],
+                outlets: vec![outlet],
+                encrypt: true,
+                ..Default::default()
+            };
+            let pool = ChainPool::default();
+            let dialer = ChainDialer::new(cfg.clone());
+            assert!(pool
+                .take(&dialer, "", Cmds::Connect, &target)
+                .await
+                .is_none());
+
+            // the warm chain only waits for the header of the outlet
+            cfg.pool.size = 1;
+            let dialer = ChainDialer::new(cfg);
+            pool.refresh(&dialer).await;
+            assert_eq!(pool.len("").await, 1);
+            let r = pool.take(&dialer, "", Cmds::Connect, &target).await;
+            let mut tunnel = r.unwrap().unwrap();
+            assert!(tunnel.is_sealed());
+            assert_eq!(pool.len("").await, 0);
+            let hello = Message::binary(b"hello".to_vec());
+            tunnel.send(hello).await.unwrap();
+            let msg = tunnel.next().await.unwrap().unwrap();
+            assert_eq!(msg.into_data(), b"hello");
+        });
+    }
+}
@@ -40,7 +40,7 @@ impl InnerProxy {
                 if let Some(pwd) = url.password() {
                     let encoded_str = format!(
                         "Basic {}",
-                        base64::encode(&format!("{}:{}", url.username(), pwd))
+                        base64::encode(format!("{}:{}", url.username(), pwd))
                     );
                     basic_bytes = Some(encoded_str.into_bytes());
                 };
@@ -163,7 +163,7 @@ impl InnerProxy {
                     "proxy authentication required",
                 ));
             } else {
-                return Err(Error::new(ErrorKind::Other, "unsuccessful tunnel"));
+                return Err(Error::other("unsuccessful tunnel"));
             }
         }
     }
@@ -176,7 +176,7 @@ pub enum ProxyStream {
 }
 
 impl ProxyStream {
-    pub fn into_inner(self: Self) -> TcpStream {
+    pub fn into_inner(self) -> TcpStream {
         match self {
             ProxyStream::Http(s) => s,
             ProxyStream::Socks(s) => s,
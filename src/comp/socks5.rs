@@ -1,38 +1,79 @@
 // MIT https://raw.githubusercontent.com/WANG-lp/socks5-rs/master/src/main.rs
 
-use crate::comm::models;
+use crate::comm::{infrs::ByteStream, models};
 
-use async_std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6, TcpStream};
+use async_std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
 
 use bytes::Buf;
-use futures::{AsyncReadExt, AsyncWriteExt};
+use futures::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use std::io::{Error, ErrorKind};
 
-pub async fn do_socks5_handshake(
-    local: &mut TcpStream,
+/// Picks an auth method from the `methods` offered by the client, and checks the
+/// username and password (RFC 1929) if `users` is not empty. Returns the user name,
+/// empty without users.
+pub async fn authenticate<S: ByteStream>(
+    local: &mut S,
     methods: usize,
-) -> std::io::Result<(models::Cmds, String)> {
-    let mut buffer = vec![0u8; 512];
+    users: &[models::ProxyUser],
+) -> std::io::Result<String> {
+    let mut buffer = vec![0u8; 256];
     local.read_exact(&mut buffer[0..methods]).await?;
-    let mut has_no_auth = false;
-    for i in 0..methods {
-        if buffer[i] == 0x00 {
-            has_no_auth = true;
-            break;
-        }
+    let method = if users.is_empty() { 0x00 } else { 0x02 };
+
+    if !buffer[0..methods].contains(&method) {
+        // no acceptable methods
+        local.write_all(&[0x05u8, 0xff]).await?;
+        let msg = match method {
+            0x00 => "only no-auth is supported!",
+            _ => "username/password auth is required!",
+        };
+        return Err(Error::new(ErrorKind::ConnectionAborted, msg)); // stream will be closed automaticly
+    }
+
+    // server send to client accepted auth method
+    local.write_all(&[0x05u8, method]).await?;
+    local.flush().await?;
+    if method == 0x00 {
+        return Ok("".to_string());
     }
 
-    if !has_no_auth {
+    // ver(0x01) ulen uname plen passwd
+    local.read_exact(&mut buffer[0..2]).await?;
+    if buffer[0] != 0x01 {
         return Err(Error::new(
             ErrorKind::ConnectionAborted,
-            "only no-auth is supported!",
-        )); // stream will be closed automaticly
+            format!("unsupported auth version: {}", buffer[0]),
+        ));
     }
+    let ulen = buffer[1] as usize;
+    local.read_exact(&mut buffer[0..ulen + 1]).await?;
+    let name = String::from_utf8_lossy(&buffer[0..ulen]).to_string();
+    let plen = buffer[ulen] as usize;
+    local.read_exact(&mut buffer[0..plen]).await?;
+    let password = &buffer[0..plen];
 
-    // server send to client accepted auth method (0x00 no-auth only yet)
-    local.write(&[0x05u8, 0x00]).await?;
+    let ok = users
+        .iter()
+        .any(|u| u.name == name && u.password.as_bytes() == password);
+    local
+        .write_all(&[0x01u8, if ok { 0x00 } else { 0x01 }])
+        .await?;
     local.flush().await?;
+    if !ok {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("wrong username or password of [{}]", name),
+        ));
+    }
+    Ok(name)
+}
+
+/// Reads the request after `authenticate`.
+pub async fn do_socks5_handshake<S: ByteStream>(
+    local: &mut S,
+) -> std::io::Result<(models::Cmds, String)> {
+    let mut buffer = vec![0u8; 512];
 
     // read socks5 cmd
     local.read_exact(&mut buffer[0..4]).await?;
@@ -99,16 +140,77 @@ pub async fn do_socks5_handshake(
         _ => {
             println!("recv udp from: {}", addr_port);
             reply(local, 0x07).await;
-            return Err(Error::new(
+            Err(Error::new(
                 ErrorKind::ConnectionAborted,
                 "command is not supported!",
-            ));
+            ))
         }
     }
 }
 
-pub async fn reply(local: &mut TcpStream, code: u8) {
+pub async fn reply<W: AsyncWrite + Unpin>(local: &mut W, code: u8) {
     let _ = local
         .write(&[0x05u8, code, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
         .await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{
+        net::{TcpListener, TcpStream},
+        task,
+    };
+
+    // runs `authenticate` on the server side of a connection that gets `hello`
+    async fn login(
+        users: &[models::ProxyUser],
+        hello: &[u8],
+    ) -> (std::io::Result<String>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut local, _) = listener.accept().await.unwrap();
+        client.write_all(hello).await.unwrap();
+        let mut head = [0u8; 2];
+        local.read_exact(&mut head).await.unwrap();
+        let r = authenticate(&mut local, head[1] as usize, users).await;
+        drop(local);
+        let mut resp = vec![];
+        client.read_to_end(&mut resp).await.unwrap();
+        (r, resp)
+    }
+
+    #[test]
+    fn auth_tests() {
+        task::block_on(async {
+            // the version and the number of methods are read by the listener
+            let (r, resp) = login(&[], &[0x05, 0x01, 0x00]).await;
+            assert_eq!(r.unwrap(), "");
+            assert_eq!(resp, [0x05, 0x00]);
+
+            let users = vec![models::ProxyUser {
+                name: "alice".to_string(),
+                password: "secret".to_string(),
+            }];
+            let (r, resp) = login(&users, &[0x05, 0x01, 0x00]).await;
+            assert!(r.is_err());
+            assert_eq!(resp, [0x05, 0xff]);
+
+            let mut hello = vec![0x05, 0x02, 0x00, 0x02, 0x01, 5];
+            hello.extend(b"alice");
+            hello.push(6);
+            hello.extend(b"secret");
+            let (r, resp) = login(&users, &hello).await;
+            assert_eq!(r.unwrap(), "alice");
+            assert_eq!(resp, [0x05, 0x02, 0x01, 0x00]);
+
+            let len = hello.len();
+            hello[len - 1] = b'x';
+            let (r, resp) = login(&users, &hello).await;
+            assert!(r.is_err());
+            assert_eq!(resp, [0x05, 0x02, 0x01, 0x01]);
+        });
+    }
+}
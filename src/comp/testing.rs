@@ -0,0 +1,78 @@
+//! Servers and helpers shared by the end to end tests.
+
+use crate::{
+    comm::{models, utils},
+    comp::{dialer::ChainDialer, ws::RelayServer},
+};
+use async_std::{
+    net::{SocketAddr, TcpListener},
+    task,
+};
+use futures::{AsyncReadExt, AsyncWriteExt};
+use std::sync::Arc;
+
+/// Echoes every connection back, returns its address.
+pub async fn echo_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    task::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            task::spawn(async move {
+                let (mut r, mut w) = (&stream, &stream);
+                let _ = futures::io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+    addr
+}
+
+/// An address nothing listens on.
+pub async fn closed_addr() -> SocketAddr {
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    closed.local_addr().unwrap()
+}
+
+/// A relay named `name` that may connect to the echo server on loopback,
+/// which is blocked by default.
+pub async fn start_relay(
+    name: &str,
+) -> (Arc<RelayServer>, task::JoinHandle<()>, models::ServerInfo) {
+    start_relay_with(name, |cfg| cfg.block_private = false).await
+}
+
+/// A relay named `name` with the configs changed by `f`.
+pub async fn start_relay_with<F>(
+    name: &str,
+    f: F,
+) -> (Arc<RelayServer>, task::JoinHandle<()>, models::ServerInfo)
+where
+    F: FnOnce(&mut models::ServerConfigs),
+{
+    let (pubkey, secret) = utils::generate_x25519_keypair();
+    let mut serv_cfg = models::ServerConfigs {
+        pubkey: pubkey.to_string(),
+        secret,
+        ..Default::default()
+    };
+    f(&mut serv_cfg);
+    let server = Arc::new(RelayServer::new(&serv_cfg).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let serv_addr = listener.local_addr().unwrap();
+    let s = server.clone();
+    let handle = task::spawn(async move { s.run(listener).await });
+    let info = models::ServerInfo {
+        name: name.to_string(),
+        addr: format!("ws://{}", serv_addr),
+        pubkey,
+    };
+    (server, handle, info)
+}
+
+/// Sends "hello" through a stream of `dialer` to the echo server `target`.
+pub async fn echo_through(dialer: &ChainDialer, target: &str) {
+    let mut stream = dialer.connect(target).await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let mut buff = vec![0u8; 5];
+    stream.read_exact(&mut buff).await.unwrap();
+    assert_eq!(&buff, b"hello");
+}
@@ -1,36 +1,103 @@
 // MIT https://raw.githubusercontent.com/WANG-lp/socks5-rs/master/src/main.rs
 
-use crate::comm::cons::CONN_TIMEOUT;
-use crate::comm::{infrs, models, utils};
+use crate::comm::{
+    acl::Acl,
+    infrs,
+    metrics::{self, Failure},
+    models,
+    mux::{self, MuxStream},
+    replay::ReplayGuard,
+    tls,
+    tunnel::Tunnel,
+    utils,
+};
+use async_native_tls::TlsAcceptor;
 use async_std::{
+    channel,
     future::timeout,
-    net::{TcpListener, TcpStream, UdpSocket},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
     sync::Arc,
     task,
 };
 use async_tungstenite::accept_async;
 use async_tungstenite::{
-    async_std::{connect_async, ConnectStream},
+    async_std::ConnectStream,
     stream::Stream,
-    tungstenite::{Error, Message, Result},
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Error, Message, Result,
+    },
     WebSocketStream,
 };
-use futures::{SinkExt, StreamExt};
+use futures::{future::select, SinkExt, StreamExt};
 use log::*;
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        RwLock,
+    },
+    time::{Duration, Instant},
+};
 use x25519_dalek::{PublicKey, StaticSecret};
 
-async fn listen_tcp(
-    header: &models::HeaderFrame,
-) -> std::io::Result<(TcpListener, std::net::SocketAddr)> {
-    let listener: TcpListener = TcpListener::bind(&header.param).await?;
+async fn listen_tcp(addrs: &[SocketAddr]) -> std::io::Result<(TcpListener, std::net::SocketAddr)> {
+    let listener: TcpListener = TcpListener::bind(addrs).await?;
     let addr = listener.local_addr().unwrap();
     Ok((listener, addr))
 }
 
+// a connection whose header is accepted but whose command has not been answered yet
+struct Pending {
+    ws: WebSocketStream<ConnectStream>,
+    header: models::HeaderFrame,
+    key: String,
+    t: models::Timeouts,
+    // closed when the drain deadline of a shutdown passes
+    stop: channel::Receiver<()>,
+}
+
+impl Pending {
+    // sends Status::Ok and returns the tunnel for the payload
+    async fn ready(mut self) -> Option<Tunnel> {
+        if self.header.status {
+            let msg = Message::text(models::Status::Ok.to_message());
+            if timeout(self.t.header(), self.ws.send(msg)).await.is_err() {
+                return None;
+            }
+        }
+        let tunnel = match self.header.is_sealed() {
+            true => Tunnel::sealed(self.ws, &self.key, false)?,
+            false => Tunnel::plain(self.ws),
+        };
+        Some(tunnel.with_stop(self.stop, "server shutting down"))
+    }
+
+    // tells the client why its request failed
+    async fn fail(mut self, status: models::Status, reason: String) {
+        info!("{}: {}", status, reason);
+        metrics::dial_failed(&status);
+        if self.header.status {
+            let msg = Message::text(status.to_message());
+            let _ = timeout(self.t.header(), self.ws.send(msg)).await;
+        }
+        let code = match status {
+            models::Status::Denied => CloseCode::Policy,
+            _ => CloseCode::Error,
+        };
+        let close_frame = CloseFrame {
+            code,
+            reason: reason.into(),
+        };
+        let _ = timeout(self.t.close(), self.ws.close(Some(close_frame))).await;
+    }
+}
+
 async fn accept_tcp_bind_conn(
-    mut local: WebSocketStream<ConnectStream>,
+    mut local: Tunnel,
     listener: TcpListener,
     addr: std::net::SocketAddr,
+    t: &models::Timeouts,
 ) {
     let mut r = vec![0x05, 0x00, 0x00];
     let tail = utils::addr_to_vec(addr);
@@ -43,136 +110,863 @@ async fn accept_tcp_bind_conn(
         r = vec![0x05, 0x00, 0x00];
         r.extend(utils::addr_to_vec(addr));
         let _ = local.send(Message::Binary(r)).await;
-        let _ = infrs::pump_ws_tcp(tcp_stream, local).await;
+        let _ = infrs::pump_ws_tcp(tcp_stream, local, t).await;
     } else {
         debug!("accept error");
     }
 }
 
-async fn handle_tcp_bind(mut local: WebSocketStream<ConnectStream>, header: models::HeaderFrame) {
-    let r = timeout(CONN_TIMEOUT, listen_tcp(&header)).await;
-    if let Ok(Ok((listener, addr))) = r {
-        accept_tcp_bind_conn(local, listener, addr).await;
-    } else {
-        let buf = vec![0x05, 0x00, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
-        let _ = local.send(Message::Binary(buf)).await;
+async fn handle_tcp_bind(mut local: Pending, acl: &Acl) {
+    let header = &local.header;
+    let addrs = match acl.resolve(&header.cmd, &header.param).await {
+        Ok(Ok(addrs)) => addrs,
+        Ok(Err(e)) => {
+            let reason = format!("bind on {}: {}", header.param, e);
+            return local.fail(models::Status::Denied, reason).await;
+        }
+        Err(e) => {
+            let reason = format!("resolve {} failed: {}", header.param, e);
+            return local.fail(models::Status::HostUnreachable, reason).await;
+        }
+    };
+    match timeout(local.t.connect(), listen_tcp(&addrs)).await {
+        Ok(Ok((listener, addr))) => {
+            let t = local.t;
+            if let Some(tunnel) = local.ready().await {
+                accept_tcp_bind_conn(tunnel, listener, addr, &t).await;
+            }
+        }
+        r => {
+            if !local.header.status {
+                let buf = vec![0x05, 0x00, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+                let _ = local.ws.send(Message::Binary(buf)).await;
+            }
+            let reason = format!("bind on {} failed: {:?}", local.header.param, r);
+            local.fail(models::Status::GeneralFailure, reason).await;
+        }
     }
 }
 
-async fn relay_ws_tcp(local: WebSocketStream<ConnectStream>, header: models::HeaderFrame) {
-    let addr = &header.param;
-    if let Ok(result) = timeout(CONN_TIMEOUT, TcpStream::connect(addr)).await {
-        if let Ok(remote) = result {
-            infrs::pump_ws_tcp(remote, local).await;
-        } else {
-            info!("dial failed: {}", addr);
+// connects to `addr` if the acl allows it, the error has the status for the client
+async fn connect_allowed(
+    acl: &Acl,
+    cmd: &models::Cmds,
+    addr: &str,
+    t: &models::Timeouts,
+) -> std::result::Result<TcpStream, (models::Status, String)> {
+    let addrs = match acl.resolve(cmd, addr).await {
+        Ok(Ok(addrs)) => addrs,
+        Ok(Err(e)) => return Err((models::Status::Denied, e)),
+        Err(e) => {
+            let reason = format!("resolve {} failed: {}", addr, e);
+            return Err((models::Status::HostUnreachable, reason));
+        }
+    };
+    match timeout(t.connect(), TcpStream::connect(&addrs[..])).await {
+        Ok(Ok(remote)) => Ok(remote),
+        Ok(Err(e)) => {
+            let reason = format!("dial {} failed: {}", addr, e);
+            Err((models::Status::from_io_error(&e), reason))
+        }
+        Err(_) => {
+            let reason = format!("dial {} timeout", addr);
+            Err((models::Status::TtlExpired, reason))
         }
     }
 }
 
-async fn relay_ws_ws(local: WebSocketStream<ConnectStream>, header: models::HeaderFrame) {
-    if let Ok(result) = timeout(CONN_TIMEOUT, connect_async(&header.param)).await {
-        if let Ok((remote, _)) = result {
-            infrs::pump_ws_ws(local, remote).await;
-            return;
+async fn relay_ws_tcp(local: Pending, acl: &Acl) {
+    let t = local.t;
+    match connect_allowed(acl, &local.header.cmd, &local.header.param, &t).await {
+        Ok(remote) => {
+            if let Some(tunnel) = local.ready().await {
+                infrs::pump_ws_tcp(remote, tunnel, &t).await;
+            }
         }
+        Err((status, reason)) => local.fail(status, reason).await,
     }
-    info!("dial to [{}] failed!", &header.param);
 }
 
-async fn relay_ws_udp(local: WebSocketStream<ConnectStream>) {
+async fn relay_ws_ws(local: Pending, acl: &Acl) {
+    let next = local.header.param.to_string();
+    let addr = match utils::get_addr(&next) {
+        Ok(addr) => addr,
+        Err(_) => {
+            let reason = format!("relay to {}: invalid address", next);
+            return local.fail(models::Status::Denied, reason).await;
+        }
+    };
+    let t = local.t;
+    // connects to the checked addresses, the name is not resolved again
+    let tcp_stream = match connect_allowed(acl, &local.header.cmd, &addr, &t).await {
+        Ok(s) => s,
+        Err((models::Status::Denied, reason)) => {
+            let reason = format!("relay to {}: {}", next, reason);
+            return local.fail(models::Status::Denied, reason).await;
+        }
+        Err((_, reason)) => {
+            let reason = format!("dial to [{}] failed: {}", next, reason);
+            return local.fail(models::Status::NextHopUnreachable, reason).await;
+        }
+    };
+    match infrs::handshake_ws(&next, tcp_stream, &t).await {
+        Ok(remote) => {
+            if let Some(tunnel) = local.ready().await {
+                infrs::pump_ws_ws(tunnel, Tunnel::plain(remote), &t).await;
+            }
+        }
+        Err(e) => {
+            let reason = format!("dial to [{}] failed: {}", next, e);
+            local.fail(models::Status::NextHopUnreachable, reason).await;
+        }
+    }
+}
+
+async fn relay_ws_udp(local: Pending, acl: &Acl) {
     debug!("prepare to relay udp");
-    if let Ok(raw_socket) = UdpSocket::bind("0.0.0.0:0").await {
-        if let Ok(addr) = raw_socket.local_addr() {
-            info!("Create outbound socket: {}", addr);
-            infrs::pump_ws_udp_remote_host(local, raw_socket).await;
+    let raw_socket = match UdpSocket::bind("0.0.0.0:0").await {
+        Ok(s) => s,
+        Err(e) => {
+            let reason = format!("create outbound udp socket failed: {}", e);
+            return local.fail(models::Status::GeneralFailure, reason).await;
+        }
+    };
+    if let Ok(addr) = raw_socket.local_addr() {
+        info!("Create outbound socket: {}", addr);
+    }
+    let t = local.t;
+    if let Some(tunnel) = local.ready().await {
+        infrs::pump_ws_udp_remote_host(tunnel, raw_socket, acl, &t).await;
+    }
+}
+
+// runs a stream of a mux session like a tunnel with the same command
+async fn serve_mux_stream(
+    stream: MuxStream,
+    cmd: models::Cmds,
+    target: String,
+    ctx: &ServerContext,
+) {
+    let _tunnel = metrics::tunnel(&cmd);
+    let t = &ctx.timeouts;
+    let failed = |status: models::Status, reason: String| {
+        info!("{}: {}", status, reason);
+        metrics::dial_failed(&status);
+        stream.reply(status);
+    };
+    match cmd {
+        models::Cmds::Connect => {
+            info!("mux connect to {}", target);
+            match connect_allowed(&ctx.acl, &cmd, &target, t).await {
+                Ok(remote) => {
+                    if stream.reply(models::Status::Ok) {
+                        infrs::pump_ws_tcp(remote, stream, t).await;
+                    }
+                }
+                Err((status, reason)) => failed(status, reason),
+            }
+        }
+        models::Cmds::UdpAssoc => {
+            info!("mux relay socket");
+            match UdpSocket::bind("0.0.0.0:0").await {
+                Ok(socket) => {
+                    if stream.reply(models::Status::Ok) {
+                        infrs::pump_ws_udp_remote_host(stream, socket, &ctx.acl, t).await;
+                    }
+                }
+                Err(e) => {
+                    let reason = format!("create outbound udp socket failed: {}", e);
+                    failed(models::Status::GeneralFailure, reason);
+                }
+            }
+        }
+        _ => {
+            let reason = format!("{:?} is not supported in a mux session", cmd);
+            failed(models::Status::GeneralFailure, reason);
         }
-    } else {
-        info!("Create outbound udp socket fail!");
     }
 }
 
-async fn handle_cmd(local: WebSocketStream<ConnectStream>, header: models::HeaderFrame) {
-    match header.cmd {
+async fn serve_mux(local: Pending, ctx: Arc<ServerContext>) {
+    let t = local.t;
+    let tunnel = match local.ready().await {
+        Some(tunnel) => tunnel,
+        None => return,
+    };
+    let session = mux::Session::new(tunnel, true, &t);
+    while let Some((stream, cmd, target)) = session.accept().await {
+        let ctx = ctx.clone();
+        task::spawn(async move {
+            let _active = ActiveGuard::new(ctx.active.clone());
+            until_killed(&ctx, serve_mux_stream(stream, cmd, target, &ctx)).await;
+        });
+    }
+}
+
+async fn handle_cmd(local: Pending, ctx: Arc<ServerContext>) {
+    let _tunnel = metrics::tunnel(&local.header.cmd);
+    let acl = &ctx.acl;
+    match local.header.cmd {
         // ws <- tunnel -> tcp
         models::Cmds::Bind => {
-            info!("bind on {}", header.param);
-            handle_tcp_bind(local, header).await;
+            info!("bind on {}", local.header.param);
+            handle_tcp_bind(local, acl).await;
         }
         models::Cmds::Relay => {
-            info!("relay to {}", header.param);
-            relay_ws_ws(local, header).await;
+            info!("relay to {}", local.header.param);
+            relay_ws_ws(local, acl).await;
         }
         models::Cmds::Connect => {
-            info!("connect to {}", header.param);
-            relay_ws_tcp(local, header).await;
+            info!("connect to {}", local.header.param);
+            relay_ws_tcp(local, acl).await;
         }
         // ws <- tunnel -> udp
         models::Cmds::UdpAssoc => {
             info!("relay socket");
-            relay_ws_udp(local).await;
+            relay_ws_udp(local, acl).await;
+        }
+        models::Cmds::Mux => {
+            info!("mux session");
+            serve_mux(local, ctx.clone()).await;
+        }
+        models::Cmds::Ping => {
+            debug!("ping");
+            let t = local.t;
+            if let Some(mut tunnel) = local.ready().await {
+                let _ = timeout(t.close(), tunnel.close()).await;
+            }
+        }
+    }
+}
+
+// shared by all connections of a relay server
+struct ServerContext {
+    // the primary key first
+    keys: Vec<(StaticSecret, models::ServerKey)>,
+    // (replay_window, replay_cache, legacy_headers) of the guard
+    replay: (u64, usize, bool),
+    guard: Arc<ReplayGuard>,
+    authorized: HashSet<String>,
+    acl: Acl,
+    timeouts: models::Timeouts,
+    stop: channel::Receiver<()>,
+    // connections accepted and not finished yet
+    active: Arc<AtomicUsize>,
+}
+
+// counts a connection as active while alive
+struct ActiveGuard(Arc<AtomicUsize>);
+
+impl ActiveGuard {
+    fn new(active: Arc<AtomicUsize>) -> ActiveGuard {
+        active.fetch_add(1, Ordering::SeqCst);
+        ActiveGuard(active)
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ServerContext {
+    // `prev` is the context being replaced, its replay cache and counter are kept
+    fn new(
+        cfgs: &models::ServerConfigs,
+        stop: channel::Receiver<()>,
+        prev: Option<&ServerContext>,
+    ) -> std::result::Result<ServerContext, String> {
+        let mut keys = vec![];
+        for k in cfgs.keypairs()?.into_iter() {
+            if k.is_expired() {
+                warn!("key {} expired on {}", k.pubkey, k.deprecated_until);
+                continue;
+            }
+            if let Some(secret) = utils::b64_to_secret(&k.secret) {
+                keys.push((secret, k));
+            }
+        }
+        if keys.is_empty() {
+            return Err("no valid keypair".to_string());
+        }
+        for (_, k) in keys.iter() {
+            let role = if k.primary { "primary" } else { "staged" };
+            match k.deprecated_until.as_str() {
+                "" => info!("{} key: {}", role, k.pubkey),
+                d => info!("{} key: {} (until {})", role, k.pubkey, d),
+            }
+        }
+        let replay = (cfgs.replay_window, cfgs.replay_cache, cfgs.legacy_headers);
+        let guard = match prev {
+            Some(p) if p.replay == replay => p.guard.clone(),
+            _ => Arc::new(ReplayGuard::new(replay.0, replay.1, replay.2)),
+        };
+        Ok(ServerContext {
+            keys,
+            replay,
+            guard,
+            authorized: cfgs.authorized_clients.iter().cloned().collect(),
+            acl: Acl::new(&cfgs.rules, cfgs.block_private)?,
+            timeouts: cfgs.timeouts,
+            stop,
+            active: prev.map(|p| p.active.clone()).unwrap_or_default(),
+        })
+    }
+
+    fn is_authorized(
+        &self,
+        header: &models::HeaderFrame,
+        secret: &StaticSecret,
+        ephemeral: &[u8; 32],
+    ) -> bool {
+        if self.authorized.is_empty() {
+            return true;
+        }
+        self.authorized.contains(&header.client) && header.verify(secret, ephemeral)
+    }
+
+    // tries every key that has not expired, returns the header with the key used
+    fn decrypt(
+        &self,
+        encrypted: &models::EncHeader,
+    ) -> Option<(models::HeaderFrame, Vec<u8>, String, &StaticSecret)> {
+        let their_pubkey = PublicKey::from(encrypted.pubkey);
+        for (secret, k) in self.keys.iter() {
+            if k.is_expired() {
+                continue;
+            }
+            let bytes = secret.diffie_hellman(&their_pubkey).to_bytes();
+            let key = base64::encode(bytes);
+            if let Some((header, hash)) = encrypted.decrypt(&encrypted.pubkey, &key) {
+                if !k.primary {
+                    debug!("header decrypted by key {}", k.pubkey);
+                }
+                return Some((header, hash, key, secret));
+            }
         }
+        None
     }
 }
 
+// the whole accept of a connection, from the tcp stream to the header reply
+fn accept_deadline(t: &models::Timeouts) -> Duration {
+    t.handshake() * 2 + t.warm() + t.header()
+}
+
 async fn read_one_message(
     ws_stream: &mut WebSocketStream<ConnectStream>,
-    secret: Arc<StaticSecret>,
-) -> Option<(models::HeaderFrame, Vec<u8>)> {
-    if let Ok(Some(Ok(msg))) = timeout(CONN_TIMEOUT, ws_stream.next()).await {
-        if msg.is_text() {
-            if let Ok(text) = msg.into_text() {
-                if let Ok(encrypted) = serde_json::from_str::<models::EncHeader>(&text) {
-                    // println!("recv encrypted header:\n{:?}", &encrypted);
-                    let their_pubkey = PublicKey::from(encrypted.pubkey.clone());
-                    let bytes = secret.diffie_hellman(&their_pubkey).to_bytes();
-                    let key = base64::encode(&bytes);
-                    return encrypted.decrypt(&encrypted.pubkey, &key);
+    ctx: &ServerContext,
+) -> std::result::Result<(models::HeaderFrame, Vec<u8>, String), Failure> {
+    let t = &ctx.timeouts;
+    let deadline = Instant::now() + t.warm();
+    // keepalive pings of a warm chain, one a second at most on average
+    let max_pings = t.warm.max(1);
+    let mut pings = 0;
+    let msg = loop {
+        // each message is due within the header timeout, pings do not extend
+        // the wait past the deadline
+        let left = deadline.saturating_duration_since(Instant::now());
+        match timeout(t.header().min(left), ws_stream.next()).await {
+            // keepalive of a warm chain that has not sent this header yet
+            Ok(Some(Ok(Message::Ping(_)))) | Ok(Some(Ok(Message::Pong(_)))) => {
+                pings += 1;
+                if pings > max_pings {
+                    return Err(Failure::Timeout);
                 }
             }
+            Ok(Some(Ok(msg))) => break msg,
+            Ok(_) => return Err(Failure::Invalid),
+            Err(_) => return Err(Failure::Timeout),
         }
+    };
+    // json headers come in text messages and versioned binary ones in binary messages
+    let encrypted = match msg {
+        Message::Text(text) => models::EncHeader::from_text(&text),
+        Message::Binary(buf) => models::EncHeader::from_bytes(&buf),
+        _ => None,
+    };
+    let encrypted = encrypted.ok_or(Failure::Invalid)?;
+    // println!("recv encrypted header:\n{:?}", &encrypted);
+    let (header, hash, key, secret) = ctx.decrypt(&encrypted).ok_or(Failure::Decrypt)?;
+    if !ctx.is_authorized(&header, secret, &encrypted.pubkey) {
+        warn!("reject unauthorized client: [{}]", header.client);
+        return Err(Failure::Unauthorized);
     }
-    None
+    if let Err(e) = ctx.guard.check(&encrypted.pubkey, &header) {
+        let (stale, duplicate) = ctx.guard.rejected();
+        let evicted = ctx.guard.evicted();
+        warn!("reject header: {e} (stale: {stale}, duplicate: {duplicate}, evicted: {evicted})");
+        return Err(Failure::Replay);
+    }
+    Ok((header, hash, key))
 }
 
+// `acceptor` is set on the wss listener
 async fn accept_ws_conn(
-    secret: Arc<StaticSecret>,
+    ctx: Arc<ServerContext>,
     tcp_stream: TcpStream,
-) -> Result<(WebSocketStream<ConnectStream>, models::HeaderFrame)> {
-    let stream = Stream::Plain(tcp_stream);
-    if let Ok(Ok(mut ws_stream)) = timeout(CONN_TIMEOUT, accept_async(stream)).await {
-        if let Some((header, hash)) = read_one_message(&mut ws_stream, secret).await {
-            let msg = Message::binary(hash);
-            if let Ok(_) = timeout(CONN_TIMEOUT, ws_stream.send(msg)).await {
-                return Ok((ws_stream, header));
+    acceptor: Option<TlsAcceptor>,
+) -> Result<Pending> {
+    let t = ctx.timeouts;
+    let stream = match acceptor {
+        Some(acceptor) => match timeout(t.handshake(), acceptor.accept(tcp_stream)).await {
+            Ok(Ok(s)) => Stream::Tls(s),
+            Ok(Err(e)) => {
+                info!("tls handshake failed: {}", e);
+                metrics::handshake_failed(Failure::Invalid);
+                return Err(Error::ConnectionClosed);
+            }
+            Err(_) => {
+                metrics::handshake_failed(Failure::Timeout);
+                return Err(Error::ConnectionClosed);
+            }
+        },
+        None => Stream::Plain(tcp_stream),
+    };
+    let mut ws_stream = match timeout(t.handshake(), accept_async(stream)).await {
+        Ok(Ok(ws)) => ws,
+        Ok(Err(e)) => {
+            metrics::handshake_failed(Failure::Invalid);
+            return Err(e);
+        }
+        Err(_) => {
+            metrics::handshake_failed(Failure::Timeout);
+            return Err(Error::ConnectionClosed);
+        }
+    };
+    match read_one_message(&mut ws_stream, &ctx).await {
+        Ok((header, hash, key)) => {
+            let msg = Message::binary(header.reply_hash(&hash));
+            if timeout(t.header(), ws_stream.send(msg)).await.is_ok() {
+                return Ok(Pending {
+                    ws: ws_stream,
+                    header,
+                    key,
+                    t,
+                    stop: ctx.stop.clone(),
+                });
             }
-        } else {
-            infrs::close_ws_stream(ws_stream).await;
+        }
+        Err(f) => {
+            metrics::handshake_failed(f);
+            infrs::close_ws_stream(ws_stream, &t).await;
         }
     }
     info!("parse header failed");
     Err(Error::ConnectionClosed)
 }
 
-pub fn serv(cfgs: models::ServerConfigs) {
-    let addr = cfgs.listen.to_string();
-    let secret = Arc::new(utils::b64_to_secret(&cfgs.secret).unwrap());
+/// A relay node that serves websocket tunnels on a listener.
+pub struct RelayServer {
+    // swapped by `reload()`, connections keep the one they started with
+    ctx: RwLock<Arc<ServerContext>>,
+    stop_tx: channel::Sender<()>,
+    stop_rx: channel::Receiver<()>,
+    close_tx: channel::Sender<()>,
+}
 
-    task::block_on(async {
-        let socket = TcpListener::bind(&addr).await.unwrap();
-        info!("listening on: {}", addr);
+impl RelayServer {
+    /// Fails if `secret` and `pubkey` in `cfgs` are not a keypair or a rule is invalid.
+    pub fn new(cfgs: &models::ServerConfigs) -> std::result::Result<RelayServer, String> {
+        let (stop_tx, stop_rx) = channel::bounded(1);
+        let (close_tx, close_rx) = channel::bounded(1);
+        let ctx = ServerContext::new(cfgs, close_rx, None)?;
+        Ok(RelayServer {
+            ctx: RwLock::new(Arc::new(ctx)),
+            stop_tx,
+            stop_rx,
+            close_tx,
+        })
+    }
 
-        while let Ok((stream, _)) = socket.accept().await {
-            let s = secret.clone();
-            task::spawn(async move {
-                if let Ok(Ok((ws_stream, header))) =
-                    timeout(CONN_TIMEOUT, accept_ws_conn(s, stream)).await
-                {
-                    let _ = handle_cmd(ws_stream, header).await;
-                } else {
-                    info!("connection closed");
+    fn context(&self) -> Arc<ServerContext> {
+        self.ctx.read().unwrap().clone()
+    }
+
+    /// Validates `cfgs` and uses its keys, clients, rules and timeouts for new
+    /// connections. The listen address only changes on restart.
+    pub fn reload(&self, cfgs: &models::ServerConfigs) -> std::result::Result<(), String> {
+        let prev = self.context();
+        let ctx = ServerContext::new(cfgs, prev.stop.clone(), Some(&prev))?;
+        *self.ctx.write().unwrap() = Arc::new(ctx);
+        info!("configs reloaded");
+        Ok(())
+    }
+
+    /// Accepts connections from `listener` until `shutdown()` is called.
+    pub async fn run(&self, listener: TcpListener) {
+        self.serve(listener, None).await
+    }
+
+    /// Same as `run()` but takes wss connections with `acceptor`. It may run at
+    /// the same time as `run()` on another listener.
+    pub async fn run_tls(&self, listener: TcpListener, acceptor: TlsAcceptor) {
+        self.serve(listener, Some(acceptor)).await
+    }
+
+    async fn serve(&self, listener: TcpListener, acceptor: Option<TlsAcceptor>) {
+        loop {
+            let accepted = Box::pin(async { Some(listener.accept().await) });
+            let stopped = Box::pin(async {
+                let _ = self.stop_rx.recv().await;
+                None
+            });
+            let (stream, _) = match select(accepted, stopped).await.factor_first().0 {
+                Some(Ok(r)) => r,
+                Some(Err(e)) => {
+                    // e.g. out of file descriptors, wait for some to be closed
+                    warn!("accept error: {}", e);
+                    task::sleep(Duration::from_millis(100)).await;
+                    continue;
                 }
+                None => break,
+            };
+            let ctx = self.context();
+            let acceptor = acceptor.clone();
+            task::spawn(async move {
+                let _active = ActiveGuard::new(ctx.active.clone());
+                let serve = async {
+                    let deadline = accept_deadline(&ctx.timeouts);
+                    match timeout(deadline, accept_ws_conn(ctx.clone(), stream, acceptor)).await {
+                        Ok(Ok(pending)) => handle_cmd(pending, ctx.clone()).await,
+                        Ok(Err(_)) => info!("connection closed"),
+                        Err(_) => {
+                            metrics::handshake_failed(Failure::Timeout);
+                            info!("accept timeout, connection closed");
+                        }
+                    }
+                };
+                until_killed(&ctx, serve).await;
             });
         }
+        info!("stop accepting new connections");
+    }
+
+    /// Stops the accept loop in `run()`. Tunnels already established keep running.
+    pub fn shutdown(&self) {
+        self.stop_tx.close();
+    }
+
+    /// Returns the number of (stale, duplicate) header frames rejected so far.
+    pub fn replay_rejections(&self) -> (u64, u64) {
+        self.context().guard.rejected()
+    }
+
+    /// Returns the number of header frames of old clients accepted without
+    /// replay protection so far.
+    pub fn legacy_headers(&self) -> u64 {
+        self.context().guard.legacy_accepted()
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.stop_tx.is_closed()
+    }
+
+    /// Number of connections that have not finished yet.
+    pub fn active(&self) -> usize {
+        self.context().active.load(Ordering::SeqCst)
+    }
+
+    /// Waits up to `deadline` for the connections to finish after `shutdown()`,
+    /// then closes the remaining tunnels with a close frame.
+    pub async fn drain(&self, deadline: Duration) {
+        let ctx = self.context();
+        if !wait_idle(&ctx.active, deadline).await {
+            warn!(
+                "close {} connections after the drain deadline",
+                self.active()
+            );
+            self.close_tx.close();
+            let grace = ctx.timeouts.close() + Duration::from_secs(1);
+            wait_idle(&ctx.active, grace).await;
+        }
+    }
+}
+
+// runs `serve` until it finishes. Tunnels send their close frames once the drain
+// deadline passes, whatever is still running after the close timeout is dropped
+async fn until_killed<F>(ctx: &ServerContext, serve: F)
+where
+    F: std::future::Future<Output = ()>,
+{
+    let killed = async {
+        let _ = ctx.stop.recv().await;
+        task::sleep(ctx.timeouts.close()).await;
+    };
+    select(Box::pin(serve), Box::pin(killed)).await;
+}
+
+// true if no connection is active before `deadline`
+async fn wait_idle(active: &AtomicUsize, deadline: Duration) -> bool {
+    let start = Instant::now();
+    loop {
+        let n = active.load(Ordering::SeqCst);
+        if n == 0 {
+            return true;
+        }
+        if start.elapsed() >= deadline {
+            return false;
+        }
+        debug!("waiting for {} connections", n);
+        task::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+// None if `addr` is empty, an error is logged
+async fn bind(addr: &str) -> std::result::Result<Option<TcpListener>, ()> {
+    if addr.is_empty() {
+        return Ok(None);
+    }
+    match TcpListener::bind(addr).await {
+        Ok(l) => Ok(Some(l)),
+        Err(e) => {
+            error!("failed to listen on {}: {}", addr, e);
+            Err(())
+        }
+    }
+}
+
+pub fn serv(cfgs: models::ServerConfigs, config_path: Option<String>) {
+    let addr = cfgs.listen.to_string();
+    let server = match RelayServer::new(&cfgs) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    if addr.is_empty() && cfgs.tls_listen.is_empty() {
+        error!("neither listen nor tls_listen is set");
+        return;
+    }
+    let acceptor = match cfgs.tls_listen.as_str() {
+        "" => None,
+        _ if !cfgs.tls.is_enabled() => {
+            error!("tls_listen needs a certificate or self_signed in tls");
+            return;
+        }
+        tls_addr => match tls::acceptor(&cfgs.tls) {
+            Ok((a, fingerprint)) => {
+                info!("certificate of {}: {}", tls_addr, fingerprint);
+                Some(a)
+            }
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        },
+    };
+
+    let server = Arc::new(server);
+    let (s, prev) = (server.clone(), cfgs.clone());
+    utils::on_reload(config_path, move |config| {
+        let cfgs: models::ServerConfigs = match serde_json::from_str(&config) {
+            Ok(c) => c,
+            Err(e) => return error!("failed to parse configs: {}", e),
+        };
+        if cfgs.listen != prev.listen || cfgs.tls_listen != prev.tls_listen {
+            warn!("listen address takes effect after restart");
+        }
+        if cfgs.tls != prev.tls {
+            warn!("tls certificate takes effect after restart");
+        }
+        if let Err(e) = s.reload(&cfgs) {
+            error!("keep the old configs: {}", e);
+        }
+    });
+    let s = server.clone();
+    utils::on_shutdown_signal(move || {
+        info!("shutting down, press Ctrl+C again to exit now");
+        s.shutdown();
     });
+
+    task::block_on(async {
+        // both listeners or none
+        let plain_socket = match bind(&addr).await {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+        let tls_socket = match acceptor {
+            Some(_) => match bind(&cfgs.tls_listen).await {
+                Ok(s) => s,
+                Err(_) => return,
+            },
+            None => None,
+        };
+        if !cfgs.metrics.is_empty() {
+            task::spawn(metrics::serv(cfgs.metrics.to_string()));
+        }
+        let plain = async {
+            if let Some(socket) = plain_socket {
+                info!("listening on: {}", addr);
+                server.run(socket).await;
+            }
+        };
+        let tls = async {
+            if let (Some(socket), Some(acceptor)) = (tls_socket, acceptor) {
+                info!("listening on: {} (tls)", cfgs.tls_listen);
+                server.run_tls(socket, acceptor).await;
+            }
+        };
+        futures::join!(plain, tls);
+        server.drain(Duration::from_secs(cfgs.drain)).await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comp::{dialer::ChainDialer, testing};
+
+    // a header for the key `server_pubkey`, signed by `identity` if set
+    fn seal_header(server_pubkey: &str, identity: Option<&StaticSecret>) -> models::EncHeader {
+        let secret = utils::generate_secret();
+        let pubkey = PublicKey::from(&secret).to_bytes();
+        let their_pubkey = utils::b64_to_pubkey(server_pubkey).unwrap();
+        let key = base64::encode(secret.diffie_hellman(&their_pubkey).to_bytes());
+        let mut header = models::HeaderFrame::new(models::Cmds::Connect, "bing.com:443");
+        if let Some(identity) = identity {
+            header.sign(identity, &their_pubkey, &pubkey);
+        }
+        let format = models::HeaderFormat::Binary;
+        header
+            .encrypt(&pubkey, &key, format, Default::default(), 0)
+            .unwrap()
+            .0
+    }
+
+    fn accepts(ctx: &ServerContext, enc: &models::EncHeader) -> bool {
+        match ctx.decrypt(enc) {
+            Some((header, _, _, secret)) => ctx.is_authorized(&header, secret, &enc.pubkey),
+            None => false,
+        }
+    }
+
+    #[test]
+    fn server_context_tests() {
+        let (client_pub, client_secret) = utils::generate_x25519_keypair();
+        let identity = utils::b64_to_secret(&client_secret).unwrap();
+        let (pubkey, secret) = utils::generate_x25519_keypair();
+        let mut cfg = models::ServerConfigs {
+            pubkey: pubkey.to_string(),
+            secret,
+            authorized_clients: vec![client_pub],
+            ..Default::default()
+        };
+        let staged = cfg.stage_key();
+        let expired = cfg.stage_key();
+        cfg.keys[1].deprecated_until = "2000-01-01".to_string();
+        let (_stop_tx, stop) = channel::bounded(1);
+        let ctx = ServerContext::new(&cfg, stop.clone(), None).unwrap();
+
+        // the primary and the staged keys are tried, an expired one is not
+        assert!(accepts(&ctx, &seal_header(&pubkey, Some(&identity))));
+        assert!(accepts(&ctx, &seal_header(&staged.pubkey, Some(&identity))));
+        assert!(!accepts(
+            &ctx,
+            &seal_header(&expired.pubkey, Some(&identity))
+        ));
+
+        // anonymous and unknown clients are refused
+        assert!(!accepts(&ctx, &seal_header(&pubkey, None)));
+        let stranger = utils::generate_secret();
+        assert!(!accepts(&ctx, &seal_header(&pubkey, Some(&stranger))));
+
+        // a reload keeps the active counter, and the replay cache unless its
+        // settings changed
+        let _active = ActiveGuard::new(ctx.active.clone());
+        let reloaded = ServerContext::new(&cfg, stop.clone(), Some(&ctx)).unwrap();
+        assert!(Arc::ptr_eq(&ctx.guard, &reloaded.guard));
+        assert_eq!(reloaded.active.load(Ordering::SeqCst), 1);
+        cfg.replay_cache += 1;
+        let resized = ServerContext::new(&cfg, stop.clone(), Some(&ctx)).unwrap();
+        assert!(!Arc::ptr_eq(&ctx.guard, &resized.guard));
+        assert_eq!(resized.active.load(Ordering::SeqCst), 1);
+
+        // invalid configs are refused
+        cfg.secret = "".to_string();
+        assert!(ServerContext::new(&cfg, stop, Some(&ctx)).is_err());
+    }
+
+    #[test]
+    fn wait_idle_tests() {
+        task::block_on(async {
+            let active = Arc::new(AtomicUsize::new(0));
+            let guard = ActiveGuard::new(active.clone());
+            assert!(!wait_idle(&active, Duration::from_millis(200)).await);
+            drop(guard);
+            assert!(wait_idle(&active, Duration::from_millis(200)).await);
+        });
+    }
+
+    #[test]
+    fn drain_tests() {
+        task::block_on(async {
+            let target = testing::echo_server().await;
+            let (server, handle, outlet) = testing::start_relay("outlet").await;
+            let dialer = ChainDialer::new(models::ClientConfigs {
+                length: 0,
+                outlets: vec![outlet],
+                ..Default::default()
+            });
+
+            // an idle tunnel is closed when the server stops
+            let mut tunnel = dialer.dial(models::Cmds::Connect, &target).await.unwrap();
+            assert_eq!(server.active(), 1);
+            server.shutdown();
+            handle.await;
+            server.drain(Duration::from_millis(300)).await;
+            assert_eq!(server.active(), 0);
+            assert!(server.is_shutdown());
+            match tunnel.next().await {
+                Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Away),
+                m => panic!("unexpected message: {:?}", m),
+            }
+        });
+    }
+
+    #[test]
+    fn run_tls_tests() {
+        task::block_on(async {
+            let target = testing::echo_server().await;
+            let (server, _, plain) = testing::start_relay("outlet").await;
+            let tls = models::TlsConfigs {
+                self_signed: true,
+                ..Default::default()
+            };
+            let (acceptor, fingerprint) = tls::acceptor(&tls).unwrap();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let tls_addr = listener.local_addr().unwrap();
+            let s = server.clone();
+            task::spawn(async move { s.run_tls(listener, acceptor).await });
+            let pinned = models::ServerInfo {
+                addr: format!("wss://{}/?fingerprint={}", tls_addr, fingerprint),
+                ..plain.clone()
+            };
+            let cfg = models::ClientConfigs {
+                length: 0,
+                outlets: vec![pinned.clone()],
+                ..Default::default()
+            };
+
+            // straight to the wss listener, and from the plain one to it
+            testing::echo_through(&ChainDialer::new(cfg.clone()), &target).await;
+            let relayed = models::ClientConfigs {
+                inlets: vec![plain],
+                ..cfg.clone()
+            };
+            testing::echo_through(&ChainDialer::new(relayed), &target).await;
+
+            // the self-signed certificate is only trusted by its fingerprint
+            let mut wrong = cfg.clone();
+            wrong.outlets[0].addr = pinned.addr.replace("fingerprint=", "fingerprint=00");
+            let dialer = ChainDialer::new(wrong);
+            assert!(dialer.dial(models::Cmds::Connect, &target).await.is_err());
+            let mut unpinned = cfg;
+            unpinned.outlets[0].addr = format!("wss://{}", tls_addr);
+            let dialer = ChainDialer::new(unpinned);
+            assert!(dialer.dial(models::Cmds::Connect, &target).await.is_err());
+        });
+    }
 }
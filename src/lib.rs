@@ -0,0 +1,30 @@
+//! Thomas chains several websocket servers into one dynamic proxy chain.
+//!
+//! The two binaries are thin wrappers around this crate. Other programs can
+//! use [`ChainDialer`] to open streams through a chain and [`RelayServer`] to
+//! run a relay node on their own listener.
+
+extern crate openssl_probe;
+
+mod comm;
+mod comp;
+
+pub use comm::infrs::ChainStream;
+pub use comm::models::{
+    ChainProfile, Cipher, ClientConfigs, Cmds, DialError, HeaderFormat, PoolConfigs, ProxyUser,
+    RouteAction, RouteRule, ServerConfigs, ServerInfo, ServerKey, Status, Timeouts, TlsConfigs,
+};
+pub use comm::tunnel::Tunnel;
+pub use comp::dialer::ChainDialer;
+pub use comp::ws::RelayServer;
+
+/// What the `client` and `server` binaries run, not a stable API.
+#[doc(hidden)]
+pub mod cli {
+    pub use crate::comm::{
+        cons::{PKG_NAME, VERSION},
+        logging::init as init_logging,
+        utils::{init_ssl_cert_env_vars, parse_cmd_args, register_ctrl_c_handler},
+    };
+    pub use crate::comp::{listener::serv as serv_client, ws::serv as serv_server};
+}
@@ -1,35 +1,34 @@
-extern crate openssl_probe;
 use log::*;
-
-mod comm;
-mod comp;
+use thomas::cli;
 
 fn main() {
-    let config = comm::utils::parse_cmd_args(true);
-    if config == None {
-        std::process::exit(0);
-    }
+    let (config, path) = match cli::parse_cmd_args(true) {
+        Some(a) => a,
+        None => std::process::exit(0),
+    };
 
-    let cfg = parse_args_for_server(&config.unwrap());
-    if !comm::utils::is_keypair(&cfg.secret, &cfg.pubkey) {
-        println!("invalid keypair!\nplease run \"server --key\" to generate new keypair");
+    let cfg = parse_args_for_server(&config);
+    if let Err(e) = cfg.keypairs() {
+        println!(
+            "{}!\nplease run \"server --key\" to generate new keypair",
+            e
+        );
         std::process::exit(2);
     }
 
-    comm::logging::init(&cfg.loglevel);
-    openssl_probe::init_ssl_cert_env_vars();
-    comm::utils::register_ctrl_c_handler();
+    cli::init_logging(&cfg.loglevel);
+    cli::init_ssl_cert_env_vars();
 
-    let ver = crate::comm::cons::VERSION;
-    let name = crate::comm::cons::PKG_NAME;
+    let ver = cli::VERSION;
+    let name = cli::PKG_NAME;
     println!("{} server v{} starts", name, ver);
-    comp::ws::serv(cfg);
+    cli::serv_server(cfg, path);
     info!("{} exits", name);
 }
 
-fn parse_args_for_server(config: &str) -> comm::models::ServerConfigs {
+fn parse_args_for_server(config: &str) -> thomas::ServerConfigs {
     match serde_json::from_str(config) {
-        Ok(c) => return c,
+        Ok(c) => c,
         Err(e) => {
             println!("parse config fail");
             println!("{:?}", e);